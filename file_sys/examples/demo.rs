@@ -0,0 +1,89 @@
+extern crate file_sys;
+extern crate chrono;
+extern crate rand;
+
+use rand::Rng;
+use chrono::prelude::*;
+use file_sys::{Database, DbError, LogLevel, RawData, TableOptions};
+
+// User will configure a top level directory.
+
+fn main() -> Result<(), DbError> {
+    let database = Database::builder()
+                    .source("data")
+                    .log_level(LogLevel::Info)
+                    .build()?;
+    if database.manifest("levels")?.is_none() {
+        database.create_table("levels", TableOptions::of::<RawData>())?;
+    }
+    let levels = database.table::<RawData>("levels")?;
+
+    for _ in 0..3 {
+        levels.insert(&generate_raw_data())?;
+    }
+
+    let now = Utc::now().timestamp_millis() as u64;
+    for value in levels.range(now - 3_600_000, now)? {
+        println!("{:?}", value?);
+    }
+
+    return Ok(());
+}
+
+/***
+* Function generate_raw_data:
+*
+* Purpose:
+* generates random data for struct
+***/
+fn generate_raw_data() -> RawData {
+    let raw_data = RawData {
+        AQHI:		generate_i32(),
+        AQI:		generate_i32(),
+        CO:			generate_f32(),
+        CO2:		generate_f32(),
+        NO:			generate_f32(),
+        NO2:		generate_f32(),
+        O3:			generate_f32(),
+        PM1:		generate_f32(),
+        PM2_5:		generate_f32(),
+        PM10:		generate_f32(),
+        SO2:		generate_f32(),
+        T:			generate_f32(),
+        RH:			generate_f32(),
+        NOISE:		generate_f32(),
+        TimeStamp:	Some("".to_string())
+    };
+
+    return raw_data;
+}
+
+/***
+* Function generate_i32:
+*
+* Purpose:
+* Generates random i32 from 0-10, if it's greater than 8, return null
+***/
+fn generate_i32() -> Option<i32> {
+    let mut rng = rand::thread_rng();
+    let num: i32 = rng.gen_range(0,10);
+    if num >= 8 {
+        return None;
+    }
+    return Some(num);
+}
+
+/***
+* Function generate_f32:
+*
+* Purpose:
+* Generates random f32 from 0-10, if it's greater than 8, return null
+***/
+fn generate_f32() -> Option<f32> {
+    let mut rng = rand::thread_rng();
+    let num: f32 = rng.gen_range(0.0,10.0);
+    if num >= 8.0 {
+        return None;
+    }
+    return Some(num);
+}
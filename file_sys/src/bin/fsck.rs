@@ -0,0 +1,65 @@
+extern crate file_sys;
+
+use std::env;
+use std::path::PathBuf;
+use std::process;
+use file_sys::{Config, Database, LogLevel, Result};
+
+/***
+* fsck:
+*
+* Purpose:
+* Checks a data tree, Ex: fsck data --repair
+*
+*   <source>            top level directory, defaults to data or the configured one
+*   --repair            truncate torn tails, migrate old files and quarantine unreadable ones
+*   --config <file>     TOML configuration, needed for its keys if partitions are encrypted
+***/
+fn main() {
+    let mut source = None;
+    let mut config_file = None;
+    let mut repair = false;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--repair" => repair = true,
+            "--config" => config_file = args.next(),
+            "-h" | "--help" => {
+                println!("usage: fsck [<source>] [--repair] [--config <file>]");
+                return;
+            }
+            _ => source = Some(PathBuf::from(arg)),
+        }
+    }
+
+    // Not built, a missing source is reported instead of created
+    let report = open(config_file, source).and_then(|database| {
+        if repair { database.repair() } else { database.verify() }
+    });
+    match report {
+        Ok(report) => {
+            println!("{}", report);
+            if !report.is_clean() && !repair {
+                process::exit(1);
+            }
+        }
+        Err(err) => {
+            eprintln!("fsck: {}", err);
+            process::exit(2);
+        }
+    }
+}
+
+// Database over the source with the keys of the configuration, if any
+fn open(config_file: Option<String>, source: Option<PathBuf>) -> Result<Database> {
+    let config = match config_file {
+        Some(path) => Config::from_toml_file(path)?,
+        None => Config::default()
+    };
+    let keys = config.keys()?;
+    return Ok(Database::from_config(Config {
+        source:     source.unwrap_or(config.source),
+        log_level:  LogLevel::Info,
+        ..config
+    }).with_keys(keys));
+}
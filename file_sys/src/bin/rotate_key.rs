@@ -0,0 +1,37 @@
+extern crate file_sys;
+
+use std::env;
+use std::process;
+use file_sys::{Config, Database, LogLevel};
+
+/***
+* rotate_key:
+*
+* Purpose:
+* Encrypts every partition again with a new key, Ex: rotate_key /etc/file_sys.toml
+*
+*   <config>    TOML configuration, key_file names the new key and
+*               old_key_files every key data may still be encrypted with
+***/
+fn main() {
+    let path = match env::args().nth(1) {
+        Some(path) if path != "-h" && path != "--help" => path,
+        _ => {
+            println!("usage: rotate_key <config>");
+            return;
+        }
+    };
+
+    let rotated = Config::from_toml_file(&path).and_then(|config| {
+        let keys = config.keys()?;
+        let database = Database::from_config(Config { log_level: LogLevel::Info, ..config }).with_keys(keys);
+        database.rotate_keys()
+    });
+    match rotated {
+        Ok(rotated) => println!("{} partitions rotated", rotated),
+        Err(err) => {
+            eprintln!("rotate_key: {}", err);
+            process::exit(2);
+        }
+    }
+}
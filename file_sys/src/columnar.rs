@@ -0,0 +1,592 @@
+use std::io;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use crc::crc32;
+use crate::database::{MpdRecordType, serialize_struct};
+use crate::error::{DbError, Result};
+use crate::format::{Frame, HEADER_LEN, encode_frame, read_frame};
+use crate::raw_data::RawData;
+
+/***
+* Columnar encoding:
+*
+* Purpose:
+* Stores the RawData records of a sealed partition column by column, in blocks
+* of BLOCK_RECORDS records so the partition can be decoded a block at a time,
+* see BlockReader. Each block is prefixed with its length as a varint, and each
+* column in it with its own:
+*
+*   count       varint, records in the block
+*   timestamps  delta-of-delta, 1 bit for a record on schedule
+*   seq         null bitmap, zigzag varint deltas
+*   AQHI, AQI   null bitmap, zigzag varint deltas
+*   CO .. NOISE null bitmap, XOR of each value with the previous (Gorilla)
+*   TimeStamp   null bitmap, varint length and bytes of each string
+*
+* Decoding rebuilds the frames byte for byte, so offsets in the sparse index
+* stay valid. Partitions that wouldn't survive the round trip (damaged frames,
+* records that aren't RawData) are left as msgpack.
+***/
+
+const FLOAT_FIELDS: usize = 12;
+const BLOCK_RECORDS: usize = 4096;
+
+// Columns of a partition, or None if it can't be stored as columns
+pub(crate) fn encode(frames: &[u8]) -> Option<Vec<u8>> {
+    let mut records = Vec::new();
+    let mut pos = 0;
+    while pos < frames.len() {
+        match read_frame(frames, pos) {
+            Frame::Record(payload, next) => {
+                let entry: MpdRecordType = rmps::from_read(payload).ok()?;
+                let value: RawData = rmps::from_read(&entry.datalog[..]).ok()?;
+                records.push((entry, value));
+                pos = next;
+            }
+            Frame::Damaged(_) => return None
+        }
+    }
+
+    let mut columns = Vec::new();
+    for block in records.chunks(BLOCK_RECORDS) {
+        write_column(&mut columns, &encode_block(block));
+    }
+
+    // Only keep columns that give back the same bytes
+    let mut decoded = Vec::new();
+    match BlockReader::new(Path::new(""), &columns[..]).read_to_end(&mut decoded) {
+        Ok(_) if decoded == frames => Some(columns),
+        _ => None
+    }
+}
+
+// Columns of a block of records
+fn encode_block(records: &[(MpdRecordType, RawData)]) -> Vec<u8> {
+    let mut columns = Vec::new();
+    write_varint(&mut columns, records.len() as u64);
+    write_column(&mut columns, &encode_timestamps(records.iter().map(|(entry, _)| entry.timestamp)));
+    write_column(&mut columns, &encode_integers(records.iter().map(|(entry, _)| Some(i64::from(entry.seq)))));
+    write_column(&mut columns, &encode_integers(records.iter().map(|(_, value)| value.AQHI.map(i64::from))));
+    write_column(&mut columns, &encode_integers(records.iter().map(|(_, value)| value.AQI.map(i64::from))));
+    for field in 0..FLOAT_FIELDS {
+        write_column(&mut columns, &encode_floats(records.iter().map(|(_, value)| float_fields(value)[field])));
+    }
+    write_column(&mut columns, &encode_strings(records.iter().map(|(_, value)| value.TimeStamp.as_deref())));
+    return columns;
+}
+
+/***
+* Function decode_block:
+*
+* Purpose:
+* Rebuilds the frames of a block of records from its columns
+***/
+fn decode_block(file: &Path, columns: &[u8]) -> Result<Vec<u8>> {
+    let corrupt = || DbError::CorruptRecord { file: file.to_path_buf(), offset: HEADER_LEN as u64 };
+    let mut reader = ByteReader { bytes: columns, pos: 0 };
+    let count = reader.varint().ok_or_else(corrupt)? as usize;
+
+    let timestamps = reader.column().and_then(|column| decode_timestamps(column, count)).ok_or_else(corrupt)?;
+    let seqs = reader.column().and_then(|column| decode_integers(column, count)).ok_or_else(corrupt)?;
+    let aqhi = reader.column().and_then(|column| decode_integers(column, count)).ok_or_else(corrupt)?;
+    let aqi = reader.column().and_then(|column| decode_integers(column, count)).ok_or_else(corrupt)?;
+    let mut floats = Vec::new();
+    for _ in 0..FLOAT_FIELDS {
+        floats.push(reader.column().and_then(|column| decode_floats(column, count)).ok_or_else(corrupt)?);
+    }
+    let time_stamps = reader.column().and_then(|column| decode_strings(column, count)).ok_or_else(corrupt)?;
+
+    let mut frames = Vec::new();
+    for i in 0..count {
+        let value = RawData {
+            AQHI:       aqhi[i].map(|value| value as i32),
+            AQI:        aqi[i].map(|value| value as i32),
+            CO:         floats[0][i],
+            CO2:        floats[1][i],
+            NO:         floats[2][i],
+            NO2:        floats[3][i],
+            O3:         floats[4][i],
+            PM1:        floats[5][i],
+            PM2_5:      floats[6][i],
+            PM10:       floats[7][i],
+            SO2:        floats[8][i],
+            T:          floats[9][i],
+            RH:         floats[10][i],
+            NOISE:      floats[11][i],
+            TimeStamp:  time_stamps[i].clone(),
+        };
+        let datalog = rmps::to_vec(&value)?;
+        let entry = MpdRecordType {
+            timestamp:  timestamps[i],
+            seq:        seqs[i].ok_or_else(corrupt)? as u32,
+            checksum:   crc32::checksum_ieee(&datalog),
+            datalog:    datalog,
+            source:     None,
+        };
+        frames.extend_from_slice(&encode_frame(&serialize_struct(entry)?));
+    }
+    return Ok(frames);
+}
+
+/***
+* Struct BlockReader:
+*
+* Purpose:
+* Reads the frames of a partition from the blocks written by encode, decoding
+* one block at a time
+***/
+pub(crate) struct BlockReader<R> {
+    file:       PathBuf,
+    source:     R,
+    frames:     Vec<u8>,    // Frames of the current block
+    pos:        usize,      // Bytes of 'frames' already read
+}
+
+impl<R: Read> BlockReader<R> {
+    pub(crate) fn new(file: &Path, source: R) -> BlockReader<R> {
+        BlockReader {
+            file:       file.to_path_buf(),
+            source:     source,
+            frames:     Vec::new(),
+            pos:        0,
+        }
+    }
+
+    // Decode the next block into 'frames', false once the source ends between blocks
+    fn next_block(&mut self) -> Result<bool> {
+        let file = &self.file;
+        let corrupt = || DbError::CorruptRecord { file: file.clone(), offset: HEADER_LEN as u64 };
+        let mut len = 0u64;
+        for shift in (0..64).step_by(7) {
+            let mut byte = [0u8];
+            if self.source.read(&mut byte)? == 0 {
+                if shift == 0 {
+                    return Ok(false);
+                }
+                return Err(corrupt());
+            }
+            len |= u64::from(byte[0] & 0x7f) << shift;
+            if byte[0] & 0x80 == 0 {
+                break;
+            }
+        }
+
+        let mut block = Vec::new();
+        (&mut self.source).take(len).read_to_end(&mut block)?;
+        if block.len() as u64 != len {
+            return Err(corrupt());
+        }
+        self.frames = decode_block(&self.file, &block)?;
+        self.pos = 0;
+        return Ok(true);
+    }
+}
+
+impl<R: Read> Read for BlockReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.frames.len() {
+            if !self.next_block().map_err(io::Error::other)? {
+                return Ok(0);
+            }
+        }
+        let len = buf.len().min(self.frames.len() - self.pos);
+        buf[..len].copy_from_slice(&self.frames[self.pos..self.pos + len]);
+        self.pos += len;
+        return Ok(len);
+    }
+}
+
+fn float_fields(value: &RawData) -> [Option<f32>; FLOAT_FIELDS] {
+    [value.CO, value.CO2, value.NO, value.NO2, value.O3, value.PM1, value.PM2_5,
+     value.PM10, value.SO2, value.T, value.RH, value.NOISE]
+}
+
+/*** Timestamps ***/
+
+// Delta of delta buckets: '0' same interval, then '10', '110', '1110' and '1111' prefixes
+const BUCKETS: [(u64, u32, u32); 4] = [(0b10, 2, 7), (0b110, 3, 9), (0b1110, 4, 12), (0b1111, 4, 64)];
+
+fn encode_timestamps<I>(timestamps: I) -> Vec<u8> where I: Iterator<Item = u64> {
+    let mut writer = BitWriter::default();
+    let mut previous = None;
+    let mut delta = 0i64;
+    for timestamp in timestamps {
+        match previous {
+            None => writer.write(timestamp, 64),
+            Some(previous) => {
+                let next_delta = timestamp.wrapping_sub(previous) as i64;
+                let dod = zigzag(next_delta.wrapping_sub(delta));
+                if dod == 0 {
+                    writer.write(0, 1);
+                } else {
+                    for &(prefix, prefix_len, bits) in &BUCKETS {
+                        if bits == 64 || dod < 1 << bits {
+                            writer.write(prefix, prefix_len);
+                            writer.write(dod, bits);
+                            break;
+                        }
+                    }
+                }
+                delta = next_delta;
+            }
+        }
+        previous = Some(timestamp);
+    }
+    return writer.bytes;
+}
+
+fn decode_timestamps(column: &[u8], count: usize) -> Option<Vec<u64>> {
+    let mut reader = BitReader { bytes: column, pos: 0 };
+    let mut timestamps = Vec::new();
+    let mut delta = 0i64;
+    for i in 0..count {
+        if i == 0 {
+            timestamps.push(reader.read(64)?);
+            continue;
+        }
+        if reader.read(1)? == 1 {
+            // Count the ones of the prefix to find the bucket
+            let mut ones = 1;
+            while ones < 4 && reader.read(1)? == 1 {
+                ones += 1;
+            }
+            let bits = BUCKETS[ones - 1].2;
+            delta = delta.wrapping_add(unzigzag(reader.read(bits)?));
+        }
+        timestamps.push(timestamps[i - 1].wrapping_add(delta as u64));
+    }
+    return Some(timestamps);
+}
+
+/*** Integers ***/
+
+fn encode_integers<I>(values: I) -> Vec<u8> where I: Iterator<Item = Option<i64>> {
+    let values: Vec<Option<i64>> = values.collect();
+    let mut column = encode_bitmap(&values);
+    let mut previous = 0i64;
+    for value in values.into_iter().flatten() {
+        write_varint(&mut column, zigzag(value.wrapping_sub(previous)));
+        previous = value;
+    }
+    return column;
+}
+
+fn decode_integers(column: &[u8], count: usize) -> Option<Vec<Option<i64>>> {
+    let (present, rest) = decode_bitmap(column, count)?;
+    let mut reader = ByteReader { bytes: rest, pos: 0 };
+    let mut previous = 0i64;
+    let mut values = Vec::new();
+    for is_present in present {
+        if !is_present {
+            values.push(None);
+            continue;
+        }
+        previous = previous.wrapping_add(unzigzag(reader.varint()?));
+        values.push(Some(previous));
+    }
+    return Some(values);
+}
+
+/*** Floats ***/
+
+// Each value is XORed with the previous one, slowly changing values leave few meaningful bits
+fn encode_floats<I>(values: I) -> Vec<u8> where I: Iterator<Item = Option<f32>> {
+    let values: Vec<Option<f32>> = values.collect();
+    let mut column = encode_bitmap(&values);
+    let mut writer = BitWriter::default();
+    let mut previous: Option<u32> = None;
+    let mut window: Option<(u32, u32)> = None;     // Leading and trailing zeros of the last stored XOR
+    for value in values.into_iter().flatten() {
+        let bits = value.to_bits();
+        match previous {
+            None => writer.write(u64::from(bits), 32),
+            Some(previous) => {
+                let xor = bits ^ previous;
+                if xor == 0 {
+                    // Same value
+                    writer.write(0, 1);
+                } else {
+                    let leading = xor.leading_zeros();
+                    let trailing = xor.trailing_zeros();
+                    match window {
+                        Some((window_leading, window_trailing)) if leading >= window_leading && trailing >= window_trailing => {
+                            // Fits the previous window
+                            writer.write(0b10, 2);
+                            writer.write(u64::from(xor >> window_trailing), 32 - window_leading - window_trailing);
+                        }
+                        _ => {
+                            // New window: leading zeros, length - 1, meaningful bits
+                            let len = 32 - leading - trailing;
+                            writer.write(0b11, 2);
+                            writer.write(u64::from(leading), 5);
+                            writer.write(u64::from(len - 1), 5);
+                            writer.write(u64::from(xor >> trailing), len);
+                            window = Some((leading, trailing));
+                        }
+                    }
+                }
+            }
+        }
+        previous = Some(bits);
+    }
+    column.extend_from_slice(&writer.bytes);
+    return column;
+}
+
+fn decode_floats(column: &[u8], count: usize) -> Option<Vec<Option<f32>>> {
+    let (present, rest) = decode_bitmap(column, count)?;
+    let mut reader = BitReader { bytes: rest, pos: 0 };
+    let mut previous: Option<u32> = None;
+    let mut window = (0, 0);
+    let mut values = Vec::new();
+    for is_present in present {
+        if !is_present {
+            values.push(None);
+            continue;
+        }
+        let bits = match previous {
+            None => reader.read(32)? as u32,
+            Some(previous) if reader.read(1)? == 0 => previous,
+            Some(previous) => {
+                if reader.read(1)? == 1 {
+                    let leading = reader.read(5)? as u32;
+                    let len = reader.read(5)? as u32 + 1;
+                    if leading + len > 32 {
+                        return None;
+                    }
+                    window = (leading, 32 - leading - len);
+                }
+                let (leading, trailing) = window;
+                previous ^ ((reader.read(32 - leading - trailing)? as u32) << trailing)
+            }
+        };
+        values.push(Some(f32::from_bits(bits)));
+        previous = Some(bits);
+    }
+    return Some(values);
+}
+
+/*** Strings ***/
+
+fn encode_strings<'a, I>(values: I) -> Vec<u8> where I: Iterator<Item = Option<&'a str>> {
+    let values: Vec<Option<&str>> = values.collect();
+    let mut column = encode_bitmap(&values);
+    for value in values.into_iter().flatten() {
+        write_varint(&mut column, value.len() as u64);
+        column.extend_from_slice(value.as_bytes());
+    }
+    return column;
+}
+
+fn decode_strings(column: &[u8], count: usize) -> Option<Vec<Option<String>>> {
+    let (present, rest) = decode_bitmap(column, count)?;
+    let mut reader = ByteReader { bytes: rest, pos: 0 };
+    let mut values = Vec::new();
+    for is_present in present {
+        if !is_present {
+            values.push(None);
+            continue;
+        }
+        let len = reader.varint()? as usize;
+        values.push(Some(String::from_utf8(reader.bytes(len)?.to_vec()).ok()?));
+    }
+    return Some(values);
+}
+
+/*** Shared ***/
+
+// One bit per record, set when the value isn't null
+fn encode_bitmap<T>(values: &[Option<T>]) -> Vec<u8> {
+    let mut bitmap = vec![0u8; values.len().div_ceil(8)];
+    for (i, value) in values.iter().enumerate() {
+        if value.is_some() {
+            bitmap[i / 8] |= 1 << (i % 8);
+        }
+    }
+    return bitmap;
+}
+
+fn decode_bitmap(column: &[u8], count: usize) -> Option<(Vec<bool>, &[u8])> {
+    let len = count.div_ceil(8);
+    if column.len() < len {
+        return None;
+    }
+    let present = (0..count).map(|i| column[i / 8] & (1 << (i % 8)) != 0).collect();
+    return Some((present, &column[len..]));
+}
+
+fn zigzag(value: i64) -> u64 {
+    ((value << 1) ^ (value >> 63)) as u64
+}
+
+fn unzigzag(value: u64) -> i64 {
+    ((value >> 1) as i64) ^ -((value & 1) as i64)
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn write_column(out: &mut Vec<u8>, column: &[u8]) {
+    write_varint(out, column.len() as u64);
+    out.extend_from_slice(column);
+}
+
+struct ByteReader<'a> {
+    bytes:  &'a [u8],
+    pos:    usize,
+}
+
+impl<'a> ByteReader<'a> {
+    fn varint(&mut self) -> Option<u64> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = *self.bytes.get(self.pos)?;
+            self.pos += 1;
+            value |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                return Some(value);
+            }
+        }
+        return None;
+    }
+
+    fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        let bytes = self.bytes.get(self.pos..self.pos.checked_add(len)?)?;
+        self.pos += len;
+        return Some(bytes);
+    }
+
+    fn column(&mut self) -> Option<&'a [u8]> {
+        let len = self.varint()? as usize;
+        return self.bytes(len);
+    }
+}
+
+#[derive(Default)]
+struct BitWriter {
+    bytes:  Vec<u8>,
+    used:   u32,        // Bits used in the last byte, 0 when it's full
+}
+
+impl BitWriter {
+    // Append the low 'count' bits of 'value', most significant first
+    fn write(&mut self, value: u64, count: u32) {
+        for i in (0..count).rev() {
+            if self.used == 0 {
+                self.bytes.push(0);
+            }
+            let last = self.bytes.len() - 1;
+            self.bytes[last] |= (((value >> i) & 1) as u8) << (7 - self.used);
+            self.used = (self.used + 1) % 8;
+        }
+    }
+}
+
+struct BitReader<'a> {
+    bytes:  &'a [u8],
+    pos:    usize,      // In bits
+}
+
+impl<'a> BitReader<'a> {
+    fn read(&mut self, count: u32) -> Option<u64> {
+        let mut value = 0u64;
+        for _ in 0..count {
+            let byte = *self.bytes.get(self.pos / 8)?;
+            value = (value << 1) | u64::from((byte >> (7 - self.pos % 8)) & 1);
+            self.pos += 1;
+        }
+        return Some(value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const T: u64 = 1_577_887_200_000;      // 2020-01-01 14:00:00 UTC
+
+    fn reading(i: u64) -> RawData {
+        RawData {
+            AQHI: if i.is_multiple_of(3) { None } else { Some(i as i32 % 11) }, AQI: Some(-(i as i32)), CO: None,
+            CO2: Some(400.0 + (i * 7919 % 1000) as f32 / 3.0), NO: Some(f32::NAN), NO2: Some(f32::MAX),
+            O3: Some(1.5), PM1: if i.is_multiple_of(2) { Some(-0.0) } else { None }, PM2_5: Some(i as f32), PM10: None,
+            SO2: Some(f32::MIN_POSITIVE), T: Some(21.5 - i as f32 / 100.0), RH: None, NOISE: Some(f32::INFINITY),
+            TimeStamp: if i.is_multiple_of(5) { None } else { Some(format!("2020-01-01T14:00:{:02}", i % 60)) },
+        }
+    }
+
+    fn frame(timestamp: u64, seq: u32, value: &RawData, source: Option<&str>) -> Vec<u8> {
+        let datalog = rmps::to_vec(value).unwrap();
+        let entry = MpdRecordType {
+            timestamp:  timestamp,
+            seq:        seq,
+            checksum:   crc32::checksum_ieee(&datalog),
+            datalog:    datalog,
+            source:     source.map(String::from),
+        };
+        return encode_frame(&serialize_struct(entry).unwrap());
+    }
+
+    fn decode(columns: &[u8]) -> Result<Vec<u8>> {
+        let mut frames = Vec::new();
+        BlockReader::new(Path::new("14"), columns).read_to_end(&mut frames).map_err(DbError::from)?;
+        return Ok(frames);
+    }
+
+    #[test]
+    fn round_trip_gives_back_the_frames() {
+        // Readings on schedule, late, repeated and out of order, over more than one block
+        let mut frames = Vec::new();
+        let mut timestamp = T;
+        for i in 0..(BLOCK_RECORDS as u64 + 100) {
+            timestamp = match i % 7 {
+                0 => timestamp - 2_000,
+                1 => timestamp,
+                2 => timestamp + u64::from(u32::MAX),
+                _ => timestamp + 1_000
+            };
+            frames.extend_from_slice(&frame(timestamp, (i % 4) as u32, &reading(i), None));
+        }
+        let columns = encode(&frames).unwrap();
+        assert!(columns.len() < frames.len() / 2);
+        assert_eq!(decode(&columns).unwrap(), frames);
+        assert_eq!(decode(&[]).unwrap(), Vec::<u8>::new());
+    }
+
+    #[test]
+    fn partitions_that_would_change_stay_frames() {
+        let record = frame(T, 0, &reading(1), None);
+
+        // A source isn't stored as a column, nor a damaged frame or a datalog that isn't RawData
+        assert!(encode(&frame(T, 0, &reading(1), Some("sensor-7"))).is_none());
+        let mut damaged = record.clone();
+        let last = damaged.len() - 1;
+        damaged[last] ^= 0xff;
+        assert!(encode(&damaged).is_none());
+        let entry = MpdRecordType { timestamp: T, seq: 0, datalog: b"raw".to_vec(), checksum: 0, source: None };
+        assert!(encode(&encode_frame(&serialize_struct(entry).unwrap())).is_none());
+    }
+
+    #[test]
+    fn truncated_or_damaged_columns_fail() {
+        let mut frames = Vec::new();
+        for i in 0..20 {
+            frames.extend_from_slice(&frame(T + i * 1_000, 0, &reading(i), None));
+        }
+        let columns = encode(&frames).unwrap();
+        for len in 1..columns.len() {
+            assert!(decode(&columns[..len]).is_err(), "truncated to {} bytes", len);
+        }
+
+        // A record count past what the columns hold, after the 2 byte length of the block
+        assert!(columns[0] & 0x80 != 0 && columns[1] & 0x80 == 0);
+        let mut damaged = columns.clone();
+        damaged[2] = 0x7f;
+        assert!(decode(&damaged).is_err());
+    }
+}
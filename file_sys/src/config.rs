@@ -0,0 +1,332 @@
+use std::fs;
+use std::fs::create_dir_all;
+use std::path::{Path, PathBuf};
+use serde::{Serialize, Deserialize};
+use crate::crypto::{Key, KeyRing};
+use crate::database::{Database, check_writable};
+use crate::error::{DbError, Result};
+use crate::format::Cipher;
+use crate::partition::{Granularity, Zone};
+
+// When appended records are forced to disk
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FsyncPolicy {
+    Never,              // Left to the OS
+    EveryRecord,        // fsync after every insert
+    Interval(u64),      // fsync at most once every N milliseconds
+}
+
+// What a query does with a record whose 'datalog' doesn't match its checksum
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChecksumPolicy {
+    Fail,           // End the query with DbError::ChecksumMismatch
+    Skip,           // Leave the record out and count it in ReadStats
+    Quarantine,     // Skip and copy the record to data/<table>/quarantine
+}
+
+// Messages printed by the database, each level includes the ones before it
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogLevel {
+    Off,
+    Info,       // Files created, sources changed
+    Debug,      // Every read and write
+}
+
+/***
+* Struct Config:
+*
+* Purpose:
+* Deployment settings of a database, set through DatabaseBuilder or loaded
+* from a TOML file. Missing keys fall back to the defaults.
+***/
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub source:             PathBuf,        // Top level directory
+    pub partition:          Granularity,    // Span of one partition file
+    pub time_zone:          Zone,           // Zone partitions are named in
+    pub fsync:              FsyncPolicy,
+    pub wal:                bool,           // Log inserts ahead of the partitions, fsync applies to the log
+    pub write_buffer:       usize,          // Bytes of records buffered per table before they are written, 0 writes each
+    pub flush_interval:     u64,            // Milliseconds records stay buffered at most, 0 flushes each insert
+    pub checksum_policy:    ChecksumPolicy,
+    pub index_interval:     u32,            // Records between sparse index entries, 0 disables the index
+    pub read_buffer:        usize,          // Bytes of a large partition a query holds at once, 0 maps it unless sealed
+    pub cipher:             Cipher,         // Encryption of new partitions, Cipher::None stores plaintext
+    pub key_file:           Option<PathBuf>, // Key new data is encrypted with
+    pub old_key_files:      Vec<PathBuf>,   // Keys replaced by key_file, still read until rotated out
+    pub log_level:          LogLevel,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            source:             PathBuf::from("data"),
+            partition:          Granularity::Hour,
+            time_zone:          Zone::Utc,
+            fsync:              FsyncPolicy::Never,
+            wal:                false,
+            write_buffer:       64 * 1024,
+            flush_interval:     1000,
+            checksum_policy:    ChecksumPolicy::Skip,
+            index_interval:     64,
+            read_buffer:        1024 * 1024,
+            cipher:             Cipher::None,
+            key_file:           None,
+            old_key_files:      Vec::new(),
+            log_level:          LogLevel::Off,
+        }
+    }
+}
+
+impl Config {
+    // Parse a TOML document
+    pub fn from_toml_str(text: &str) -> Result<Config> {
+        toml::from_str(text).map_err(|err| DbError::Config(err.to_string()))
+    }
+
+    // Load a TOML file, Ex: /etc/file_sys.toml
+    pub fn from_toml_file<P: AsRef<Path>>(path: P) -> Result<Config> {
+        let text = fs::read_to_string(path)?;
+        return Config::from_toml_str(&text);
+    }
+
+    // Load the key files named by the configuration
+    pub fn keys(&self) -> Result<KeyRing> {
+        let mut keys = KeyRing::default();
+        if let Some(key_file) = &self.key_file {
+            keys.current = Some(Key::from_file(key_file)?);
+        }
+        for key_file in &self.old_key_files {
+            keys.old.push(Key::from_file(key_file)?);
+        }
+        return Ok(keys);
+    }
+}
+
+/***
+* Struct DatabaseBuilder:
+*
+* Purpose:
+* Configures and opens a Database
+***/
+#[derive(Clone, Debug, Default)]
+pub struct DatabaseBuilder {
+    config: Config,
+    keys:   KeyRing,    // Keys given in code, used alongside the key files
+}
+
+impl DatabaseBuilder {
+    // Builder with the default configuration
+    pub fn new() -> DatabaseBuilder {
+        DatabaseBuilder::default()
+    }
+
+    // Builder starting from an existing configuration
+    pub fn from_config(config: Config) -> DatabaseBuilder {
+        DatabaseBuilder { config: config, keys: KeyRing::default() }
+    }
+
+    // Builder starting from a TOML file, options can still be overridden
+    pub fn from_toml_file<P: AsRef<Path>>(path: P) -> Result<DatabaseBuilder> {
+        Ok(DatabaseBuilder::from_config(Config::from_toml_file(path)?))
+    }
+
+    pub fn source<P: Into<PathBuf>>(mut self, source: P) -> DatabaseBuilder {
+        self.config.source = source.into();
+        self
+    }
+
+    pub fn partition(mut self, granularity: Granularity) -> DatabaseBuilder {
+        self.config.partition = granularity;
+        self
+    }
+
+    pub fn time_zone(mut self, zone: Zone) -> DatabaseBuilder {
+        self.config.time_zone = zone;
+        self
+    }
+
+    pub fn fsync(mut self, policy: FsyncPolicy) -> DatabaseBuilder {
+        self.config.fsync = policy;
+        self
+    }
+
+    pub fn wal(mut self, enabled: bool) -> DatabaseBuilder {
+        self.config.wal = enabled;
+        self
+    }
+
+    pub fn write_buffer(mut self, bytes: usize) -> DatabaseBuilder {
+        self.config.write_buffer = bytes;
+        self
+    }
+
+    pub fn flush_interval(mut self, millis: u64) -> DatabaseBuilder {
+        self.config.flush_interval = millis;
+        self
+    }
+
+    pub fn checksum_policy(mut self, policy: ChecksumPolicy) -> DatabaseBuilder {
+        self.config.checksum_policy = policy;
+        self
+    }
+
+    pub fn index_interval(mut self, records: u32) -> DatabaseBuilder {
+        self.config.index_interval = records;
+        self
+    }
+
+    pub fn read_buffer(mut self, bytes: usize) -> DatabaseBuilder {
+        self.config.read_buffer = bytes;
+        self
+    }
+
+    pub fn cipher(mut self, cipher: Cipher) -> DatabaseBuilder {
+        self.config.cipher = cipher;
+        self
+    }
+
+    pub fn key_file<P: Into<PathBuf>>(mut self, path: P) -> DatabaseBuilder {
+        self.config.key_file = Some(path.into());
+        self
+    }
+
+    pub fn old_key_file<P: Into<PathBuf>>(mut self, path: P) -> DatabaseBuilder {
+        self.config.old_key_files.push(path.into());
+        self
+    }
+
+    // Key new data is encrypted with, Ex: Key::from_passphrase, takes precedence over key_file
+    pub fn key(mut self, key: Key) -> DatabaseBuilder {
+        self.keys.current = Some(key);
+        self
+    }
+
+    // Key that may still be needed to read older data
+    pub fn old_key(mut self, key: Key) -> DatabaseBuilder {
+        self.keys.old.push(key);
+        self
+    }
+
+    pub fn log_level(mut self, level: LogLevel) -> DatabaseBuilder {
+        self.config.log_level = level;
+        self
+    }
+
+    // Create the source directory if needed and open the database, recovering it after a crash
+    pub fn build(self) -> Result<Database> {
+        let mut keys = self.config.keys()?;
+        if self.keys.current.is_some() {
+            keys.current = self.keys.current;
+        }
+        keys.old.extend(self.keys.old);
+        if self.config.cipher != Cipher::None && keys.current.is_none() {
+            return Err(DbError::Config("a cipher needs a key or key_file".to_string()));
+        }
+
+        create_dir_all(&self.config.source)?;
+        check_writable(&self.config.source)?;
+        let database = Database::from_config(self.config).with_keys(keys);
+        database.recover()?;
+        return Ok(database);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn from_toml_file_reads_every_key() {
+        let dir = TempDir::new("config_file");
+        let file = dir.path().join("file_sys.toml");
+        fs::write(&file, "source = \"other\"\npartition = \"minute\"\ntime_zone = \"-06:00\"\n\
+                          fsync = { interval = 1000 }\nwal = true\nwrite_buffer = 0\n\
+                          flush_interval = 250\nchecksum_policy = \"quarantine\"\n\
+                          index_interval = 16\nread_buffer = 4096\nlog_level = \"debug\"\n").unwrap();
+        let config = Config::from_toml_file(&file).unwrap();
+        assert_eq!(config, Config {
+            source:             PathBuf::from("other"),
+            partition:          Granularity::Minute,
+            time_zone:          Zone::Offset(-360),
+            fsync:              FsyncPolicy::Interval(1000),
+            wal:                true,
+            write_buffer:       0,
+            flush_interval:     250,
+            checksum_policy:    ChecksumPolicy::Quarantine,
+            index_interval:     16,
+            read_buffer:        4096,
+            cipher:             Cipher::None,
+            key_file:           None,
+            old_key_files:      Vec::new(),
+            log_level:          LogLevel::Debug,
+        });
+        assert!(matches!(Config::from_toml_file(dir.path().join("missing.toml")), Err(DbError::Io(_))));
+    }
+
+    #[test]
+    fn missing_keys_fall_back_to_defaults() {
+        let config = Config::from_toml_str("partition = \"day\"").unwrap();
+        assert_eq!(config, Config { partition: Granularity::Day, ..Config::default() });
+        assert_eq!(Config::from_toml_str("").unwrap(), Config::default());
+    }
+
+    #[test]
+    fn bad_values_are_config_errors() {
+        assert!(matches!(Config::from_toml_str("time_zone = \"mars\""), Err(DbError::Config(_))));
+        assert!(matches!(Config::from_toml_str("time_zone = \"+24:00\""), Err(DbError::Config(_))));
+        assert!(matches!(Config::from_toml_str("fsync = \"sometimes\""), Err(DbError::Config(_))));
+        assert!(matches!(Config::from_toml_str("fsync = { interval = -1 }"), Err(DbError::Config(_))));
+        assert!(matches!(Config::from_toml_str("partition = 1"), Err(DbError::Config(_))));
+        assert!(matches!(Config::from_toml_str("checksum_policy = \"ignore\""), Err(DbError::Config(_))));
+        assert!(matches!(Config::from_toml_str("cipher = \"rot13\""), Err(DbError::Config(_))));
+    }
+
+    #[test]
+    fn cipher_needs_a_key() {
+        let dir = TempDir::new("config_cipher");
+        let builder = DatabaseBuilder::new().source(dir.path()).cipher(Cipher::Aes256Gcm);
+        assert!(matches!(builder.clone().build(), Err(DbError::Config(_))));
+        assert!(matches!(builder.key_file(dir.path().join("missing.key")).build(), Err(DbError::Io(_))));
+    }
+
+    #[test]
+    fn builder_overrides_the_file() {
+        let dir = TempDir::new("config_builder");
+        let file = dir.path().join("file_sys.toml");
+        fs::write(&file, "partition = \"minute\"\nfsync = \"every_record\"\n").unwrap();
+        let source = dir.path().join("data");
+        let database = DatabaseBuilder::from_toml_file(&file).unwrap()
+            .source(&source)
+            .time_zone(Zone::Local)
+            .fsync(FsyncPolicy::Never)
+            .write_buffer(4096)
+            .flush_interval(0)
+            .checksum_policy(ChecksumPolicy::Fail)
+            .index_interval(0)
+            .read_buffer(0)
+            .build().unwrap();
+        assert!(source.is_dir());
+        assert_eq!(database.config(), &Config {
+            source:             source,
+            partition:          Granularity::Minute,
+            time_zone:          Zone::Local,
+            fsync:              FsyncPolicy::Never,
+            wal:                false,
+            write_buffer:       4096,
+            flush_interval:     0,
+            checksum_policy:    ChecksumPolicy::Fail,
+            index_interval:     0,
+            read_buffer:        0,
+            cipher:             Cipher::None,
+            key_file:           None,
+            old_key_files:      Vec::new(),
+            log_level:          LogLevel::Off,
+        });
+    }
+}
@@ -0,0 +1,505 @@
+use std::convert::TryInto;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use aes_gcm::Aes256Gcm;
+use chacha20poly1305::ChaCha20Poly1305;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use sha2::{Digest, Sha256};
+use crate::error::{DbError, Result};
+use crate::format::{Cipher, Frame, PartitionHeader, encode_frame, read_frame};
+
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+const CHUNK_LEN: usize = 64 * 1024;
+const PASSPHRASE_ROUNDS: u32 = 100_000;
+
+/***
+* Struct Key:
+*
+* Purpose:
+* 256 bit key for partition encryption. Its id, the first 4 bytes of its
+* SHA-256, is stored with everything it encrypts so older keys can still be
+* found after a rotation.
+***/
+#[derive(Clone, PartialEq)]
+pub struct Key {
+    id:     u32,
+    bytes:  [u8; KEY_LEN],
+}
+
+impl Key {
+    pub fn new(bytes: [u8; KEY_LEN]) -> Key {
+        let digest = Sha256::digest(bytes);
+        Key {
+            id:     u32::from_le_bytes([digest[0], digest[1], digest[2], digest[3]]),
+            bytes:  bytes,
+        }
+    }
+
+    // Key file holding 32 raw bytes or 64 hex digits
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Key> {
+        let path = path.as_ref();
+        let contents = fs::read(path)?;
+        let invalid = || DbError::Config(format!("{:?} must hold a 32 byte key, raw or as 64 hex digits", path));
+
+        if contents.len() == KEY_LEN {
+            return Ok(Key::new(contents[..].try_into().map_err(|_| invalid())?));
+        }
+        let text = String::from_utf8(contents).map_err(|_| invalid())?;
+        let text = text.trim();
+        if text.len() != KEY_LEN * 2 {
+            return Err(invalid());
+        }
+        let mut bytes = [0u8; KEY_LEN];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(text.get(i * 2..i * 2 + 2).ok_or_else(invalid)?, 16).map_err(|_| invalid())?;
+        }
+        return Ok(Key::new(bytes));
+    }
+
+    // Key derived from a passphrase with PBKDF2-HMAC-SHA256, Ex: salted with the device serial number
+    pub fn from_passphrase(passphrase: &str, salt: &[u8]) -> Key {
+        let mut bytes = [0u8; KEY_LEN];
+        pbkdf2::pbkdf2_hmac::<Sha256>(passphrase.as_bytes(), salt, PASSPHRASE_ROUNDS, &mut bytes);
+        return Key::new(bytes);
+    }
+
+    // Identifies the key without revealing it
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+}
+
+impl fmt::Debug for Key {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Key({:08x})", self.id)
+    }
+}
+
+// Key new data is encrypted with and the older ones still accepted for reading
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct KeyRing {
+    pub current:    Option<Key>,
+    pub old:        Vec<Key>,
+}
+
+impl KeyRing {
+    fn find(&self, id: u32) -> Option<&Key> {
+        self.current.iter().chain(self.old.iter()).find(|key| key.id == id)
+    }
+
+    pub(crate) fn current(&self) -> Result<&Key> {
+        self.current.as_ref().ok_or_else(|| DbError::Encryption("no key is configured".to_string()))
+    }
+
+    // True unless 'data' was encrypted with the current key
+    pub(crate) fn is_stale(&self, data: &[u8]) -> bool {
+        match (&self.current, data.get(0..4)) {
+            (Some(key), Some(id)) => u32::from_le_bytes(id.try_into().unwrap_or_default()) != key.id,
+            _ => true
+        }
+    }
+}
+
+/***
+* Function encrypt:
+*
+* Purpose:
+* Encrypts 'plaintext' as key id u32 | nonce | ciphertext and tag
+***/
+pub(crate) fn encrypt(cipher: Cipher, key: &Key, aad: &[u8], plaintext: &[u8]) -> Result<Vec<u8>> {
+    let payload = Payload { msg: plaintext, aad: aad };
+    let (nonce, ciphertext) = match cipher {
+        Cipher::None => return Ok(plaintext.to_vec()),
+        Cipher::ChaCha20Poly1305 => {
+            let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+            (nonce, ChaCha20Poly1305::new(&key.bytes.into()).encrypt(&nonce, payload))
+        }
+        Cipher::Aes256Gcm => {
+            let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+            (nonce, Aes256Gcm::new(&key.bytes.into()).encrypt(&nonce, payload))
+        }
+    };
+    let ciphertext = ciphertext.map_err(|_| DbError::Encryption("payload could not be encrypted".to_string()))?;
+
+    let mut data = Vec::with_capacity(4 + NONCE_LEN + ciphertext.len());
+    data.extend_from_slice(&key.id.to_le_bytes());
+    data.extend_from_slice(&nonce);
+    data.extend_from_slice(&ciphertext);
+    return Ok(data);
+}
+
+/***
+* Function decrypt:
+*
+* Purpose:
+* Reverses encrypt with the key named in 'data'. None if the ciphertext or
+* 'aad' were tampered with, DbError::MissingKey if the key isn't in the ring.
+***/
+pub(crate) fn decrypt(cipher: Cipher, keys: &KeyRing, aad: &[u8], data: &[u8]) -> Result<Option<Vec<u8>>> {
+    if cipher == Cipher::None {
+        return Ok(Some(data.to_vec()));
+    }
+    if data.len() < 4 + NONCE_LEN {
+        return Ok(None);
+    }
+
+    let id = u32::from_le_bytes(data[0..4].try_into().unwrap_or_default());
+    let key = keys.find(id).ok_or(DbError::MissingKey(id))?;
+    let nonce = data[4..4 + NONCE_LEN].into();
+    let payload = Payload { msg: &data[4 + NONCE_LEN..], aad: aad };
+    let plaintext = match cipher {
+        Cipher::None => Ok(data.to_vec()),
+        Cipher::ChaCha20Poly1305 => ChaCha20Poly1305::new(&key.bytes.into()).decrypt(nonce, payload),
+        Cipher::Aes256Gcm => Aes256Gcm::new(&key.bytes.into()).decrypt(nonce, payload),
+    };
+    return Ok(plaintext.ok());
+}
+
+/***
+* Function decrypt_frames:
+*
+* Purpose:
+* Frames of an open partition, starting at partition offset 'start', with their
+* payloads decrypted. Damaged frames are kept as they are so readers skip them
+* the same way. Also returns whether any payload used a key other than the
+* current one.
+***/
+pub(crate) fn decrypt_frames(file: &Path, header: &PartitionHeader, keys: &KeyRing, frames: &[u8], start: u64)
+                             -> Result<(Vec<u8>, bool)> {
+    let mut plaintext = Vec::with_capacity(frames.len());
+    let mut stale = false;
+    let mut pos = 0;
+    while pos < frames.len() {
+        match read_frame(frames, pos) {
+            Frame::Record(payload, next) => {
+                stale |= keys.is_stale(payload);
+                let offset = start + pos as u64;
+                match decrypt(header.cipher, keys, &header.aad(offset), payload)? {
+                    Some(payload) => plaintext.extend_from_slice(&encode_frame(&payload)),
+                    None => return Err(DbError::Decryption { file: file.to_path_buf(), offset: offset })
+                }
+                pos = next;
+            }
+            Frame::Damaged(next) => {
+                plaintext.extend_from_slice(&frames[pos..next]);
+                pos = next;
+            }
+        }
+    }
+    return Ok((plaintext, stale));
+}
+
+// Frames with their payloads encrypted with the current key, written at partition offset 'start', see decrypt_frames
+pub(crate) fn encrypt_frames(header: &PartitionHeader, keys: &KeyRing, frames: &[u8], start: u64) -> Result<Vec<u8>> {
+    let key = keys.current()?;
+    let mut ciphertext = Vec::with_capacity(frames.len());
+    let mut pos = 0;
+    while pos < frames.len() {
+        match read_frame(frames, pos) {
+            Frame::Record(payload, next) => {
+                let aad = header.aad(start + ciphertext.len() as u64);
+                ciphertext.extend_from_slice(&encode_frame(&encrypt(header.cipher, key, &aad, payload)?));
+                pos = next;
+            }
+            Frame::Damaged(next) => {
+                ciphertext.extend_from_slice(&frames[pos..next]);
+                pos = next;
+            }
+        }
+    }
+    return Ok(ciphertext);
+}
+
+/***
+* Function encrypt_chunks:
+*
+* Purpose:
+* Encrypts the body of a sealed partition in chunks of CHUNK_LEN bytes, the
+* last one shorter, so it can be decrypted as it is read, see ChunkReader.
+* Each chunk is bound to its offset in the partition and to whether it is the
+* last one, so chunks can't be reordered and the body can't be cut short at a
+* chunk boundary without failing authentication.
+***/
+pub(crate) fn encrypt_chunks(header: &PartitionHeader, keys: &KeyRing, body: &[u8], start: u64) -> Result<Vec<u8>> {
+    let key = keys.current()?;
+    let mut ciphertext = Vec::with_capacity(body.len() + (body.len() / CHUNK_LEN + 1) * encrypted_len(header.cipher, 0));
+    let mut chunks = body.chunks(CHUNK_LEN).peekable();
+    if chunks.peek().is_none() {
+        return encrypt(header.cipher, key, &chunk_aad(header, start, true), &[]);
+    }
+    while let Some(chunk) = chunks.next() {
+        let aad = chunk_aad(header, start + ciphertext.len() as u64, chunks.peek().is_none());
+        ciphertext.extend_from_slice(&encrypt(header.cipher, key, &aad, chunk)?);
+    }
+    return Ok(ciphertext);
+}
+
+fn chunk_aad(header: &PartitionHeader, offset: u64, last: bool) -> Vec<u8> {
+    let mut aad = header.aad(offset);
+    aad.push(last as u8);
+    return aad;
+}
+
+/***
+* Struct ChunkReader:
+*
+* Purpose:
+* Decrypts a body written by encrypt_chunks as it is read, holding one chunk
+* at a time. A chunk failing authentication, Ex: modified, moved or the body
+* truncated, fails the read with DbError::Decryption.
+***/
+pub(crate) struct ChunkReader<R> {
+    file:       PathBuf,
+    header:     PartitionHeader,
+    keys:       KeyRing,
+    source:     R,
+    chunk:      Vec<u8>,    // Plaintext of the current chunk
+    pos:        usize,      // Bytes of 'chunk' already read
+    offset:     u64,        // Partition offset of the next chunk
+    done:       bool,       // The last chunk was decrypted
+}
+
+impl<R: BufRead> ChunkReader<R> {
+    pub(crate) fn new(file: &Path, header: &PartitionHeader, keys: &KeyRing, source: R, start: u64) -> ChunkReader<R> {
+        ChunkReader {
+            file:       file.to_path_buf(),
+            header:     *header,
+            keys:       keys.clone(),
+            source:     source,
+            chunk:      Vec::new(),
+            pos:        0,
+            offset:     start,
+            done:       false,
+        }
+    }
+
+    // Decrypt the next chunk into 'chunk', a short read or the end of the source marks the last one
+    fn next_chunk(&mut self) -> Result<()> {
+        let mut ciphertext = Vec::with_capacity(encrypted_len(self.header.cipher, CHUNK_LEN));
+        (&mut self.source).take(encrypted_len(self.header.cipher, CHUNK_LEN) as u64).read_to_end(&mut ciphertext)?;
+        let last = self.source.fill_buf()?.is_empty();
+        let aad = chunk_aad(&self.header, self.offset, last);
+        self.chunk = match decrypt(self.header.cipher, &self.keys, &aad, &ciphertext)? {
+            Some(chunk) => chunk,
+            None => return Err(DbError::Decryption { file: self.file.clone(), offset: self.offset })
+        };
+        self.pos = 0;
+        self.offset += ciphertext.len() as u64;
+        self.done = last;
+        return Ok(());
+    }
+}
+
+impl<R: BufRead> Read for ChunkReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.chunk.len() {
+            if self.done {
+                return Ok(0);
+            }
+            self.next_chunk().map_err(io::Error::other)?;
+        }
+        let len = buf.len().min(self.chunk.len() - self.pos);
+        buf[..len].copy_from_slice(&self.chunk[self.pos..self.pos + len]);
+        self.pos += len;
+        return Ok(len);
+    }
+}
+
+// Length of 'len' bytes once encrypted with 'cipher', see encrypt
+pub(crate) fn encrypted_len(cipher: Cipher, len: usize) -> usize {
+    match cipher {
+        Cipher::None => len,
+        _ => 4 + NONCE_LEN + len + TAG_LEN
+    }
+}
+
+/***
+* Function encrypt_sidecar:
+*
+* Purpose:
+* Encrypts the contents of a sidecar of a partition, Ex: an index entry or its
+* summary, as the partition is so timestamps and statistics aren't left in
+* plaintext beside it. The partition's header and 'tag', naming the sidecar,
+* are authenticated with it: a sidecar copied from another partition or kept
+* after its partition was rewritten fails to decrypt.
+***/
+pub(crate) fn encrypt_sidecar(header: &PartitionHeader, keys: &KeyRing, tag: &[u8], plaintext: &[u8]) -> Result<Vec<u8>> {
+    if header.cipher == Cipher::None {
+        return Ok(plaintext.to_vec());
+    }
+    return encrypt(header.cipher, keys.current()?, &sidecar_aad(header, tag), plaintext);
+}
+
+// Reverses encrypt_sidecar, None if it doesn't authenticate or its key is gone, sidecars are only a cache
+pub(crate) fn decrypt_sidecar(header: &PartitionHeader, keys: &KeyRing, tag: &[u8], data: &[u8]) -> Option<Vec<u8>> {
+    decrypt(header.cipher, keys, &sidecar_aad(header, tag), data).ok()?
+}
+
+fn sidecar_aad(header: &PartitionHeader, tag: &[u8]) -> Vec<u8> {
+    let mut aad = header.encode().to_vec();
+    aad.extend_from_slice(tag);
+    return aad;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::PathBuf;
+    use crc::crc32;
+    use crate::database::Database;
+    use crate::format::HEADER_LEN;
+    use crate::index::index_path;
+    use crate::raw_data::RawData;
+    use crate::registry::{Duplicates, TableOptions};
+    use crate::seal::{is_stale, read_header};
+    use crate::summary::{read_summary, summary_path};
+    use crate::testing::{TempDir, entry};
+    use super::*;
+
+    const T: u64 = 1_577_887_200_000;      // 2020-01-01 14:00:00 UTC
+
+    fn open(dir: &TempDir, cipher: Cipher, key: u8, old: Option<u8>) -> Database {
+        let mut builder = Database::builder().source(dir.path()).cipher(cipher).key(Key::new([key; KEY_LEN]));
+        if let Some(old) = old {
+            builder = builder.old_key(Key::new([old; KEY_LEN]));
+        }
+        return builder.build().unwrap();
+    }
+
+    fn partition(dir: &TempDir) -> PathBuf {
+        dir.path().join("levels").join("20200101").join("14")
+    }
+
+    fn read(database: &Database) -> (Vec<Vec<u8>>, u64) {
+        let mut records = database.range("levels", T, T + 1000).unwrap();
+        let datalogs = records.by_ref().map(|record| record.unwrap().datalog).collect();
+        return (datalogs, records.stats().auth_failures);
+    }
+
+    // Rewrite bytes of the partition header and fix its checksum up, as someone with the SD card could
+    fn tamper_header(file: &PathBuf, f: impl FnOnce(&mut [u8])) {
+        let mut bytes = fs::read(file).unwrap();
+        f(&mut bytes[..HEADER_LEN]);
+        let checksum = crc32::checksum_ieee(&bytes[0..20]);
+        bytes[20..24].copy_from_slice(&checksum.to_le_bytes());
+        fs::write(file, bytes).unwrap();
+    }
+
+    #[test]
+    fn round_trip_hides_records() {
+        for cipher in &[Cipher::ChaCha20Poly1305, Cipher::Aes256Gcm] {
+            let dir = TempDir::new("crypto_round_trip");
+            let database = open(&dir, *cipher, 1, None);
+            database.create_table("levels", TableOptions::default()).unwrap();
+            database.insert(entry("levels", T, b"secret reading")).unwrap();
+            database.insert(entry("levels", T + 1, b"another reading")).unwrap();
+
+            let bytes = fs::read(partition(&dir)).unwrap();
+            assert!(!bytes.windows(6).any(|window| window == b"secret"));
+            assert_eq!(read(&database), (vec![b"secret reading".to_vec(), b"another reading".to_vec()], 0));
+        }
+    }
+
+    #[test]
+    fn stripped_or_swapped_cipher_is_caught() {
+        let dir = TempDir::new("crypto_downgrade");
+        let database = open(&dir, Cipher::ChaCha20Poly1305, 1, None);
+        database.create_table("levels", TableOptions::default()).unwrap();
+        database.insert(entry("levels", T, b"a")).unwrap();
+        drop(database);
+
+        // Another cipher fails authentication, the header is part of it
+        tamper_header(&partition(&dir), |header| header[7] = 2 << 4);
+        let database = open(&dir, Cipher::Aes256Gcm, 1, None);
+        assert_eq!(read(&database), (vec![], 1));
+
+        // No cipher at all is refused while one is configured
+        tamper_header(&partition(&dir), |header| header[7] = 0);
+        match database.range("levels", T, T + 1000).unwrap().next() {
+            Some(Err(DbError::InvalidHeader { .. })) => {}
+            other => panic!("plaintext partition read: {:?}", other)
+        }
+        assert!(!database.verify().unwrap().is_clean());
+    }
+
+    #[test]
+    fn moved_frames_fail_authentication() {
+        let dir = TempDir::new("crypto_reorder");
+        let database = open(&dir, Cipher::ChaCha20Poly1305, 1, None);
+        database.create_table("levels", TableOptions::default()).unwrap();
+        database.insert(entry("levels", T, b"a")).unwrap();
+        database.insert(entry("levels", T + 1, b"b")).unwrap();
+        drop(database);
+
+        // Swap the two frames, both the same length
+        let mut bytes = fs::read(partition(&dir)).unwrap();
+        let len = (bytes.len() - HEADER_LEN) / 2;
+        let first = bytes[HEADER_LEN..HEADER_LEN + len].to_vec();
+        bytes.copy_within(HEADER_LEN + len.., HEADER_LEN);
+        let end = bytes.len() - len;
+        bytes[end..].copy_from_slice(&first);
+        fs::write(partition(&dir), bytes).unwrap();
+
+        let database = open(&dir, Cipher::ChaCha20Poly1305, 1, None);
+        assert_eq!(read(&database), (vec![], 2));
+    }
+
+    #[test]
+    fn rotation_covers_every_partition() {
+        let dir = TempDir::new("crypto_rotate");
+        let database = open(&dir, Cipher::ChaCha20Poly1305, 1, None);
+        let options = TableOptions { compression: crate::format::Compression::Zstd, ..TableOptions::default() };
+        database.create_table("levels", options).unwrap();
+        database.insert(entry("levels", T, b"sealed")).unwrap();
+        database.insert(entry("levels", T + 3_600_000, b"current")).unwrap();
+        drop(database);
+
+        let database = open(&dir, Cipher::ChaCha20Poly1305, 2, Some(1));
+        assert_eq!(database.rotate_keys().unwrap(), 2);
+        let rotated = open(&dir, Cipher::ChaCha20Poly1305, 2, None);
+        for hour in &["14", "15"] {
+            assert!(!is_stale(&dir.path().join("levels").join("20200101").join(hour), rotated.keys()).unwrap());
+        }
+        assert_eq!(read(&rotated), (vec![b"sealed".to_vec()], 0));
+        assert_eq!(rotated.rotate_keys().unwrap(), 0);
+    }
+
+    #[test]
+    fn sidecars_are_encrypted() {
+        let dir = TempDir::new("crypto_sidecars");
+        let database = Database::builder().source(dir.path()).cipher(Cipher::ChaCha20Poly1305)
+            .key(Key::new([1; KEY_LEN])).index_interval(1).build().unwrap();
+        let options = TableOptions { duplicates: Duplicates::Ignore, ..TableOptions::of::<RawData>() };
+        database.create_table("levels", options).unwrap();
+        let reading = RawData {
+            AQHI: None, AQI: Some(3), CO: None, CO2: None, NO: None, NO2: None, O3: Some(1.5), PM1: None,
+            PM2_5: None, PM10: None, SO2: None, T: None, RH: None, NOISE: None, TimeStamp: None,
+        };
+        let levels = database.table::<RawData>("levels").unwrap();
+        for i in 0..3 {
+            levels.insert_at(T + i, &reading).unwrap();
+        }
+        levels.insert_at(T + 3_600_000, &reading).unwrap();
+
+        // Neither the timestamps indexed nor the field names summarized are in plaintext
+        let index = fs::read(index_path(&partition(&dir))).unwrap();
+        assert!(!index.windows(8).any(|window| window == (T + 1).to_le_bytes()));
+        let stored = fs::read(summary_path(&partition(&dir))).unwrap();
+        assert!(!stored.windows(2).any(|window| window == b"O3"));
+
+        assert_eq!(read_summary(&partition(&dir), database.keys()).unwrap().records, 3);
+        let records = database.range("levels", T + 2, T + 1000).unwrap().map(|record| record.unwrap().timestamp);
+        assert_eq!(records.collect::<Vec<_>>(), vec![T + 2]);
+
+        // Bound to the partition's header, a summary copied to another partition doesn't decrypt
+        let header = read_header(&partition(&dir)).unwrap();
+        assert!(decrypt_sidecar(&header, database.keys(), b"sum", &stored).is_some());
+        let other = PartitionHeader { start: T + 3_600_000, ..header };
+        assert!(decrypt_sidecar(&other, database.keys(), b"sum", &stored).is_none());
+    }
+}
@@ -1,292 +1,855 @@
 extern crate chrono;
 
-use std::convert::TryFrom;
-use std::io;
+use std::borrow::Cow;
+use std::cmp::{max, min};
 use std::io::prelude::*;
 use std::fs;
 use std::fs::File;
 use std::fs::create_dir_all;
-use std::fs::OpenOptions;
-use std::fs::metadata;
-use std::path::Path;
-use std::io::{Error, ErrorKind};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Instant;
 use chrono::prelude::*;
-use chrono::Duration;
 use serde::{Serialize, Deserialize};
-use crc::{crc32, Hasher32};
-use rmps::{Serializer, Deserializer};
-
-static DATE_FORMAT: &str = "%Y%m%d";
-static TIME_FORMAT: &str = "%H";
+use serde::de::DeserializeOwned;
+use crc::crc32;
+use rmps::Serializer;
+use crate::config::{Config, DatabaseBuilder, FsyncPolicy, LogLevel};
+use crate::crypto::{KeyRing, encrypt};
+use crate::dedup::filter_path;
+use crate::error::{DbError, Result};
+use crate::format::{Cipher, Codec, Compression, PartitionHeader, FORMAT_VERSION, MAX_FRAME_LEN, encode_frame};
+use crate::fsck::{FsckReport, check_source};
+use crate::index::{append_entry, index_path};
+use crate::order::unsorted_path;
+use crate::partition::{Partitioning, DATE_FORMAT, get_datetime, get_timestamp};
+use crate::query::{Nearest, Range, partition_days};
+use crate::registry::{Duplicates, Manifest, TableOptions, read_manifest, write_manifest, check_table_name};
+use crate::seal::{is_stale, read_header, recompress, write_partition};
+use crate::raw_data::RawData;
+use crate::summary::{Summarize, Summarizer, read_summary, store_summary, summarizer_of, summary_path};
+use crate::table::{Schema, Table};
+use crate::wal::{Wal, recover_source};
+use crate::writer::{Flusher, PartitionWriter, Shared, TableWriters, sync_due};
 
 pub struct Database {
-    source:         &'static str,
+    config:         Config,
+    keys:           KeyRing,                                // Loaded from the configured key files
+    tables:         Mutex<HashMap<String, Manifest>>,      // Manifests loaded so far
+    last_sync:      Arc<Mutex<Instant>>,                    // Last fsync for FsyncPolicy::Interval
+    writers:        Arc<Mutex<HashMap<String, TableWriters>>>,  // Partitions each table is writing to
+    flusher:        Option<Flusher>,                        // Flushes and syncs 'writers' and 'wal' on a timer
+    wal:            Arc<Mutex<Option<Wal>>>,                // Write-ahead log once recovered, if Config::wal
+    summarizers:    Mutex<HashMap<String, Summarizer>>,     // By table schema, for summaries of closed partitions
+    recovered:      Mutex<bool>,                            // Whether the source was recovered after a crash
 }
 
-pub struct Entry {
-    pub table: &'static str,
+pub struct Entry<'a> {
+    pub table:      &'a str,
     pub data:       Vec<u8>,
+    pub timestamp:  Option<u64>,    // Capture time in milliseconds since epoch, None for now
+    pub source:     Option<String>, // Device the record came from, with the timestamp its key, see Duplicates
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
 pub struct MpdRecordType {
-    id:         u32,        // Record identifier
-    datalog:    Vec<u8>,    // Byte array of length 'size'
-    checksum:   u32,        // CRC-32 checksum of 'datalog'
+    pub timestamp:  u64,        // Capture time in milliseconds since epoch
+    pub seq:        u32,        // Orders records sharing a timestamp
+    #[serde(with = "serde_bytes")]
+    pub datalog:    Vec<u8>,    // Byte array of length 'size', stored as msgpack bin
+    pub checksum:   u32,        // CRC-32 checksum of 'datalog'
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source:     Option<String>, // Absent from records stored without one, so they keep their encoding
+}
+
+// MpdRecordType decoded in place, see Range::next_with
+#[derive(Deserialize, Debug, PartialEq)]
+pub struct MpdRecordRef<'a> {
+    pub timestamp:  u64,
+    pub seq:        u32,
+    #[serde(borrow, with = "serde_bytes")]
+    pub datalog:    Cow<'a, [u8]>,  // Borrowed, copied only from records stored before datalog was bin
+    pub checksum:   u32,
+    #[serde(default)]
+    pub source:     Option<Cow<'a, str>>,
+}
+
+impl MpdRecordRef<'_> {
+    // Record owning its 'datalog'
+    pub fn into_record(self) -> MpdRecordType {
+        MpdRecordType {
+            timestamp:  self.timestamp,
+            seq:        self.seq,
+            datalog:    self.datalog.into_owned(),
+            checksum:   self.checksum,
+            source:     self.source.map(Cow::into_owned),
+        }
+    }
 }
 
 pub trait DB {
     // Set a new source for the database
-    fn set_source(&self, source: &str) -> Result<(), io::Error>;
+    fn set_source(&mut self, source: &str) -> Result<()>;
     
     // Lists all the databases within the current data source
-    fn list_db(&self);
+    fn list_db(&self) -> Result<()>;
 
     // Insert into database
-    fn insert(&self, entry: Entry) -> Result<(), io::Error>;
+    fn insert(&self, entry: Entry<'_>) -> Result<()>;
 
     // Find a particular file
-    fn find_file(&self, source: &str) -> Result<Vec<u8>, io::Error>;
+    fn find_file(&self, source: &str) -> Result<Vec<u8>>;
+
+    // Find all entries of a day
+    fn find_data(&self, table: &str, date: &str) -> Result<Range>;
 
-    // Find a partical Entry
-    fn find_data(&self, date: &str);
+    // Find the entry closest to a timestamp
+    fn find_nearest(&self, table: &str, timestamp: u64, nearest: Nearest) -> Result<Option<MpdRecordType>>;
 }
 
 impl Database {
-    // Constructor
-    pub fn new(source: &'static str) -> Database {
-        Database {
-            source: source
+    // Constructor, uses the default configuration with the given source and recovers it after a crash
+    pub fn new<P: Into<PathBuf>>(source: P) -> Result<Database> {
+        Database::builder().source(source).build()
+    }
+
+    // Constructor from a configuration, see DatabaseBuilder for a validated and recovered one. The source is
+    // recovered before the first write instead.
+    pub fn from_config(config: Config) -> Database {
+        let mut summarizers: HashMap<String, Summarizer> = HashMap::new();
+        summarizers.insert("RawData".to_string(), summarizer_of::<RawData>);
+        let mut database = Database {
+            config:         config,
+            keys:           KeyRing::default(),
+            tables:         Mutex::new(HashMap::new()),
+            last_sync:      Arc::new(Mutex::new(Instant::now())),
+            writers:        Arc::new(Mutex::new(HashMap::new())),
+            flusher:        None,
+            wal:            Arc::new(Mutex::new(None)),
+            summarizers:    Mutex::new(summarizers),
+            recovered:      Mutex::new(false),
+        };
+
+        // Every insert flushes with an interval of 0, and syncs without an fsync interval
+        let syncs = matches!(database.config.fsync, FsyncPolicy::Interval(millis) if millis != 0);
+        if database.config.flush_interval != 0 || syncs {
+            let shared = Shared {
+                writers:    Arc::clone(&database.writers),
+                wal:        Arc::clone(&database.wal),
+                last_sync:  Arc::clone(&database.last_sync),
+            };
+            let config = &database.config;
+            match Flusher::start(shared, config.flush_interval, config.fsync, config.log_level) {
+                Ok(flusher) => database.flusher = Some(flusher),
+                Err(err) => database.log(LogLevel::Info, || format!("Records flushed on insert only: {}", err))
+            }
         }
+        return database;
     }
 
-    // Set a new source for the database
-    pub fn set_source(&self, source: &str) -> Result<(), io::Error> {
+    // Keys to encrypt and decrypt partitions with, Ex: from Config::keys
+    pub fn with_keys(mut self, keys: KeyRing) -> Database {
+        self.keys = keys;
+        self
+    }
+
+    // Configure a database in code or from a TOML file
+    pub fn builder() -> DatabaseBuilder {
+        DatabaseBuilder::new()
+    }
+
+    // Current configuration of the database
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    // Keys partitions are encrypted and decrypted with
+    pub(crate) fn keys(&self) -> &KeyRing {
+        &self.keys
+    }
+
+    // Current source of the database
+    pub fn source(&self) -> &Path {
+        &self.config.source
+    }
+
+    // Set a new source for the database, the directory must exist and be writable
+    pub fn set_source<P: AsRef<Path>>(&mut self, source: P) -> Result<()> {
+        let source = source.as_ref();
+        check_writable(source)?;
+        self.checkpoint()?;
+        *self.lock_wal() = None;
+        *self.recovered.get_mut().unwrap_or_else(|err| err.into_inner()) = false;
+        self.config.source = source.to_path_buf();
+        self.tables.get_mut().unwrap_or_else(|err| err.into_inner()).clear();
+        self.log(LogLevel::Info, || format!("Source set to {:?}", source));
+        self.recover()?;
         Ok(())
     }
     
-    // Lists all the databases within the current data source
-    pub fn list_db(&self) {
-        print_directories(self.source, 0);
+    // Summarize the partitions of tables with the given schema as they are closed, RawData is registered already
+    pub fn register_summary<T>(&self, schema: &str) where T: DeserializeOwned + Summarize {
+        let mut summarizers = self.summarizers.lock().unwrap_or_else(|err| err.into_inner());
+        summarizers.insert(schema.to_string(), summarizer_of::<T>);
     }
 
-    // Insert into database
-    pub fn insert_at(&self, path: &str, file: &str, entry: Entry) -> Result<(), io::Error> {
-        // Set the directory
-        let mut directory = format!("{}/{}/{}", 
-                    self.source,                    // Database Directory
-                    entry.table,               // Sub directory
-                    path  // Current format of time
-                );
-        println!("Directory is: {:?}", directory);
-
-        
-        // Ensure directory/file exists
-        create_dir_all(&directory).unwrap();
-        directory.push_str(&format!("/{}", file));
-        let path = Path::new(&mut directory);
-        if !path.exists() {
-            File::create(&directory)?;
-            println!("File created!\n");
-        }
-
-        // Set up data
-        let new_data = MpdRecordType{
-            id:         1,
-            datalog:    entry.data.clone(),
-            checksum:   crc32::checksum_ieee(&entry.data)
+    pub(crate) fn summarizer(&self, schema: &str) -> Option<Summarizer> {
+        self.summarizers.lock().unwrap_or_else(|err| err.into_inner()).get(schema).copied()
+    }
+
+    // Typed handle to a table storing values of T, the table's manifest must name the schema of T
+    pub fn table<'a, T>(&'a self, name: &'a str) -> Result<Table<'a, T>>
+                        where T: Serialize + DeserializeOwned + Schema {
+        let manifest = match self.manifest(name)? {
+            Some(manifest) => manifest,
+            None => return Err(DbError::UnknownTable(name.to_string()))
         };
-        let serialized_data = serialize_struct(new_data).unwrap();
+        if manifest.schema != T::NAME || manifest.schema_version != T::VERSION {
+            return Err(DbError::SchemaMismatch {
+                table:      name.to_string(),
+                schema:     manifest.schema,
+                version:    manifest.schema_version,
+            });
+        }
+        return Ok(Table::new(self, name));
+    }
 
-        // Write to database
-        let mut file = OpenOptions::new().append(true).open(&directory)?;   // Write at end of file
-        file.write_all(&serialized_data)?;
-        println!("Wrote: {:?}\n", serialized_data);
-        Ok(())
+    // Register a new table, partitioning defaults to the database configuration
+    pub fn create_table(&self, name: &str, options: TableOptions) -> Result<Manifest> {
+        check_table_name(name)?;
+        let directory = self.config.source.join(name);
+        if read_manifest(&directory)?.is_some() {
+            return Err(DbError::TableExists(name.to_string()));
+        }
+
+        let id = self.list_tables()?.iter().map(|table| table.id).max().unwrap_or(0) + 1;
+        let manifest = Manifest {
+            name:               name.to_string(),
+            id:                 id,
+            schema:             options.schema,
+            schema_version:     options.schema_version,
+            partition:          options.partition.unwrap_or(self.config.partition),
+            created:            get_timestamp(),
+            time_zone:          options.time_zone.unwrap_or(self.config.time_zone),
+            codec:              options.codec,
+            compression:        options.compression,
+            retention_days:     options.retention_days,
+            duplicates:         options.duplicates,
+        };
+        create_dir_all(&directory)?;
+        write_manifest(&directory, &manifest)?;
+        self.lock_tables().insert(name.to_string(), manifest.clone());
+        self.log(LogLevel::Info, || format!("Created table {:?}", name));
+        return Ok(manifest);
     }
 
-    // Insert into database
-    pub fn insert(&self, entry: Entry) -> Result<(), io::Error> {
-        // Set the directory
-        let directory = format!("{}/{}/{}/{}", 
-                    self.source,                    // Database Directory
-                    entry.table,               // Sub directory
-                    get_local_datetime(DATE_FORMAT), // Current format of data Ex: &Y&m&d -> 19700101
-                    get_local_datetime(TIME_FORMAT)  // Current format of time
-                );
-        println!("{:?}", directory);
-        // Check if exists
-        if !Path::new(&directory).exists() {
-            File::create(&directory)?;
-            println!("File created!\n")
-        }
-        
-
-        // Write to database
-        let mut file = OpenOptions::new().append(true).open(&directory)?;   // Write at end of file
-        file.write_all(&entry.data)?;
-        println!("Wrote: {:?}\n", entry.data);
-        Ok(())
+    // Delete a table and all of its partitions
+    pub fn drop_table(&self, name: &str) -> Result<()> {
+        if self.manifest(name)?.is_none() {
+            return Err(DbError::UnknownTable(name.to_string()));
+        }
+
+        self.checkpoint()?;     // The log must not replay into the dropped table
+        fs::remove_dir_all(self.config.source.join(name))?;
+        self.lock_tables().remove(name);
+        self.log(LogLevel::Info, || format!("Dropped table {:?}", name));
+        return Ok(());
     }
 
-    // Find a particular file/folder
-    pub fn find_file(&self, source: &str) -> Result<Vec<u8>, io::Error> {
-        // Set the directory
-        let mut directory = String::new();
-        directory.push_str(self.source);    // Database Directory
-        directory.push_str(source);         // Sub directory
+    // Manifests of every table in the source, sorted by name
+    pub fn list_tables(&self) -> Result<Vec<Manifest>> {
+        let mut tables = Vec::new();
+        if !self.config.source.exists() {
+            return Ok(tables);
+        }
 
-        // Read from file
-        let mut buf: Vec<u8> = Vec::new();
-        let mut file = File::open(&mut directory)?;
-        file.read_to_end(&mut buf)?;
-        println!("Read: {:?}\n", buf);
-        return Ok(buf);
+        for entry in fs::read_dir(&self.config.source)? {
+            let entry = entry?;
+            if let Some(manifest) = read_manifest(&entry.path())? {
+                tables.push(manifest);
+            }
+        }
+        tables.sort_by(|a, b| a.name.cmp(&b.name));
+        return Ok(tables);
+    }
+
+    // Manifest of a table, None if it was never created
+    pub fn manifest(&self, name: &str) -> Result<Option<Manifest>> {
+        if let Some(manifest) = self.lock_tables().get(name) {
+            return Ok(Some(manifest.clone()));
+        }
+
+        check_table_name(name)?;
+        let manifest = read_manifest(&self.config.source.join(name))?;
+        if let Some(manifest) = &manifest {
+            self.lock_tables().insert(name.to_string(), manifest.clone());
+        }
+        return Ok(manifest);
+    }
+
+    fn lock_tables(&self) -> MutexGuard<'_, HashMap<String, Manifest>> {
+        self.tables.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    // Lists all the databases within the current data source
+    pub fn list_db(&self) -> Result<()> {
+        print_directories(&self.config.source, &self.keys, 0)
+    }
+
+    // Insert into database, the partition is derived from the record's own timestamp
+    pub fn insert(&self, entry: Entry<'_>) -> Result<()> {
+        self.ensure_recovered()?;
+
+        let timestamp = entry.timestamp.unwrap_or_else(get_timestamp);
+        if get_datetime(timestamp).is_none() {
+            return Err(DbError::InvalidTimestamp(timestamp));
+        }
+        if entry.data.len() > MAX_FRAME_LEN {
+            return Err(DbError::RecordTooLarge(entry.data.len()));
+        }
+        let source = entry.source.as_deref();
+
+        // Records of the partition being written go straight to the table's writer
+        let mut writers = self.lock_writers();
+        if !writers.get(entry.table).is_some_and(|table| table.holds(timestamp)) {
+            self.rotate(&mut writers, entry.table, timestamp)?;
+        }
+        let writer = match writers.get_mut(entry.table).and_then(|table| table.get_mut(timestamp)) {
+            Some(writer) => writer,
+            None => return Err(DbError::UnknownTable(entry.table.to_string()))
+        };
+
+        // A record stored under the same source and timestamp, Ex: an upload retried after a timeout
+        let duplicates = writer.duplicates();
+        if duplicates != Duplicates::Allow && writer.contains(&self.keys, source, timestamp)? {
+            if duplicates == Duplicates::Ignore {
+                self.log(LogLevel::Debug, || format!("Ignored a duplicate of {} in {:?}", timestamp, writer.path()));
+                return Ok(());
+            }
+
+            // Appended with a higher seq, queries leave the stored record out and sealing drops it
+            writer.supersede()?;
+            self.log(LogLevel::Debug, || format!("Replaced the record of {} in {:?}", timestamp, writer.path()));
+        }
+
+        // Set up data, the seq follows the records of the partition sharing the timestamp
+        let checksum = crc32::checksum_ieee(&entry.data);
+        let seq = writer.next_seq(&self.keys, timestamp)?;
+        let new_data = MpdRecordType{
+            timestamp:  timestamp,
+            seq:        seq,
+            datalog:    entry.data,
+            checksum:   checksum,
+            source:     entry.source.clone(),
+        };
+        let payload = serialize_struct(new_data)?;
+
+        // Encrypt with the header and the frame's offset as additional data, a payload moved fails authentication
+        let header = *writer.header();
+        let offset = writer.len();
+        let payload = match header.cipher {
+            Cipher::None => payload,
+            cipher => encrypt(cipher, self.keys.current()?, &header.aad(offset), &payload)?
+        };
+
+        // Readers take a longer frame for damage, it must not reach the log or the partition
+        if payload.len() > MAX_FRAME_LEN {
+            return Err(DbError::RecordTooLarge(payload.len()));
+        }
+        let serialized_data = encode_frame(&payload);
+
+        // With a write-ahead log only the log is synced, partitions are synced at checkpoints
+        let count = writer.count();
+        let mut wal = self.lock_wal();
+        if let Some(wal) = wal.as_mut() {
+            wal.append(writer.path(), offset, &serialized_data)?;
+            if self.sync_due() {
+                wal.sync()?;
+            }
+        }
+        writer.write(source, timestamp, seq, &serialized_data)?;
+        if wal.is_none() && self.sync_due() {
+            writer.sync()?;
+        } else if writer.flush_due(self.config.flush_interval) {
+            writer.flush()?;
+        }
+        self.index(writer.path(), &header, count, timestamp, offset)?;
+        self.log(LogLevel::Debug, || format!("Wrote {} bytes to {:?}", serialized_data.len(), writer.path()));
+
+        if let Some(wal) = wal.as_mut().filter(|wal| wal.is_full()) {
+            for table in writers.values_mut() {
+                table.flush()?;
+            }
+            wal.checkpoint()?;
+        }
+        Ok(())
     }
 
-    // Find a particular Entry
-    pub fn find_data(&self, date: &str) {
-        
+    // True if the fsync policy calls for a sync after this write
+    fn sync_due(&self) -> bool {
+        sync_due(self.config.fsync, &self.last_sync)
     }
 
-    pub fn get_data(&self, source: &'static str, start_time: u32, end_time: u32) {
-        // Variables
-        let mut curr_timestamp = get_datetime(start_time);
-        let mut end_date = get_datetime(end_time);
-        let mut buf = Vec::new();
-        let mut curr_directory = String::new();
-        let mut curr_file = String::new();
+    // Open the partition a record at 'timestamp' belongs to for the table, flushing the one it replaces.
+    // Moving on to a later partition seals and summarizes the previous one, a sealed partition is reopened for
+    // late records and one encrypted otherwise than configured or of an older format is rewritten before it is
+    // appended to.
+    fn rotate(&self, writers: &mut HashMap<String, TableWriters>, table: &str, timestamp: u64) -> Result<()> {
+        let manifest = match self.manifest(table)? {
+            Some(manifest) => manifest,
+            None => return Err(DbError::UnknownTable(table.to_string()))
+        };
+        let partitioning = manifest.partitioning();
+        let directory = self.config.source.join(table);
+        let start = match partitioning.start_of(timestamp) {
+            Some(start) => start,
+            None => return Err(DbError::InvalidTimestamp(timestamp))
+        };
+        let path = partitioning.file(&directory, start);
 
-        // Find starting point
-        while curr_timestamp <= end_date {
-            // Setup variables
-            buf.clear();
-            curr_directory = format!("{}/{}/{}", self.source, source, curr_timestamp.format(DATE_FORMAT));
-            curr_file = format!("{}/{}", curr_directory, curr_timestamp.format(TIME_FORMAT));
+        // A record before the current partition is late, it gets a writer of its own so the current one stays open
+        let table_writers = writers.entry(table.to_string()).or_default();
+        let late = table_writers.current.as_ref().is_some_and(|current| timestamp < current.start());
+        let latest = match &table_writers.current {
+            Some(current) if late => current.path().to_path_buf(),
+            _ => path.clone()
+        };
+        let replaced = match late {
+            true => table_writers.late.take(),
+            false => table_writers.current.take()
+        };
+        let previous = match replaced {
+            Some(writer) => {
+                let previous = (writer.path().to_path_buf(), writer.start(), writer.end() - 1);
+                writer.close(&self.keys)?;
+                Some(previous)
+            }
+            None => None
+        };
 
-            /*** Check if Directory doesn't exist ***/
-            if !Path::new(&curr_directory).exists() {
-                // Add a day of time and continue
-                curr_timestamp = curr_timestamp + Duration::seconds(86400);  // += gives error
-                continue;
+        // Partition names sort in time order, those before the latest one written are closed
+        let closed = previous.filter(|(previous, _, _)| *previous < latest && previous.exists());
+        let reopen = read_header(&path).is_ok_and(|header| {
+            header.is_sealed() || header.cipher != self.config.cipher || header.version != FORMAT_VERSION
+        });
+        if (closed.is_some() && manifest.seals()) || reopen {
+            // Partitions are rewritten below, the log must not replay over them
+            self.checkpoint_with(writers)?;
+        }
+        if let Some((previous, start, end)) = closed {
+            if manifest.seals()
+                && recompress(&previous, manifest.codec, manifest.compression, self.config.cipher, &self.keys, false,
+                              manifest.supersedes())? {
+                self.log(LogLevel::Info, || format!("Sealed {:?}", previous));
             }
+            self.summarize(&manifest, &previous, start, end);
+        }
+        if reopen {
+            recompress(&path, Codec::MsgPack, Compression::None, self.config.cipher, &self.keys, false,
+                       manifest.supersedes())?;
+            self.log(LogLevel::Info, || format!("Rewrote {:?} to append to it", path));
+        }
 
-            /*** Check if File doesn't exist ***/
-            if !Path::new(&curr_file).exists() {
-                // Add an hour of time and continue
-                curr_timestamp = curr_timestamp + Duration::seconds(3600);  // += gives error
-                continue;
+        create_dir_all(partitioning.directory(&directory, start))?;
+        let start_time = start;
+        let end_time = partitioning.end_of(start);
+        if fs::metadata(&path).map_or(true, |metadata| metadata.len() == 0) {
+            // New partition, its header is logged and written whole first so the file never lacks one
+            let header = PartitionHeader {
+                cipher: self.config.cipher,
+                ..PartitionHeader::new(Codec::MsgPack, manifest.id, start_time)
+            };
+            if let Some(wal) = self.lock_wal().as_mut() {
+                wal.append(&path, 0, &header.encode())?;
             }
-            
-            /*** Read File ***/
-            let mut file = File::open(curr_file).unwrap();
-            file.read_to_end(&mut buf).unwrap();
-
-            /*** Deserialize and "publish" ***/
-            let mut de = Deserializer::new(&buf[..]);
-            loop {
-                let entry: MpdRecordType = match Deserialize::deserialize(&mut de) {
-                    Ok(entry) => entry,
-                    Err(_) => {
-                        // Add an hour of time and continue
-                        println!("Error!");
-                        curr_timestamp = curr_timestamp + Duration::seconds(3600);  // += gives error
-                        break;
-                    }
-                };
+            write_partition(&path, &header, &[], &self.keys)?;
+            self.log(LogLevel::Info, || format!("Created {:?}", path));
+        }
+        let writer = PartitionWriter::open(&path, &self.keys, start_time, end_time, self.config.write_buffer,
+                                           manifest.duplicates)?;
+        let table_writers = writers.entry(table.to_string()).or_default();
+        match late {
+            true => table_writers.late = Some(writer),
+            false => table_writers.current = Some(writer)
+        }
+        return Ok(());
+    }
+
+    // Store the summary of a closed partition, a partition that can't be summarized is left for its first query
+    fn summarize(&self, manifest: &Manifest, partition: &Path, start: u64, end: u64) {
+        if let Err(err) = store_summary(self, &manifest.name, &manifest.schema, partition, start, end) {
+            self.log(LogLevel::Info, || format!("Not summarized {:?}: {}", partition, err));
+        }
+    }
+
+    // Add every index_interval'th record of a partition to its sparse index
+    fn index(&self, partition: &Path, header: &PartitionHeader, count: u64, timestamp: u64, offset: u64) -> Result<()> {
+        if self.config.index_interval != 0 && count.is_multiple_of(u64::from(self.config.index_interval)) {
+            append_entry(partition, header, &self.keys, timestamp, offset)?;
+        }
+        return Ok(());
+    }
 
-                // Send data here
-                println!("{:?}", entry);
+    // Encode and compress the closed partitions of a table that aren't sealed yet, returns how many were
+    pub fn seal(&self, table: &str) -> Result<u64> {
+        let manifest = match self.manifest(table)? {
+            Some(manifest) => manifest,
+            None => return Err(DbError::UnknownTable(table.to_string()))
+        };
+        if !manifest.seals() {
+            return Ok(0);
+        }
+        self.ensure_recovered()?;
+        self.checkpoint()?;
 
-                // Check if entry ID is smaller than start_timestamp
-                if entry.id < start_time {
+        let partitioning = manifest.partitioning();
+        let directory = self.config.source.join(table);
+        let current = partitioning.start_of(get_timestamp());
+        let mut sealed = 0;
+        for day in partition_days(&directory)? {
+            for entry in fs::read_dir(directory.join(day.format(DATE_FORMAT).to_string()))? {
+                let path = entry?.path();
+                if !path.is_file() || path.extension().is_some() {
                     continue;
                 }
+                let header = match read_header(&path) {
+                    Ok(header) => header,
+                    Err(_) => continue      // Left for fsck
+                };
+                let start = match partitioning.start_of(header.start) {
+                    Some(start) if Some(start) < current => start,
+                    _ => continue       // Still written to, or a start left for fsck
+                };
+                if recompress(&path, manifest.codec, manifest.compression, self.config.cipher, &self.keys, false,
+                              manifest.supersedes())? {
+                    self.log(LogLevel::Info, || format!("Sealed {:?}", path));
+                    sealed += 1;
+                }
+                let end = partitioning.end_of(start);
+                self.summarize(&manifest, &path, header.start, end - 1);
+            }
+        }
+        return Ok(sealed);
+    }
 
-                // Check if entry ID is biiger than end_timestamp
-                if entry.id > end_time {
-                    return;
+    // Delete the partitions of a table that end more than its retention_days ago, returns how many were
+    pub fn expire(&self, table: &str) -> Result<u64> {
+        let manifest = match self.manifest(table)? {
+            Some(manifest) => manifest,
+            None => return Err(DbError::UnknownTable(table.to_string()))
+        };
+        let days = match manifest.retention_days {
+            Some(days) => days,
+            None => return Ok(0)
+        };
+        self.ensure_recovered()?;
+        self.checkpoint()?;
+
+        let partitioning = manifest.partitioning();
+        let directory = self.config.source.join(table);
+        let cutoff = get_timestamp().saturating_sub(u64::from(days) * 86_400_000);
+        let mut expired = 0;
+        for day in partition_days(&directory)? {
+            let day_directory = directory.join(day.format(DATE_FORMAT).to_string());
+            for entry in fs::read_dir(&day_directory)? {
+                let path = entry?.path();
+                if !path.is_file() || path.extension().is_some() {
+                    continue;
                 }
+                let start = match read_header(&path).ok().and_then(|header| partitioning.start_of(header.start)) {
+                    Some(start) => start,
+                    None => continue        // Left for fsck
+                };
+                if partitioning.end_of(start) > cutoff {
+                    continue;
+                }
+                fs::remove_file(&path)?;
+                for sidecar in &[index_path(&path), filter_path(&path), summary_path(&path), unsorted_path(&path)] {
+                    if sidecar.exists() {
+                        fs::remove_file(sidecar)?;
+                    }
+                }
+                self.log(LogLevel::Info, || format!("Expired {:?}", path));
+                expired += 1;
+            }
+            if fs::read_dir(&day_directory)?.next().is_none() {
+                fs::remove_dir(&day_directory)?;
+            }
+        }
+        return Ok(expired);
+    }
 
-                println!("Data is good!");
+    // Encrypt the partitions of every table with the current key and cipher, returns how many were. Inserts wait
+    // until it is done, then old keys can be dropped from the configuration.
+    pub fn rotate_keys(&self) -> Result<u64> {
+        if self.config.cipher != Cipher::None {
+            self.keys.current()?;
+        }
+        self.ensure_recovered()?;
+        let mut writers = self.lock_writers();      // Held so the partitions being written can be rewritten too
+        self.checkpoint_with(&mut writers)?;
+        let mut rotated = 0;
+        for manifest in self.list_tables()? {
+            let directory = self.config.source.join(&manifest.name);
+            for day in partition_days(&directory)? {
+                for entry in fs::read_dir(directory.join(day.format(DATE_FORMAT).to_string()))? {
+                    let path = entry?.path();
+                    if !path.is_file() || path.extension().is_some() {
+                        continue;
+                    }
+                    let header = match read_header(&path) {
+                        Ok(header) => header,
+                        Err(_) => continue      // Left for fsck
+                    };
+                    if header.cipher == self.config.cipher && header.version == FORMAT_VERSION
+                        && !is_stale(&path, &self.keys)? {
+                        continue;
+                    }
+                    recompress(&path, header.codec, header.compression, self.config.cipher, &self.keys, true,
+                               manifest.supersedes())?;
+                    self.log(LogLevel::Info, || format!("Rotated {:?}", path));
+                    rotated += 1;
+                }
             }
         }
+        return Ok(rotated);
+    }
+
+    /***
+    * Function recover:
+    *
+    * Purpose:
+    * Replays the write-ahead log left by a crash and truncates torn tails so
+    * partitions hold whole records, returns how many appends were replayed.
+    * Runs by itself when the database is opened, see DatabaseBuilder::build,
+    * or its source is set, and before the first write to a database made
+    * with from_config. Queries never run it.
+    ***/
+    pub fn recover(&self) -> Result<u64> {
+        let mut writers = self.lock_writers();      // Held so no insert runs while tails are truncated
+        self.checkpoint_with(&mut writers)?;
+        let mut wal = self.lock_wal();
+        let replayed = recover_source(self)?;
+        if self.config.wal && wal.is_none() {
+            *wal = Some(Wal::open(&self.config.source)?);
+        }
+        *self.recovered.lock().unwrap_or_else(|err| err.into_inner()) = true;
+        self.log(LogLevel::Info, || format!("Recovered {:?}, {} appends replayed", self.config.source, replayed));
+        return Ok(replayed);
     }
-}
 
-// fn get_starting_point(source: &'static str, buf: &mut Vec<u8>, curr_timestamp: &mut DateTime<Utc>, end_time: &mut DateTime<Utc>) -> Result<DateTime<Utc>, Error> {
-//     // Initialize variables
-//     let mut curr_directory = format!("{}{}", source, curr_timestamp.format(DATE_FORMAT));
-//     let mut curr_file = format!("{}{}", curr_directory, curr_timestamp.format(TIME_FORMAT));
+    fn ensure_recovered(&self) -> Result<()> {
+        if !*self.recovered.lock().unwrap_or_else(|err| err.into_inner()) {
+            self.recover()?;
+        }
+        return Ok(());
+    }
 
-//     // Find starting point
-//     while curr_timestamp <= end_time {
+    // Write the records buffered by the partition writers out to their partitions, Ex: from a timer
+    pub fn flush(&self) -> Result<()> {
+        for table in self.lock_writers().values_mut() {
+            table.flush()?;
+        }
+        return Ok(());
+    }
 
-//         /*** Check if Directory doesn't exist ***/
-//         if !Path::new(&curr_directory).exists() {
-//             // Add a day of time and continue
-//             curr_timestamp = &mut (*curr_timestamp + Duration::seconds(86400));  // += gives error
-//             continue;
-//         }
+    // Write the records buffered for a table out, Ex: before it is read
+    pub(crate) fn flush_table(&self, table: &str) -> Result<()> {
+        if let Some(writers) = self.lock_writers().get_mut(table) {
+            writers.flush()?;
+        }
+        return Ok(());
+    }
 
-//         /*** Check if File doesn't exist ***/
-//         if !Path::new(&curr_file).exists() {
-//             // Add an hour of time and continue
-//             curr_timestamp = &mut (*curr_timestamp + Duration::seconds(3600));  // += gives error
-//             continue;
-//         }
+    // Close the partition writers and empty the write-ahead log, Ex: before partitions are rewritten
+    pub(crate) fn checkpoint(&self) -> Result<()> {
+        self.checkpoint_with(&mut self.lock_writers())
+    }
 
-//         /*** Read until first value is found ***/
-//         let mut file = File::open(source)?;
-//         file.read_to_end(&mut buf)?;
+    fn checkpoint_with(&self, writers: &mut HashMap<String, TableWriters>) -> Result<()> {
+        for (_, table) in writers.drain() {
+            table.close(&self.keys)?;
+        }
+        if let Some(wal) = self.lock_wal().as_mut() {
+            wal.checkpoint()?;
+        }
+        return Ok(());
+    }
 
-//         return Ok(*curr_timestamp);
-//     }
+    fn lock_writers(&self) -> MutexGuard<'_, HashMap<String, TableWriters>> {
+        self.writers.lock().unwrap_or_else(|err| err.into_inner())
+    }
 
-//     return Err(Error::new(ErrorKind::Other, format!("No Data exists in the time range {:?} to {:?}.", start_time, end_time)));
-// }
+    fn lock_wal(&self) -> MutexGuard<'_, Option<Wal>> {
+        self.wal.lock().unwrap_or_else(|err| err.into_inner())
+    }
 
-/***
-* Function read:
-*
-* Purpose:
-* reads directory
-***/
-fn read(mut buf: &mut Vec<u8>, mut directory: &mut String) -> std::io::Result<()> {
-    let mut file = File::open(&mut directory)?;
-    file.read_to_end(&mut buf)?;
-    println!("Read: {:?}\n", buf);
-    Ok(())
-}
+    // Partition layout of a table, tables without a manifest follow the configuration
+    pub(crate) fn partitioning(&self, table: &str) -> Result<Partitioning> {
+        match self.manifest(table)? {
+            Some(manifest) => Ok(manifest.partitioning()),
+            None => Ok(Partitioning {
+                granularity:    self.config.partition,
+                zone:           self.config.time_zone,
+            })
+        }
+    }
 
-fn print_directories(path: &str, count: usize) {
-    let paths = fs::read_dir(path).unwrap();
+    // Print a message to stderr if the configured log level allows it, stdout is left to the application
+    pub(crate) fn log<F>(&self, level: LogLevel, message: F) where F: FnOnce() -> String {
+        if level != LogLevel::Off && level <= self.config.log_level {
+            eprintln!("[file_sys] {}", message());
+        }
+    }
 
-    for entry in paths {
-        if let Ok(entry) = entry {
-            if entry.path().is_dir() {
-                // Print Directory
-                print!("{:-<1$}", "", count);
-                println!("{}", entry.file_name().into_string().unwrap());
-                print_directories(entry.path().to_str().unwrap(), count + 1);
+    // Find a particular file/folder
+    pub fn find_file(&self, source: &str) -> Result<Vec<u8>> {
+        // Set the directory
+        let directory = self.config.source.join(source);    // Sub directory
+        if !directory.exists() {
+            return Err(DbError::NotFound(directory));
+        }
+
+        // Read from file
+        self.flush()?;
+        let mut buf: Vec<u8> = Vec::new();
+        let mut file = File::open(&directory)?;
+        file.read_to_end(&mut buf)?;
+        self.log(LogLevel::Debug, || format!("Read {} bytes from {:?}", buf.len(), directory));
+        return Ok(buf);
+    }
+
+    // Find all records of a table for a day, Ex: 20200101
+    pub fn find_data(&self, table: &str, date: &str) -> Result<Range> {
+        let day = NaiveDate::parse_from_str(date, DATE_FORMAT)
+                    .map_err(|_| DbError::InvalidDate(date.to_string()))?;
+        let (start_time, end_time) = day_bounds(self.partitioning(table)?, day)?;
+        return self.range(table, start_time, end_time);
+    }
+
+    // Find the record closest to a timestamp, Ex: the value at time T is AtOrBefore T
+    pub fn find_nearest(&self, table: &str, timestamp: u64, nearest: Nearest) -> Result<Option<MpdRecordType>> {
+        let partitioning = self.partitioning(table)?;
+        let days = partition_days(&self.config.source.join(table))?;
+        let target = match partitioning.to_naive(timestamp) {
+            Some(datetime) => datetime.date(),
+            None => return Err(DbError::InvalidRange { start: timestamp, end: timestamp })
+        };
+
+        match nearest {
+            Nearest::AtOrAfter => {
+                // First record of the earliest day that has one
+                for day in days.into_iter().filter(|day| *day >= target) {
+                    let (start_time, end_time) = day_bounds(partitioning, day)?;
+                    if let Some(entry) = self.range(table, max(start_time, timestamp), end_time)?.next() {
+                        return entry.map(Some);
+                    }
+                }
+            }
+            Nearest::AtOrBefore => {
+                // Last record of the latest day that has one
+                for day in days.into_iter().rev().filter(|day| *day <= target) {
+                    let (start_time, end_time) = day_bounds(partitioning, day)?;
+                    let mut last = None;
+                    for entry in self.range(table, start_time, min(end_time, timestamp))? {
+                        last = Some(entry?);
+                    }
+                    if last.is_some() {
+                        return Ok(last);
+                    }
+                }
             }
         }
+
+        return Ok(None);
+    }
+
+    // Check every partition file without changing anything
+    pub fn verify(&self) -> Result<FsckReport> {
+        self.flush()?;
+        check_source(self, false)
+    }
+
+    // Check every partition file, truncating torn tails and quarantining unreadable files
+    pub fn repair(&self) -> Result<FsckReport> {
+        self.ensure_recovered()?;
+        self.checkpoint()?;
+        check_source(self, true)
+    }
+
+    // Iterate over the records of a table between two timestamps, in time order
+    pub fn range(&self, table: &str, start_time: u64, end_time: u64) -> Result<Range> {
+        if start_time > end_time {
+            return Err(DbError::InvalidRange { start: start_time, end: end_time });
+        }
+        self.flush_table(table)?;
+
+        let partitioning = self.partitioning(table)?;
+        let manifest = self.manifest(table)?;
+        let table_id = manifest.as_ref().map_or(0, |manifest| manifest.id);
+        let range = Range::new(self.config.source.join(table), partitioning, table_id, &self.config, self.keys.clone(),
+                               start_time, end_time)?;
+        return Ok(range.superseding(manifest.is_some_and(|manifest| manifest.supersedes())));
     }
 }
 
-fn print_db(path: &str, count: usize) {
-    let paths = fs::read_dir(path).unwrap();
+impl Drop for Database {
+    // Leave no appends to replay after a clean shutdown
+    fn drop(&mut self) {
+        self.flusher.take();
+        if let Err(err) = self.checkpoint() {
+            self.log(LogLevel::Info, || format!("Write-ahead log not checkpointed: {}", err));
+        }
+    }
+}
+
+impl DB for Database {
+    fn set_source(&mut self, source: &str) -> Result<()> {
+        Database::set_source(self, source)
+    }
+
+    fn list_db(&self) -> Result<()> {
+        Database::list_db(self)
+    }
+
+    fn insert(&self, entry: Entry<'_>) -> Result<()> {
+        Database::insert(self, entry)
+    }
+
+    fn find_file(&self, source: &str) -> Result<Vec<u8>> {
+        Database::find_file(self, source)
+    }
+
+    fn find_data(&self, table: &str, date: &str) -> Result<Range> {
+        Database::find_data(self, table, date)
+    }
+
+    fn find_nearest(&self, table: &str, timestamp: u64, nearest: Nearest) -> Result<Option<MpdRecordType>> {
+        Database::find_nearest(self, table, timestamp, nearest)
+    }
+}
+
+fn print_directories(path: &Path, keys: &KeyRing, count: usize) -> Result<()> {
+    let paths = fs::read_dir(path)?;
 
     for entry in paths {
-        if let Ok(entry) = entry {
+        let entry = entry?;
+        if entry.path().is_dir() {
             // Print Directory
             print!("{:-<1$}", "", count);
-            println!("{}", entry.file_name().into_string().unwrap());
-            if entry.path().is_dir() {
-                print_directories(entry.path().to_str().unwrap(), count + 1);
+            println!("{}", entry.file_name().to_string_lossy());
+            print_directories(&entry.path(), keys, count + 1)?;
+        } else if entry.path().extension().is_none() {
+            // Print Partition, with its record count once summarized
+            print!("{:-<1$}", "", count);
+            match read_summary(&entry.path(), keys) {
+                Some(summary) => println!("{} ({} records)", entry.file_name().to_string_lossy(), summary.records),
+                None => println!("{}", entry.file_name().to_string_lossy())
             }
         }
     }
+    return Ok(());
 }
 
 /***
@@ -295,39 +858,240 @@ fn print_db(path: &str, count: usize) {
 * Purpose:
 * Serializes structs
 ***/
-fn serialize_struct<T>(data: T) -> Result<Vec<u8>, ()> where T: Serialize, {
+pub(crate) fn serialize_struct<T>(data: T) -> Result<Vec<u8>> where T: Serialize, {
     let mut buf = Vec::new();
     let mut msg_pack = Serializer::new(&mut buf);
-    match data.serialize(&mut msg_pack) {
-        Ok(_) => return Ok(buf),
-        Err(e) => {
-            println!("Error serializing: {:?}", e);
-            return Err(())
-        }
-    }
+    data.serialize(&mut msg_pack)?;
+    return Ok(buf);
 }
 
-fn get_local_datetime(format: &str) -> String {
-    let local: DateTime<Utc> = Utc::now();
-    return local.format(format).to_string();
-}
+/***
+* Function check_writable:
+*
+* Purpose:
+* Ensures a directory exists and files can be created in it
+***/
+pub(crate) fn check_writable(path: &Path) -> Result<()> {
+    if !path.is_dir() {
+        return Err(DbError::InvalidSource(path.to_path_buf()));
+    }
 
-// Convert timestamp to datetime
-fn get_datetime(timestamp: u32) -> DateTime<Utc> {
-    let naive_datetime = NaiveDateTime::from_timestamp(i64::from(timestamp), 0);  // the 0 represents nanoseconds for leap seconds
-    let utc_datetime = DateTime::<Utc>::from_utc(naive_datetime, Utc);
-    return utc_datetime;
+    // Probe with a file, permissions alone don't account for read-only mounts
+    let probe = path.join(".write_probe");
+    File::create(&probe)?;
+    fs::remove_file(&probe)?;
+    return Ok(());
 }
 
-fn get_timestamp() -> Option<u32> {
-    let local: DateTime<Local> = Local::now();
-    return u32::try_from(local.timestamp()).ok();
+// First and last millisecond of a day in the partition zone
+fn day_bounds(partitioning: Partitioning, day: NaiveDate) -> Result<(u64, u64)> {
+    partitioning.day_bounds(day)
+        .ok_or_else(|| DbError::InvalidDate(day.format(DATE_FORMAT).to_string()))
 }
 
-fn print_error(err: Error) {
-    if let Some(inner_err) = err.into_inner() {
-        println!("Inner error: {}", inner_err);
-    } else {
-        println!("No inner error");
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::partition::Zone;
+    use crate::testing::{TempDir, entry};
+
+    #[test]
+    fn set_source_needs_a_directory() {
+        let dir = TempDir::new("set_source");
+        let mut database = Database::new(dir.path()).unwrap();
+        assert!(matches!(database.set_source(dir.path().join("missing")), Err(DbError::InvalidSource(_))));
+        fs::write(dir.path().join("file"), b"").unwrap();
+        assert!(matches!(database.set_source(dir.path().join("file")), Err(DbError::InvalidSource(_))));
+        assert_eq!(database.source(), dir.path());
+
+        fs::create_dir(dir.path().join("next")).unwrap();
+        database.set_source(dir.path().join("next")).unwrap();
+        assert_eq!(database.source(), dir.path().join("next"));
+        assert!(!dir.path().join("next").join(".write_probe").exists());
+    }
+
+    #[test]
+    fn set_source_needs_a_writable_directory() {
+        let dir = TempDir::new("set_source_read_only");
+        let root = dir.path().join("read_only");
+        fs::create_dir(&root).unwrap();
+        let original = fs::metadata(&root).unwrap().permissions();
+        let mut permissions = original.clone();
+        permissions.set_readonly(true);
+        fs::set_permissions(&root, permissions).unwrap();
+
+        // Permissions don't hold for every user, Ex: root, the probe must agree with the file system
+        let writable = File::create(root.join("probe")).is_ok();
+        let _ = fs::remove_file(root.join("probe"));
+        let mut database = Database::new(dir.path()).unwrap();
+        let result = database.set_source(&root);
+        fs::set_permissions(&root, original).unwrap();
+        assert_eq!(result.is_ok(), writable);
+    }
+
+    #[test]
+    fn errors_are_returned() {
+        let dir = TempDir::new("errors");
+        let database = Database::new(dir.path()).unwrap();
+        assert!(matches!(database.find_file("levels"), Err(DbError::NotFound(_))));
+        assert!(matches!(database.range("levels", 2, 1), Err(DbError::InvalidRange { start: 2, end: 1 })));
+
+        // Times past the dates partitions are named for
+        database.create_table("levels", TableOptions::default()).unwrap();
+        assert!(matches!(database.range("levels", 0, u64::MAX), Err(DbError::InvalidRange { start: 0, end: u64::MAX })));
+        assert!(matches!(database.find_nearest("levels", u64::MAX, Nearest::AtOrBefore),
+                         Err(DbError::InvalidRange { .. })));
+        assert!(matches!(database.insert(entry("levels", u64::MAX, b"a")), Err(DbError::InvalidTimestamp(u64::MAX))));
+
+        // A partition that isn't one
+        let partition = dir.path().join("levels").join("20200101").join("14");
+        fs::create_dir_all(partition.parent().unwrap()).unwrap();
+        fs::write(&partition, [0xc1]).unwrap();
+        let mut records = database.range("levels", 1_577_887_200_000, 1_577_887_300_000).unwrap();
+        match records.next() {
+            Some(Err(DbError::InvalidHeader { file, .. })) => assert_eq!(file, partition),
+            _ => panic!("invalid header not reported")
+        }
+        assert!(records.next().is_none());
+    }
+
+    #[test]
+    fn oversize_records_are_not_stored() {
+        let dir = TempDir::new("oversize");
+        let t = 1_577_887_200_000;
+        {
+            let database = Database::builder().source(dir.path()).wal(true).build().unwrap();
+            database.create_table("levels", TableOptions::default()).unwrap();
+            database.insert(entry("levels", t, b"a")).unwrap();
+            let data = vec![0; MAX_FRAME_LEN + 1];
+            let len = data.len();
+            let oversize = Entry { table: "levels", data: data, timestamp: Some(t + 1), source: None };
+            assert!(matches!(database.insert(oversize), Err(DbError::RecordTooLarge(got)) if got == len));
+
+            // Fits as data, not once stored as a record
+            let data = vec![0; MAX_FRAME_LEN];
+            let oversize = Entry { table: "levels", data: data, timestamp: Some(t + 2), source: None };
+            assert!(matches!(database.insert(oversize), Err(DbError::RecordTooLarge(got)) if got > MAX_FRAME_LEN));
+            database.insert(entry("levels", t + 3, b"b")).unwrap();
+        }
+
+        // Nor replayed from the log
+        let database = Database::builder().source(dir.path()).wal(true).build().unwrap();
+        let records: Vec<(u64, Vec<u8>)> = database.range("levels", t, t + 3).unwrap()
+            .map(|record| record.map(|record| (record.timestamp, record.datalog)).unwrap())
+            .collect();
+        assert_eq!(records, vec![(t, b"a".to_vec()), (t + 3, b"b".to_vec())]);
+    }
+
+    #[test]
+    fn timer_syncs_the_log_under_an_fsync_interval() {
+        let dir = TempDir::new("wal_sync");
+        let database = Database::builder().source(dir.path()).wal(true).fsync(FsyncPolicy::Interval(300))
+            .build().unwrap();
+        database.create_table("levels", TableOptions::default()).unwrap();
+        *database.last_sync.lock().unwrap() = Instant::now();
+        database.insert(entry("levels", 1_577_887_200_000, b"a")).unwrap();
+        assert!(!database.lock_wal().as_ref().unwrap().is_synced());
+
+        // Synced with no insert after it
+        for _ in 0..200 {
+            if database.lock_wal().as_ref().unwrap().is_synced() {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        assert!(database.lock_wal().as_ref().unwrap().is_synced());
+    }
+
+    #[test]
+    fn records_of_the_hour_repeated_by_dst_stay_apart() {
+        // See partition::tests::hour_repeated_by_dst_is_a_partition_of_its_own
+        std::env::set_var("TZ", "EST5EDT,M3.2.0,M11.1.0");
+        let dir = TempDir::new("dst_fall_back");
+        let database = Database::builder().source(dir.path()).time_zone(Zone::Local).build().unwrap();
+        database.create_table("levels", TableOptions::default()).unwrap();
+        let first = 1_604_208_600_000;      // 2020-11-01 05:30 UTC, 01:30 EDT
+        let second = first + 3_600_000;     // 01:30 EST
+        database.insert(entry("levels", first, b"edt")).unwrap();
+        database.insert(entry("levels", second, b"est")).unwrap();
+        database.flush().unwrap();
+
+        let day = dir.path().join("levels").join("20201101");
+        assert_eq!(read_header(&day.join("01")).unwrap().start, first - 1_800_000);
+        assert_eq!(read_header(&day.join("01-0500")).unwrap().start, second - 1_800_000);
+        let records: Vec<(u64, Vec<u8>)> = database.find_data("levels", "20201101").unwrap()
+            .map(|record| record.map(|record| (record.timestamp, record.datalog)).unwrap())
+            .collect();
+        assert_eq!(records, vec![(first, b"edt".to_vec()), (second, b"est".to_vec())]);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn seq_counts_up_while_timestamp_repeats() {
+        let dir = TempDir::new("seq");
+        let database = Database::new(dir.path()).unwrap();
+        database.create_table("levels", TableOptions::default()).unwrap();
+        let t = 1_577_887_200_000;
+        for (timestamp, data) in [(t, 1), (t, 2), (t + 1, 3), (t + 1, 4), (t + 1, 5)] {
+            let entry = Entry { table: "levels", data: vec![data], timestamp: Some(timestamp), source: None };
+            database.insert(entry).unwrap();
+        }
+        let ids: Vec<(u64, u32)> = database.range("levels", t, t + 1).unwrap()
+            .map(|record| record.map(|record| (record.timestamp, record.seq)).unwrap())
+            .collect();
+        assert_eq!(ids, vec![(t, 0), (t, 1), (t + 1, 0), (t + 1, 1), (t + 1, 2)]);
+    }
+
+    fn seqs(database: &Database, t: u64) -> Vec<(u64, u32)> {
+        database.range("levels", t, t + 1000).unwrap()
+            .map(|record| record.map(|record| (record.timestamp, record.seq)).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn seq_continues_after_reopen() {
+        let dir = TempDir::new("seq_reopen");
+        let t = 1_590_451_200_000;
+        let database = Database::new(dir.path()).unwrap();
+        database.create_table("levels", TableOptions::default()).unwrap();
+        database.insert(entry("levels", t, b"a")).unwrap();
+        database.insert(entry("levels", t, b"b")).unwrap();
+        drop(database);
+
+        let database = Database::new(dir.path()).unwrap();
+        database.insert(entry("levels", t, b"c")).unwrap();
+        assert_eq!(seqs(&database, t), vec![(t, 0), (t, 1), (t, 2)]);
+    }
+
+    #[test]
+    fn seq_of_late_record_follows_its_timestamp() {
+        let dir = TempDir::new("seq_late");
+        let t = 1_590_451_200_000;
+        let database = Database::new(dir.path()).unwrap();
+        database.create_table("levels", TableOptions::default()).unwrap();
+        database.insert(entry("levels", t, b"a")).unwrap();
+        database.insert(entry("levels", t + 1, b"b")).unwrap();
+        database.insert(entry("levels", t, b"c")).unwrap();
+        database.insert(entry("levels", t + 1, b"d")).unwrap();
+        drop(database);
+
+        let database = Database::new(dir.path()).unwrap();
+        database.insert(entry("levels", t, b"e")).unwrap();
+        assert_eq!(seqs(&database, t), vec![(t, 0), (t, 1), (t, 2), (t + 1, 0), (t + 1, 1)]);
+    }
+
+    #[test]
+    fn partition_follows_record_time() {
+        let dir = TempDir::new("insert_partition");
+        let database = Database::new(dir.path()).unwrap();
+        database.create_table("levels", TableOptions::default()).unwrap();
+        // 2020-01-01 14:59:59.999 and 15:00:00
+        for timestamp in [1_577_890_799_999, 1_577_890_800_000] {
+            database.insert(Entry { table: "levels", data: vec![1], timestamp: Some(timestamp), source: None }).unwrap();
+        }
+        let day = dir.path().join("levels").join("20200101");
+        assert!(day.join("14").is_file());
+        assert!(day.join("15").is_file());
+        assert_eq!(database.find_nearest("levels", 1_577_890_800_000, Nearest::AtOrBefore).unwrap().unwrap().timestamp,
+                   1_577_890_800_000);
+    }
+}
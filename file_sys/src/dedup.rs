@@ -0,0 +1,372 @@
+use std::convert::TryInto;
+use std::fs;
+use std::path::{Path, PathBuf};
+use crc::crc32;
+use crate::crypto::{KeyRing, decrypt_sidecar, encrypt_sidecar};
+use crate::error::Result;
+use crate::format::{Frame, PartitionHeader, HEADER_LEN};
+use crate::index::{seek, timestamp_of, with_record};
+use crate::seal::map_partition;
+use crate::stream::scan_records;
+use crate::summary::file_stamp;
+
+pub(crate) static FILTER_EXTENSION: &str = "bloom";
+const FILTER_TAG: &[u8] = b"bloom";
+const FILTER_BITS: usize = 1 << 20;     // 128 KiB per partition written
+const FILTER_HASHES: u64 = 4;
+const STAMP_LEN: usize = 16;
+
+/***
+* Struct KeyFilter:
+*
+* Purpose:
+* Bloom filter of the keys, source and timestamp, of the records of a
+* partition written to a table that doesn't allow duplicates. Its size is
+* fixed, so a day partition at a high sample rate only raises the rate of
+* false hits, each confirmed against the partition by find_record. Stored
+* beside the partition once its writer is closed, Ex:
+* data/levels/20200101/14.bloom, so reopening a large partition doesn't read
+* every record. Only used while the partition is as it was stored, encrypted
+* with an encrypted partition, see encrypt_sidecar.
+*
+* Stored layout, little endian:
+*
+*   0   file_len    u64, of the partition file the filter was stored for
+*   8   modified    u64, its modification time in nanoseconds since epoch
+*   16  bits        FILTER_BITS / 8 bytes
+***/
+pub(crate) struct KeyFilter {
+    bits:   Vec<u8>,
+}
+
+impl KeyFilter {
+    pub(crate) fn new() -> KeyFilter {
+        KeyFilter { bits: vec![0; FILTER_BITS / 8] }
+    }
+
+    pub(crate) fn insert(&mut self, source: Option<&str>, timestamp: u64) {
+        for bit in bits_of(source, timestamp) {
+            self.bits[bit / 8] |= 1 << (bit % 8);
+        }
+    }
+
+    // False if no record of 'source' at 'timestamp' was inserted, true if one may have been
+    pub(crate) fn may_contain(&self, source: Option<&str>, timestamp: u64) -> bool {
+        bits_of(source, timestamp).all(|bit| self.bits[bit / 8] & 1 << (bit % 8) != 0)
+    }
+}
+
+// Bits of a record key, by double hashing two CRC-32s of its bytes
+fn bits_of(source: Option<&str>, timestamp: u64) -> impl Iterator<Item = usize> {
+    let mut bytes = timestamp.to_le_bytes().to_vec();
+    if let Some(source) = source {
+        bytes.push(1);      // Apart from no source
+        bytes.extend_from_slice(source.as_bytes());
+    }
+    let first = u64::from(crc32::checksum_ieee(&bytes));
+    let step = u64::from(crc32::checksum_castagnoli(&bytes)) | 1;
+    return (0..FILTER_HASHES).map(move |i| ((first + i * step) % FILTER_BITS as u64) as usize);
+}
+
+// Sidecar filter of a partition file
+pub(crate) fn filter_path(partition: &Path) -> PathBuf {
+    partition.with_extension(FILTER_EXTENSION)
+}
+
+/***
+* Function read_filter:
+*
+* Purpose:
+* Key filter of a partition that isn't sealed. Taken from the stored filter if
+* the partition hasn't changed since, true then, otherwise the partition is
+* scanned.
+***/
+pub(crate) fn read_filter(partition: &Path, header: &PartitionHeader, keys: &KeyRing) -> Result<(KeyFilter, bool)> {
+    if let Some((stamp, filter)) = read_stored(partition, header, keys) {
+        if file_stamp(partition)? == stamp {
+            return Ok((filter, true));
+        }
+    }
+
+    let mut filter = KeyFilter::new();
+    scan_records(partition, header, keys, HEADER_LEN as u64, |_, entry| {
+        filter.insert(entry.source.as_deref(), entry.timestamp);
+    })?;
+    return Ok((filter, false));
+}
+
+fn read_stored(partition: &Path, header: &PartitionHeader, keys: &KeyRing) -> Option<((u64, u64), KeyFilter)> {
+    let bytes = fs::read(filter_path(partition)).ok()?;
+    let mut bytes = decrypt_sidecar(header, keys, FILTER_TAG, &bytes)?;
+    if bytes.len() != STAMP_LEN + FILTER_BITS / 8 {
+        return None;
+    }
+    let file_len = u64::from_le_bytes(bytes[0..8].try_into().ok()?);
+    let modified = u64::from_le_bytes(bytes[8..16].try_into().ok()?);
+    return Some(((file_len, modified), KeyFilter { bits: bytes.split_off(STAMP_LEN) }));
+}
+
+/***
+* Function write_filter:
+*
+* Purpose:
+* Stores the key filter of a partition as it is now, its writer must have
+* flushed the records inserted into it
+***/
+pub(crate) fn write_filter(partition: &Path, header: &PartitionHeader, keys: &KeyRing, filter: &KeyFilter) -> Result<()> {
+    let (file_len, modified) = file_stamp(partition)?;
+    let mut bytes = Vec::with_capacity(STAMP_LEN + filter.bits.len());
+    bytes.extend_from_slice(&file_len.to_le_bytes());
+    bytes.extend_from_slice(&modified.to_le_bytes());
+    bytes.extend_from_slice(&filter.bits);
+    fs::write(filter_path(partition), encrypt_sidecar(header, keys, FILTER_TAG, &bytes)?)?;
+    return Ok(());
+}
+
+/***
+* Function find_record:
+*
+* Purpose:
+* True if a partition that isn't sealed holds a record of 'source' at
+* 'timestamp', Ex: to confirm a hit of its KeyFilter. A partition in time
+* order is read from the last indexed record before 'timestamp' up to the
+* first record after it, see seek, others from the start.
+***/
+pub(crate) fn find_record(partition: &Path, header: &PartitionHeader, keys: &KeyRing, sorted: bool,
+                          source: Option<&str>, timestamp: u64) -> Result<bool> {
+    let (_, mut data) = map_partition(partition, keys)?;
+    let mut pos = match sorted {
+        true => seek(partition, header, keys, timestamp, |offset, indexed| match data.frame(offset) {
+            Ok(Frame::Record(payload, _)) => timestamp_of(header, keys, offset as u64, payload) == Some(indexed),
+            _ => false
+        }),
+        false => HEADER_LEN
+    };
+    while !data.at_end(pos)? {
+        let (record, next) = match data.frame(pos)? {
+            Frame::Record(payload, next) => {
+                let record = with_record(header, keys, pos as u64, payload, |entry| {
+                    (entry.timestamp, entry.source.as_deref() == source)
+                });
+                (record, next)
+            }
+            Frame::Damaged(next) => (None, next)
+        };
+        match record {
+            Some((at, true)) if at == timestamp => return Ok(true),
+            Some((at, _)) if sorted && at > timestamp => return Ok(false),
+            _ => {}
+        }
+        pos = next;
+    }
+    return Ok(false);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::mem;
+    use crate::database::{Database, Entry};
+    use crate::format::Compression;
+    use crate::order::is_sorted;
+    use crate::registry::{Duplicates, TableOptions};
+    use crate::seal::{read_header, read_partition};
+    use serde::{Serialize, Deserialize};
+    use crate::summary::{Summarize, read_summary};
+    use crate::table::Schema;
+    use crate::testing::{TempDir, entry};
+    use super::*;
+
+    const T: u64 = 1_577_887_200_000;      // 2020-01-01 14:00:00 UTC
+    const HOUR: u64 = 3_600_000;
+
+    fn open(dir: &TempDir, duplicates: Duplicates) -> Database {
+        let database = Database::new(dir.path()).unwrap();
+        database.create_table("levels", TableOptions { duplicates: duplicates, ..TableOptions::default() }).unwrap();
+        return database;
+    }
+
+    fn partition(dir: &TempDir) -> PathBuf {
+        dir.path().join("levels").join("20200101").join("14")
+    }
+
+    fn datalogs(database: &Database) -> Vec<Vec<u8>> {
+        database.range("levels", T, T + HOUR).unwrap().map(|record| record.unwrap().datalog).collect()
+    }
+
+    // Entry of 'data' from a device
+    fn from(source: &str, timestamp: u64, data: &[u8]) -> Entry<'static> {
+        Entry { source: Some(source.to_string()), ..entry("levels", timestamp, data) }
+    }
+
+    #[test]
+    fn ignore_keeps_the_stored_record() {
+        let dir = TempDir::new("dedup_ignore");
+        let database = open(&dir, Duplicates::Ignore);
+        database.insert(from("sensor-7", T, b"first")).unwrap();
+        database.insert(from("sensor-7", T, b"retried")).unwrap();
+        database.insert(from("sensor-8", T, b"other device")).unwrap();
+        database.insert(entry("levels", T, b"no source")).unwrap();
+        database.insert(entry("levels", T, b"no source again")).unwrap();
+
+        let records: Vec<_> = database.range("levels", T, T + 1).unwrap().map(Result::unwrap).collect();
+        let stored: Vec<_> = records.iter().map(|record| (record.source.as_deref(), &record.datalog[..])).collect();
+        assert_eq!(stored, vec![(Some("sensor-7"), &b"first"[..]), (Some("sensor-8"), &b"other device"[..]),
+                                (None, &b"no source"[..])]);
+    }
+
+    #[test]
+    fn replace_keeps_the_latest_record() {
+        let dir = TempDir::new("dedup_replace");
+        let database = open(&dir, Duplicates::Replace);
+        database.insert(from("sensor-7", T, b"first")).unwrap();
+        database.insert(from("sensor-7", T + 1, b"next")).unwrap();
+        database.insert(from("sensor-7", T, b"second")).unwrap();
+        database.insert(from("sensor-7", T, b"third, longer")).unwrap();
+        database.insert(from("sensor-8", T, b"other device")).unwrap();
+        assert_eq!(datalogs(&database), vec![b"third, longer".to_vec(), b"other device".to_vec(), b"next".to_vec()]);
+
+        // Reopened, the stored filter catches the record to replace
+        drop(database);
+        let database = Database::new(dir.path()).unwrap();
+        database.insert(from("sensor-7", T + 1, b"last")).unwrap();
+        assert_eq!(datalogs(&database), vec![b"third, longer".to_vec(), b"other device".to_vec(), b"last".to_vec()]);
+    }
+
+    #[test]
+    fn replace_appends_and_sealing_compacts() {
+        let dir = TempDir::new("dedup_append");
+        let database = Database::new(dir.path()).unwrap();
+        let options = TableOptions { duplicates: Duplicates::Replace, compression: Compression::Zstd,
+                                     ..TableOptions::default() };
+        database.create_table("levels", options).unwrap();
+        database.insert(entry("levels", T, b"old")).unwrap();
+        database.insert(entry("levels", T + 1, b"kept")).unwrap();
+        database.flush().unwrap();
+        let before = fs::read(partition(&dir)).unwrap();
+        assert!(is_sorted(&partition(&dir)));
+
+        // The stored record stays where it is, the replacement follows it
+        database.insert(entry("levels", T, b"new")).unwrap();
+        database.flush().unwrap();
+        assert!(fs::read(partition(&dir)).unwrap().starts_with(&before));
+        assert!(!is_sorted(&partition(&dir)));
+        assert_eq!(datalogs(&database), vec![b"new".to_vec(), b"kept".to_vec()]);
+
+        // Moving on seals the partition without the record replaced
+        database.insert(entry("levels", T + HOUR, b"next hour")).unwrap();
+        let mut buf = Vec::new();
+        read_partition(&partition(&dir), &KeyRing::default(), &mut buf).unwrap();
+        assert!(!buf.windows(3).any(|window| window == b"old"));
+        assert!(is_sorted(&partition(&dir)));
+        assert_eq!(database.range("levels", T, T + 1).unwrap().map(|record| record.unwrap().datalog).collect::<Vec<_>>(),
+                   vec![b"new".to_vec(), b"kept".to_vec()]);
+    }
+
+    #[test]
+    fn summaries_leave_replaced_records_out() {
+        #[derive(Serialize, Deserialize)]
+        struct Level(f64);
+        impl Schema for Level {
+            const NAME: &'static str = "Level";
+            const VERSION: u32 = 1;
+        }
+        impl Summarize for Level {
+            fn fields(&self) -> Vec<(&'static str, Option<f64>)> {
+                vec![("level", Some(self.0))]
+            }
+        }
+
+        let dir = TempDir::new("dedup_summary");
+        let database = Database::new(dir.path()).unwrap();
+        database.register_summary::<Level>("Level");
+        database.create_table("levels", TableOptions { duplicates: Duplicates::Replace, ..TableOptions::of::<Level>() })
+            .unwrap();
+        let levels = database.table::<Level>("levels").unwrap();
+        levels.insert_at(T, &Level(4.5)).unwrap();
+        levels.insert_at(T, &Level(1.0)).unwrap();
+        levels.insert_at(T + HOUR, &Level(2.0)).unwrap();
+        let summary = read_summary(&partition(&dir), database.keys()).unwrap();
+        assert_eq!((summary.records, summary.fields["level"].sum), (1, 1.0));
+    }
+
+    #[test]
+    fn duplicates_are_caught_after_a_crash() {
+        let dir = TempDir::new("dedup_crash");
+        let database = Database::builder().source(dir.path()).wal(true).build().unwrap();
+        database.create_table("levels", TableOptions { duplicates: Duplicates::Ignore, ..TableOptions::default() })
+            .unwrap();
+        database.insert(from("sensor-7", T, b"first")).unwrap();
+        database.insert(from("sensor-7", T + 1, b"second")).unwrap();
+        mem::forget(database);      // Crash, the records are only in the log and no filter was stored
+
+        let database = Database::builder().source(dir.path()).wal(true).build().unwrap();
+        database.insert(from("sensor-7", T, b"retried")).unwrap();
+        database.insert(from("sensor-7", T + 1, b"retried")).unwrap();
+        assert_eq!(datalogs(&database), vec![b"first".to_vec(), b"second".to_vec()]);
+    }
+
+    #[test]
+    fn filter_is_stored_on_close() {
+        let dir = TempDir::new("dedup_stored");
+        let database = open(&dir, Duplicates::Ignore);
+        for i in 0..3 {
+            database.insert(entry("levels", T + i, b"first")).unwrap();
+        }
+        drop(database);
+        let header = read_header(&partition(&dir)).unwrap();
+        let (filter, stored) = read_filter(&partition(&dir), &header, &KeyRing::default()).unwrap();
+        assert!(stored);
+        assert!((0..3).all(|i| filter.may_contain(None, T + i)));
+        assert_eq!(fs::metadata(filter_path(&partition(&dir))).unwrap().len(), (STAMP_LEN + FILTER_BITS / 8) as u64);
+
+        // Reopened, the stored filter still catches a retried upload
+        let database = Database::new(dir.path()).unwrap();
+        database.insert(entry("levels", T + 1, b"retried")).unwrap();
+        assert_eq!(datalogs(&database), vec![b"first".to_vec(); 3]);
+    }
+
+    #[test]
+    fn stale_or_damaged_filter_is_rebuilt() {
+        let dir = TempDir::new("dedup_stale");
+        let database = open(&dir, Duplicates::Ignore);
+        database.insert(entry("levels", T, b"a")).unwrap();
+        drop(database);
+        let old = fs::read(filter_path(&partition(&dir))).unwrap();
+
+        // Filter stored before the partition took another record
+        let database = Database::new(dir.path()).unwrap();
+        database.insert(entry("levels", T + 1, b"b")).unwrap();
+        drop(database);
+        fs::write(filter_path(&partition(&dir)), &old).unwrap();
+        let database = Database::new(dir.path()).unwrap();
+        database.insert(entry("levels", T + 1, b"retried")).unwrap();
+        drop(database);
+
+        fs::write(filter_path(&partition(&dir)), b"damaged").unwrap();
+        let database = Database::new(dir.path()).unwrap();
+        database.insert(entry("levels", T, b"retried")).unwrap();
+        assert_eq!(datalogs(&database), vec![b"a".to_vec(), b"b".to_vec()]);
+    }
+
+    #[test]
+    fn filter_hits_are_confirmed_through_the_index() {
+        let dir = TempDir::new("dedup_confirm");
+        let database = Database::builder().source(dir.path()).index_interval(2).build().unwrap();
+        database.create_table("levels", TableOptions { duplicates: Duplicates::Ignore, ..TableOptions::default() })
+            .unwrap();
+        for i in 0..10 {
+            database.insert(from("sensor-7", T + i * 1000, &[i as u8])).unwrap();
+        }
+        database.flush().unwrap();
+
+        let header = read_header(&partition(&dir)).unwrap();
+        let find = |source: &str, timestamp: u64, sorted: bool| {
+            find_record(&partition(&dir), &header, database.keys(), sorted, Some(source), timestamp).unwrap()
+        };
+        assert!(find("sensor-7", T + 5000, true));
+        assert!(find("sensor-7", T + 9000, true));
+        assert!(!find("sensor-8", T + 5000, true));
+        assert!(!find("sensor-7", T + 5500, true));
+        assert!(find("sensor-7", T, false));
+    }
+}
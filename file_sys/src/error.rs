@@ -0,0 +1,100 @@
+use std::error;
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+use crate::format::MAX_FRAME_LEN;
+
+pub type Result<T> = std::result::Result<T, DbError>;
+
+#[derive(Debug)]
+pub enum DbError {
+    Io(io::Error),                                          // Underlying file system error
+    Serialization(rmps::encode::Error),                     // Record could not be encoded
+    Deserialization(rmps::decode::Error),                   // Record could not be decoded
+    CorruptRecord { file: PathBuf, offset: u64 },           // Unreadable bytes inside a partition file
+    ChecksumMismatch { file: PathBuf, offset: u64 },        // 'datalog' does not match its CRC-32
+    NotFound(PathBuf),                                      // Requested file/folder does not exist
+    InvalidRange { start: u64, end: u64 },                  // Query range ends before it starts or past year 262143
+    InvalidTimestamp(u64),                                  // Record time past year 262143, see Partitioning
+    RecordTooLarge(usize),                                  // Stored record would be longer than a frame can be
+    InvalidDate(String),                                    // Date is not in the partition format, Ex: 20200101
+    InvalidSource(PathBuf),                                 // Database root is missing or not a directory
+    Config(String),                                         // Configuration file could not be parsed
+    UnknownTable(String),                                   // Table was never created
+    SchemaMismatch { table: String, schema: String, version: u32 },    // Table stores another schema, see Schema
+    TableExists(String),                                    // Table was already created
+    InvalidTableName(String),                               // Name is not a single directory
+    InvalidManifest { file: PathBuf, message: String },     // Table manifest could not be read/written
+    InvalidHeader { file: PathBuf, message: String },       // Partition file header is missing or foreign
+    UnsupportedVersion { file: PathBuf, version: u16 },     // Partition file written by another format version
+    Encryption(String),                                     // No key configured or encryption failed
+    MissingKey(u32),                                        // Data was encrypted with a key that isn't configured
+    Decryption { file: PathBuf, offset: u64 },              // Encrypted data failed authentication
+}
+
+impl fmt::Display for DbError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DbError::Io(err) => write!(f, "I/O error: {}", err),
+            DbError::Serialization(err) => write!(f, "Error serializing: {}", err),
+            DbError::Deserialization(err) => write!(f, "Error deserializing: {}", err),
+            DbError::CorruptRecord { file, offset } =>
+                write!(f, "Corrupt record in {:?} at byte {}", file, offset),
+            DbError::ChecksumMismatch { file, offset } =>
+                write!(f, "Checksum mismatch in {:?} at byte {}", file, offset),
+            DbError::NotFound(path) => write!(f, "{:?} does not exist", path),
+            DbError::InvalidRange { start, end } =>
+                write!(f, "Invalid time range {} to {}", start, end),
+            DbError::InvalidTimestamp(timestamp) => write!(f, "Invalid record time {}", timestamp),
+            DbError::RecordTooLarge(len) =>
+                write!(f, "Record of {} bytes is longer than the {} a frame can hold", len, MAX_FRAME_LEN),
+            DbError::InvalidDate(date) => write!(f, "Invalid date {:?}, expected YYYYMMDD", date),
+            DbError::InvalidSource(path) => write!(f, "{:?} is not a usable database source", path),
+            DbError::Config(message) => write!(f, "Invalid configuration: {}", message),
+            DbError::UnknownTable(name) => write!(f, "Table {:?} does not exist", name),
+            DbError::SchemaMismatch { table, schema, version } =>
+                write!(f, "Table {:?} stores {:?} version {}", table, schema, version),
+            DbError::TableExists(name) => write!(f, "Table {:?} already exists", name),
+            DbError::InvalidTableName(name) => write!(f, "Invalid table name {:?}", name),
+            DbError::InvalidManifest { file, message } =>
+                write!(f, "Invalid manifest {:?}: {}", file, message),
+            DbError::InvalidHeader { file, message } =>
+                write!(f, "Invalid partition header in {:?}: {}", file, message),
+            DbError::UnsupportedVersion { file, version } =>
+                write!(f, "Unsupported format version {} in {:?}", version, file),
+            DbError::Encryption(message) => write!(f, "Encryption error: {}", message),
+            DbError::MissingKey(id) => write!(f, "No key with id {:08x} is configured", id),
+            DbError::Decryption { file, offset } =>
+                write!(f, "Encrypted data in {:?} at byte {} failed authentication", file, offset),
+        }
+    }
+}
+
+impl error::Error for DbError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            DbError::Io(err) => Some(err),
+            DbError::Serialization(err) => Some(err),
+            DbError::Deserialization(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for DbError {
+    fn from(err: io::Error) -> DbError {
+        DbError::Io(err)
+    }
+}
+
+impl From<rmps::encode::Error> for DbError {
+    fn from(err: rmps::encode::Error) -> DbError {
+        DbError::Serialization(err)
+    }
+}
+
+impl From<rmps::decode::Error> for DbError {
+    fn from(err: rmps::decode::Error) -> DbError {
+        DbError::Deserialization(err)
+    }
+}
@@ -0,0 +1,324 @@
+use std::convert::TryInto;
+use std::path::Path;
+use crc::crc32;
+use serde::{Serialize, Deserialize};
+use crate::error::{DbError, Result};
+
+pub(crate) static MAGIC: &[u8; 4] = b"FSYS";
+pub(crate) const FORMAT_VERSION: u16 = 1;          // Files from before the header are read as version 0, see legacy
+pub(crate) const HEADER_LEN: usize = 24;
+
+pub(crate) const SYNC_MARKER: [u8; 4] = [0xF5, 0x5A, 0xA5, 0x5F];
+pub(crate) const FRAME_HEADER_LEN: usize = 12;
+pub(crate) const MAX_FRAME_LEN: usize = 16 * 1024 * 1024;
+
+// Encoding of the records following the header
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Codec {
+    #[default]
+    MsgPack,    // Frames of msgpack MpdRecordType
+    Columnar,   // RawData columns, sealed partitions only
+}
+
+impl Codec {
+    fn to_byte(self) -> u8 {
+        match self {
+            Codec::MsgPack => 0,
+            Codec::Columnar => 1,
+        }
+    }
+
+    fn from_byte(byte: u8) -> Option<Codec> {
+        match byte {
+            0 => Some(Codec::MsgPack),
+            1 => Some(Codec::Columnar),
+            _ => None
+        }
+    }
+}
+
+// Compression of the frames following the header, only sealed partitions are compressed
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Compression {
+    #[default]
+    None,
+    Zstd,       // Smaller, for tables read rarely
+    Lz4,        // Faster to read back
+}
+
+impl Compression {
+    fn to_byte(self) -> u8 {
+        match self {
+            Compression::None => 0,
+            Compression::Zstd => 1,
+            Compression::Lz4 => 2,
+        }
+    }
+
+    fn from_byte(byte: u8) -> Option<Compression> {
+        match byte {
+            0 => Some(Compression::None),
+            1 => Some(Compression::Zstd),
+            2 => Some(Compression::Lz4),
+            _ => None
+        }
+    }
+}
+
+// Authenticated encryption of a partition: each frame while it is open, the whole body once it is sealed
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Cipher {
+    #[default]
+    None,
+    #[serde(rename = "chacha20-poly1305")]
+    ChaCha20Poly1305,
+    #[serde(rename = "aes-256-gcm")]
+    Aes256Gcm,
+}
+
+impl Cipher {
+    fn to_byte(self) -> u8 {
+        match self {
+            Cipher::None => 0,
+            Cipher::ChaCha20Poly1305 => 1,
+            Cipher::Aes256Gcm => 2,
+        }
+    }
+
+    fn from_byte(byte: u8) -> Option<Cipher> {
+        match byte {
+            0 => Some(Cipher::None),
+            1 => Some(Cipher::ChaCha20Poly1305),
+            2 => Some(Cipher::Aes256Gcm),
+            _ => None
+        }
+    }
+}
+
+/***
+* Struct PartitionHeader:
+*
+* Purpose:
+* First HEADER_LEN bytes of every partition file, little endian:
+*
+*   0   magic           "FSYS"
+*   4   version         u16
+*   6   codec           u8
+*   7   compression     low 4 bits, 0 none, 1 zstd, 2 lz4
+*       cipher          high 4 bits, 0 none, 1 chacha20-poly1305, 2 aes-256-gcm
+*   8   table id        u32, from the table manifest
+*   12  start           u64, partition start in milliseconds since epoch
+*   20  checksum        u32, CRC-32 of bytes 0..20
+***/
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PartitionHeader {
+    pub version:        u16,
+    pub codec:          Codec,
+    pub compression:    Compression,
+    pub cipher:         Cipher,
+    pub table_id:       u32,
+    pub start:          u64,
+}
+
+impl PartitionHeader {
+    // Header of a new partition in the current format
+    pub(crate) fn new(codec: Codec, table_id: u32, start: u64) -> PartitionHeader {
+        PartitionHeader {
+            version:        FORMAT_VERSION,
+            codec:          codec,
+            compression:    Compression::None,
+            cipher:         Cipher::None,
+            table_id:       table_id,
+            start:          start,
+        }
+    }
+
+    pub(crate) fn encode(&self) -> [u8; HEADER_LEN] {
+        let mut bytes = [0u8; HEADER_LEN];
+        bytes[0..4].copy_from_slice(MAGIC);
+        bytes[4..6].copy_from_slice(&self.version.to_le_bytes());
+        bytes[6] = self.codec.to_byte();
+        bytes[7] = self.compression.to_byte() | self.cipher.to_byte() << 4;
+        bytes[8..12].copy_from_slice(&self.table_id.to_le_bytes());
+        bytes[12..20].copy_from_slice(&self.start.to_le_bytes());
+        let checksum = crc32::checksum_ieee(&bytes[0..20]);
+        bytes[20..24].copy_from_slice(&checksum.to_le_bytes());
+        return bytes;
+    }
+
+    // Parse and validate the header at the start of 'bytes'
+    pub(crate) fn decode(file: &Path, bytes: &[u8]) -> Result<PartitionHeader> {
+        let invalid = |message: &str| DbError::InvalidHeader { file: file.to_path_buf(), message: message.to_string() };
+
+        if bytes.len() < HEADER_LEN {
+            return Err(invalid("file is shorter than a header"));
+        }
+        if &bytes[0..4] != MAGIC {
+            return Err(invalid("not a partition file"));
+        }
+        if read_u32(&bytes[20..24]) != crc32::checksum_ieee(&bytes[0..20]) {
+            return Err(invalid("header checksum mismatch"));
+        }
+
+        let version = u16::from_le_bytes([bytes[4], bytes[5]]);
+        if version != FORMAT_VERSION {
+            return Err(DbError::UnsupportedVersion { file: file.to_path_buf(), version: version });
+        }
+        let codec = match Codec::from_byte(bytes[6]) {
+            Some(codec) => codec,
+            None => return Err(invalid("unknown codec"))
+        };
+        let compression = match Compression::from_byte(bytes[7] & 0x0f) {
+            Some(compression) => compression,
+            None => return Err(invalid("unknown compression"))
+        };
+        let cipher = match Cipher::from_byte(bytes[7] >> 4) {
+            Some(cipher) => cipher,
+            None => return Err(invalid("unknown cipher"))
+        };
+
+        return Ok(PartitionHeader {
+            version:        version,
+            codec:          codec,
+            compression:    compression,
+            cipher:         cipher,
+            table_id:       read_u32(&bytes[8..12]),
+            start:          u64::from_le_bytes(bytes[12..20].try_into().unwrap_or_default()),
+        });
+    }
+
+    // True once the partition was rewritten by sealing, its body is then encoded, compressed and encrypted as a whole
+    pub fn is_sealed(&self) -> bool {
+        self.codec != Codec::MsgPack || self.compression != Compression::None
+    }
+
+    // True if the payload of every frame is encrypted on its own, the layout of open partitions
+    pub fn frames_encrypted(&self) -> bool {
+        self.cipher != Cipher::None && !self.is_sealed()
+    }
+
+    // Additional data authenticated with the encrypted data at 'offset' of the partition: the whole header and
+    // the offset, so a header rewritten to another cipher or table and frames moved, dropped or copied from
+    // elsewhere fail authentication
+    pub(crate) fn aad(&self, offset: u64) -> Vec<u8> {
+        let mut aad = Vec::with_capacity(HEADER_LEN + 8);
+        aad.extend_from_slice(&self.encode());
+        aad.extend_from_slice(&offset.to_le_bytes());
+        return aad;
+    }
+
+    // Reject a partition stored in plaintext while 'cipher' is configured: its header may have been rewritten
+    // to bypass authentication, and partitions written before encryption was enabled are left to rotate_keys
+    pub(crate) fn check_cipher(&self, file: &Path, cipher: Cipher) -> Result<()> {
+        if cipher != Cipher::None && self.cipher == Cipher::None {
+            return Err(DbError::InvalidHeader {
+                file:       file.to_path_buf(),
+                message:    "stored in plaintext while a cipher is configured, see Database::rotate_keys".to_string(),
+            });
+        }
+        return Ok(());
+    }
+
+    // Reject headers written for another table, table id 0 on either side is never checked,
+    // Ex: a file from before headers existed
+    pub(crate) fn check_table(&self, file: &Path, table_id: u32) -> Result<()> {
+        if table_id != 0 && self.table_id != 0 && self.table_id != table_id {
+            return Err(DbError::InvalidHeader {
+                file:       file.to_path_buf(),
+                message:    format!("belongs to table {} not {}", self.table_id, table_id),
+            });
+        }
+        return Ok(());
+    }
+}
+
+pub(crate) fn read_u32(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes(bytes.try_into().unwrap_or_default())
+}
+
+// Result of reading the frame at an offset
+pub(crate) enum Frame<'a> {
+    Record(&'a [u8], usize),    // Payload and offset of the next frame
+    Damaged(usize),             // Offset of the next sync marker, or the end of the buffer
+}
+
+/***
+* Function encode_frame:
+*
+* Purpose:
+* Wraps a payload as sync marker | length u32 | CRC-32 u32 | payload
+***/
+pub(crate) fn encode_frame(payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(FRAME_HEADER_LEN + payload.len());
+    frame.extend_from_slice(&SYNC_MARKER);
+    frame.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    frame.extend_from_slice(&crc32::checksum_ieee(payload).to_le_bytes());
+    frame.extend_from_slice(payload);
+    return frame;
+}
+
+/***
+* Function read_frame:
+*
+* Purpose:
+* Reads the frame at 'pos'. A torn or corrupt frame is skipped up to the next
+* sync marker so the records after it can still be read.
+***/
+pub(crate) fn read_frame(buf: &[u8], pos: usize) -> Frame<'_> {
+    if buf.len() - pos >= FRAME_HEADER_LEN && buf[pos..pos + 4] == SYNC_MARKER {
+        let len = read_u32(&buf[pos + 4..pos + 8]) as usize;
+        let start = pos + FRAME_HEADER_LEN;
+        if len <= MAX_FRAME_LEN && len <= buf.len() - start {
+            let payload = &buf[start..start + len];
+            if read_u32(&buf[pos + 8..pos + 12]) == crc32::checksum_ieee(payload) {
+                return Frame::Record(payload, start + len);
+            }
+        }
+    }
+
+    return Frame::Damaged(find_sync(buf, pos + 1));
+}
+
+// Offset of the first sync marker at or after 'pos'
+pub(crate) fn find_sync(buf: &[u8], pos: usize) -> usize {
+    if pos >= buf.len() {
+        return buf.len();
+    }
+    match buf[pos..].windows(SYNC_MARKER.len()).position(|window| window == SYNC_MARKER) {
+        Some(offset) => pos + offset,
+        None => buf.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use super::*;
+
+    #[test]
+    fn header_round_trip_and_rejections() {
+        let file = Path::new("levels/20200101/14");
+        let header = PartitionHeader::new(Codec::MsgPack, 3, 1_577_887_200_000);
+        let bytes = header.encode();
+        assert_eq!(PartitionHeader::decode(file, &bytes).unwrap(), header);
+        assert!(header.check_table(file, 3).is_ok());
+        assert!(header.check_table(file, 0).is_ok());
+        assert!(matches!(header.check_table(file, 4), Err(DbError::InvalidHeader { .. })));
+
+        let corrupt = |at: usize| {
+            let mut bytes = bytes;
+            bytes[at] ^= 1;
+            PartitionHeader::decode(file, &bytes)
+        };
+        assert!(matches!(corrupt(0), Err(DbError::InvalidHeader { .. })));
+        assert!(matches!(corrupt(13), Err(DbError::InvalidHeader { .. })));
+        assert!(matches!(PartitionHeader::decode(file, &bytes[..20]), Err(DbError::InvalidHeader { .. })));
+
+        let newer = PartitionHeader { version: FORMAT_VERSION + 1, ..header };
+        assert!(matches!(PartitionHeader::decode(file, &newer.encode()),
+                         Err(DbError::UnsupportedVersion { version, .. }) if version == FORMAT_VERSION + 1));
+    }
+}
@@ -0,0 +1,362 @@
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use crc::crc32;
+use crate::config::LogLevel;
+use crate::crypto::{decrypt, encrypt_frames};
+use crate::database::{Database, MpdRecordType};
+use crate::dedup::filter_path;
+use crate::error::{DbError, Result};
+use crate::format::{Codec, Frame, PartitionHeader, FORMAT_VERSION, HEADER_LEN, read_frame};
+use crate::index::index_path;
+use crate::order::unsorted_path;
+use crate::partition::DATE_FORMAT;
+use crate::query::{QUARANTINE_DIR, partition_days, quarantine_path};
+use crate::registry::read_manifest;
+use crate::seal::{read_partition, recompress, replace_partition, write_partition};
+use crate::summary::summary_path;
+
+/***
+* Struct FsckReport:
+*
+* Purpose:
+* Outcome of checking every partition file of a source. When only verifying,
+* the counts describe what a repair would truncate and move.
+***/
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FsckReport {
+    pub repaired:               bool,           // Problems were fixed, not only found
+    pub files_scanned:          u64,
+    pub files_quarantined:      u64,            // Files with an unreadable header
+    pub files_migrated:         u64,            // Files of an older format rewritten in the current one
+    pub files_removed:          u64,            // Empty files, created before their header by older versions
+    pub records_ok:             u64,
+    pub records_corrupt:        u64,            // Damaged frames, undecodable payloads and checksum mismatches
+    pub bytes_truncated:        u64,            // Torn tails left by crashes
+    pub issues:                 Vec<String>,    // One line per problem, Ex: data/levels/20200101/14: ...
+}
+
+impl FsckReport {
+    // True when nothing needed repairing
+    pub fn is_clean(&self) -> bool {
+        self.issues.is_empty()
+    }
+}
+
+impl fmt::Display for FsckReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for issue in &self.issues {
+            writeln!(f, "{}", issue)?;
+        }
+        let action = if self.repaired { "" } else { " (not repaired)" };
+        writeln!(f, "files scanned:      {}", self.files_scanned)?;
+        writeln!(f, "records ok:         {}", self.records_ok)?;
+        writeln!(f, "records corrupt:    {}", self.records_corrupt)?;
+        writeln!(f, "bytes truncated:    {}{}", self.bytes_truncated, action)?;
+        writeln!(f, "files migrated:     {}{}", self.files_migrated, action)?;
+        writeln!(f, "files removed:      {}{}", self.files_removed, action)?;
+        write!(f, "files quarantined:  {}{}", self.files_quarantined, action)
+    }
+}
+
+/***
+* Function check_source:
+*
+* Purpose:
+* Walks every table/day/partition file of the database and validates headers,
+* frames and checksums. With 'repair', torn tails are truncated, files of an
+* older format are rewritten in the current one, empty files are removed and
+* files with an unreadable header are moved to data/<table>/quarantine.
+* Nothing may be writing to the source while it runs.
+***/
+pub(crate) fn check_source(database: &Database, repair: bool) -> Result<FsckReport> {
+    let mut report = FsckReport { repaired: repair, ..FsckReport::default() };
+    let source = database.source();
+    if !source.is_dir() {
+        return Err(DbError::InvalidSource(source.to_path_buf()));
+    }
+
+    let mut tables = Vec::new();
+    for entry in fs::read_dir(source)? {
+        let entry = entry?;
+        if entry.path().is_dir() {
+            tables.push(entry.path());
+        }
+    }
+    tables.sort();
+
+    for table in tables {
+        let table_id = read_manifest(&table)?.map_or(0, |manifest| manifest.id);
+        for day in partition_days(&table)? {
+            let directory = table.join(day.format(DATE_FORMAT).to_string());
+            let mut files = Vec::new();
+            for entry in fs::read_dir(&directory)? {
+                let entry = entry?;
+                // Sidecars (index, summary, unsorted marker) have an extension, partitions don't
+                if entry.path().is_file() && entry.path().extension().is_none() {
+                    files.push(entry.path());
+                }
+            }
+            files.sort();
+
+            for file in files {
+                check_file(database, &table, &file, table_id, repair, &mut report)?;
+            }
+        }
+    }
+
+    return Ok(report);
+}
+
+// Check one partition file, see check_source
+fn check_file(database: &Database, table: &Path, file: &Path, table_id: u32, repair: bool,
+              report: &mut FsckReport) -> Result<()> {
+    report.files_scanned += 1;
+    let mut buf = Vec::new();
+
+    /*** Remove Empty File ***/
+    if fs::metadata(file)?.len() == 0 {
+        // Left by a crash of a version that wrote the header with the first record, readers skip it
+        report.files_removed += 1;
+        report.issues.push(format!("{:?}: empty file", file));
+        if repair {
+            fs::remove_file(file)?;
+            database.log(LogLevel::Info, || format!("Removed {:?}", file));
+        }
+        return Ok(());
+    }
+
+    /*** Check Header ***/
+    let keys = database.keys();
+    let header = read_partition(file, keys, &mut buf).and_then(|header| {
+        header.check_table(file, table_id)?;
+        Ok(header)
+    });
+    let mut header = match header {
+        Ok(header) => header,
+        Err(err @ DbError::Io(_)) | Err(err @ DbError::MissingKey(_)) => return Err(err),
+        Err(err @ DbError::UnsupportedVersion { .. }) => {
+            // Readable by another version of the crate, leave it alone
+            report.issues.push(format!("{}, left in place", err));
+            return Ok(());
+        }
+        Err(err) => {
+            report.issues.push(format!("{}, unrecoverable", err));
+            report.files_quarantined += 1;
+            if repair {
+                let destination = quarantine_path(table, file).with_extension("partition");
+                fs::create_dir_all(table.join(QUARANTINE_DIR))?;
+                fs::rename(file, &destination)?;
+                for sidecar in &[index_path(file), filter_path(file), summary_path(file), unsorted_path(file)] {
+                    // Describe a partition that is no longer there
+                    if sidecar.exists() {
+                        fs::remove_file(sidecar)?;
+                    }
+                }
+                database.log(LogLevel::Info, || format!("Moved {:?} to {:?}", file, destination));
+            }
+            return Ok(());
+        }
+    };
+
+    /*** Migrate Older Format ***/
+    if header.version != FORMAT_VERSION {
+        report.files_migrated += 1;
+        if repair {
+            // Damaged frames are kept and truncated or counted below, like in any other partition
+            match recompress(file, header.codec, header.compression, header.cipher, keys, false, false) {
+                Ok(_) => {
+                    database.log(LogLevel::Info, || format!("Migrated {:?} to format version {}", file, FORMAT_VERSION));
+                    header = read_partition(file, keys, &mut buf)?;
+                }
+                Err(err @ DbError::Io(_)) => return Err(err),
+                Err(err) => report.issues.push(format!("{:?}: not migrated, {}", file, err))
+            }
+        }
+    }
+    if let Err(err) = header.check_cipher(file, database.config().cipher) {
+        report.issues.push(err.to_string());
+    }
+
+    /*** Check Frames ***/
+    let mut pos = HEADER_LEN;
+    let mut intact_end = HEADER_LEN;    // End of the last intact frame
+    let mut damaged = Vec::new();       // Damaged spans since the last intact frame
+    while pos < buf.len() {
+        match read_frame(&buf, pos) {
+            Frame::Record(payload, next) => {
+                // Damaged spans followed by an intact frame are skipped by readers, not truncated
+                for (start, end) in damaged.drain(..) {
+                    report.records_corrupt += 1;
+                    report.issues.push(format!("{:?}: {} damaged bytes at byte {}", file, end - start, start));
+                }
+                let payload = match header.frames_encrypted() {
+                    true => decrypt(header.cipher, keys, &header.aad(pos as u64), payload)?,
+                    false => Some(payload.to_vec())
+                };
+                match payload.map(|payload| rmps::from_read::<_, MpdRecordType>(&payload[..])) {
+                    None => {
+                        report.records_corrupt += 1;
+                        report.issues.push(format!("{:?}: record failing authentication at byte {}", file, pos));
+                    }
+                    Some(Ok(entry)) if crc32::checksum_ieee(&entry.datalog) == entry.checksum => report.records_ok += 1,
+                    Some(Ok(_)) => {
+                        report.records_corrupt += 1;
+                        report.issues.push(format!("{:?}: checksum mismatch at byte {}", file, pos));
+                    }
+                    Some(Err(_)) => {
+                        report.records_corrupt += 1;
+                        report.issues.push(format!("{:?}: undecodable record at byte {}", file, pos));
+                    }
+                }
+                pos = next;
+                intact_end = next;
+            }
+            Frame::Damaged(next) => {
+                damaged.push((pos, next));
+                pos = next;
+            }
+        }
+    }
+
+    /*** Truncate Torn Tail ***/
+    if intact_end < buf.len() {
+        let torn = buf.len() - intact_end;
+        report.records_corrupt += 1;
+        report.bytes_truncated += torn as u64;
+        report.issues.push(format!("{:?}: torn tail of {} bytes at byte {}", file, torn, intact_end));
+        if repair && header.version == FORMAT_VERSION {
+            if !header.is_sealed() {
+                replace_partition(file, &buf[..intact_end])?;
+            } else {
+                // Sealed, compress and encrypt the intact frames again
+                let header = PartitionHeader { codec: Codec::MsgPack, ..header };
+                let frames = &buf[HEADER_LEN..intact_end];
+                match header.frames_encrypted() {
+                    true => write_partition(file, &header, &encrypt_frames(&header, keys, frames, HEADER_LEN as u64)?, keys)?,
+                    false => write_partition(file, &header, frames, keys)?
+                }
+            }
+            database.log(LogLevel::Info, || format!("Truncated {:?} to {} bytes", file, intact_end));
+        }
+    }
+
+    return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{File, OpenOptions};
+    use std::io::prelude::*;
+    use std::path::PathBuf;
+    use memmap2::Mmap;
+    use crate::format::encode_frame;
+    use crate::registry::TableOptions;
+    use crate::testing::{TempDir, entry};
+    use super::*;
+
+    const T: u64 = 1_577_887_200_000;      // 2020-01-01 14:00:00 UTC
+    const HOUR: u64 = 3_600_000;
+
+    // Database with three records at 14:00 and one at 15:00
+    fn open(dir: &TempDir) -> Database {
+        let database = Database::new(dir.path()).unwrap();
+        database.create_table("levels", TableOptions::default()).unwrap();
+        for i in 0..3u8 {
+            database.insert(entry("levels", T + u64::from(i), &[i; 8])).unwrap();
+        }
+        database.insert(entry("levels", T + HOUR, b"next hour")).unwrap();
+        return database;
+    }
+
+    fn partition(dir: &TempDir) -> PathBuf {
+        dir.path().join("levels").join("20200101").join("14")
+    }
+
+    fn datalogs(database: &Database) -> Vec<Vec<u8>> {
+        database.range("levels", T, T + 2 * HOUR).unwrap().map(|record| record.unwrap().datalog).collect()
+    }
+
+    #[test]
+    fn clean_source() {
+        let dir = TempDir::new("fsck_clean");
+        let database = open(&dir);
+        let report = database.repair().unwrap();
+        assert!(report.is_clean());
+        assert_eq!((report.files_scanned, report.records_ok, report.records_corrupt), (2, 4, 0));
+    }
+
+    #[test]
+    fn repair_truncates_torn_tail() {
+        let dir = TempDir::new("fsck_torn");
+        let database = open(&dir);
+        let len = fs::metadata(partition(&dir)).unwrap().len();
+        let torn = &encode_frame(b"cut short")[..14];
+        OpenOptions::new().append(true).open(partition(&dir)).unwrap().write_all(torn).unwrap();
+
+        // Verifying leaves the file as it is
+        let report = database.verify().unwrap();
+        assert_eq!((report.records_ok, report.records_corrupt, report.bytes_truncated), (4, 1, torn.len() as u64));
+        assert_eq!(fs::metadata(partition(&dir)).unwrap().len(), len + torn.len() as u64);
+
+        let report = database.repair().unwrap();
+        assert_eq!(report.bytes_truncated, torn.len() as u64);
+        assert_eq!(fs::metadata(partition(&dir)).unwrap().len(), len);
+        assert!(database.verify().unwrap().is_clean());
+        assert_eq!(datalogs(&database), vec![vec![0; 8], vec![1; 8], vec![2; 8], b"next hour".to_vec()]);
+    }
+
+    #[test]
+    fn repair_leaves_mapped_readers_their_file() {
+        let dir = TempDir::new("fsck_mapped");
+        let database = open(&dir);
+        let len = fs::metadata(partition(&dir)).unwrap().len();
+        let torn = &encode_frame(&[7; 3 * 4096])[..2 * 4096];
+        OpenOptions::new().append(true).open(partition(&dir)).unwrap().write_all(torn).unwrap();
+
+        // Pages past the repaired length stay readable, a file shrunk in place would fault on them
+        let map = unsafe { Mmap::map(&File::open(partition(&dir)).unwrap()).unwrap() };
+        database.repair().unwrap();
+        assert_eq!(fs::metadata(partition(&dir)).unwrap().len(), len);
+        assert_eq!(map.len(), len as usize + torn.len());
+        assert!(map[len as usize + 12..].iter().all(|byte| *byte == 7));
+    }
+
+    #[test]
+    fn damaged_records_are_counted_and_skipped() {
+        let dir = TempDir::new("fsck_damaged");
+        let database = open(&dir);
+        let mut bytes = fs::read(partition(&dir)).unwrap();
+        let frame_len = (bytes.len() - HEADER_LEN) / 3;
+        bytes[HEADER_LEN + 2 * frame_len - 2] ^= 0xff;     // In the payload of the second record
+        fs::write(partition(&dir), &bytes).unwrap();
+
+        // Readers skip the frame up to the next sync marker, there is nothing to truncate
+        let report = database.repair().unwrap();
+        assert_eq!((report.records_ok, report.records_corrupt, report.bytes_truncated), (3, 1, 0));
+        assert_eq!(report.issues.len(), 1);
+        assert_eq!(fs::read(partition(&dir)).unwrap(), bytes);
+        assert_eq!(datalogs(&database), vec![vec![0; 8], vec![2; 8], b"next hour".to_vec()]);
+    }
+
+    #[test]
+    fn unreadable_header_is_quarantined() {
+        let dir = TempDir::new("fsck_quarantine");
+        let database = open(&dir);
+        let mut bytes = fs::read(partition(&dir)).unwrap();
+        bytes[10] ^= 0xff;      // Table id, failing the header checksum
+        fs::write(partition(&dir), &bytes).unwrap();
+
+        let report = database.verify().unwrap();
+        assert_eq!((report.files_quarantined, report.records_ok), (1, 1));
+        assert!(partition(&dir).exists());
+
+        let report = database.repair().unwrap();
+        assert_eq!(report.files_quarantined, 1);
+        assert!(!partition(&dir).exists());
+        let quarantined = fs::read_dir(dir.path().join("levels").join(QUARANTINE_DIR)).unwrap().count();
+        assert_eq!(quarantined, 1);
+        assert!(database.verify().unwrap().is_clean());
+        assert_eq!(datalogs(&database), vec![b"next hour".to_vec()]);
+    }
+}
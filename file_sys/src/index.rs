@@ -0,0 +1,176 @@
+use std::convert::TryInto;
+use std::fs;
+use std::fs::OpenOptions;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use crate::crypto::{KeyRing, decrypt, decrypt_sidecar, encrypt_sidecar, encrypted_len};
+use crate::database::MpdRecordRef;
+use crate::error::Result;
+use crate::format::{PartitionHeader, HEADER_LEN};
+
+pub(crate) static INDEX_EXTENSION: &str = "idx";
+const ENTRY_LEN: usize = 16;
+const INDEX_TAG: &[u8] = b"idx";
+
+/***
+* Sparse index:
+*
+* Purpose:
+* Sidecar of a partition file, Ex: data/levels/20200101/14.idx, holding one
+* entry every Config::index_interval records, little endian:
+*
+*   0   timestamp       u64, of the record
+*   8   offset          u64, of the record's frame in the partition file
+*
+* Entries of an encrypted partition are encrypted one by one, see
+* encrypt_sidecar, and take encrypted_len bytes each.
+*
+* The index is only a hint: entries are checked against the partition before
+* they are used and a missing or stale index falls back to reading from the start.
+***/
+
+// Sidecar index of a partition file
+pub(crate) fn index_path(partition: &Path) -> PathBuf {
+    partition.with_extension(INDEX_EXTENSION)
+}
+
+// Record that the frame at 'offset' of a partition holds 'timestamp'
+pub(crate) fn append_entry(partition: &Path, header: &PartitionHeader, keys: &KeyRing, timestamp: u64, offset: u64)
+                           -> Result<()> {
+    let mut entry = [0u8; ENTRY_LEN];
+    entry[0..8].copy_from_slice(&timestamp.to_le_bytes());
+    entry[8..16].copy_from_slice(&offset.to_le_bytes());
+    let entry = encrypt_sidecar(header, keys, INDEX_TAG, &entry)?;
+
+    let mut file = OpenOptions::new().create(true).append(true).open(index_path(partition))?;
+    file.write_all(&entry)?;
+    return Ok(());
+}
+
+/***
+* Function seek:
+*
+* Purpose:
+* Offset in a partition to start reading from for records at or after
+* 'start_time': the last indexed record before it if 'points_at' confirms the
+* record is still at that offset, or the first frame
+***/
+pub(crate) fn seek<F>(partition: &Path, header: &PartitionHeader, keys: &KeyRing, start_time: u64, points_at: F) -> usize
+                      where F: FnOnce(usize, u64) -> bool {
+    let index = match fs::read(index_path(partition)) {
+        Ok(index) => index,
+        Err(_) => return HEADER_LEN
+    };
+
+    // Entries are in write order, a torn last entry is ignored and so is the rest after one that doesn't decrypt
+    let mut last = None;
+    for entry in index.chunks_exact(encrypted_len(header.cipher, ENTRY_LEN)) {
+        let entry = match decrypt_sidecar(header, keys, INDEX_TAG, entry) {
+            Some(entry) => entry,
+            None => break
+        };
+        let timestamp = u64::from_le_bytes(entry[0..8].try_into().unwrap_or_default());
+        let offset = u64::from_le_bytes(entry[8..16].try_into().unwrap_or_default());
+        if timestamp >= start_time {
+            break;
+        }
+        last = Some((timestamp, offset));
+    }
+
+    match last {
+        Some((timestamp, offset)) if offset >= HEADER_LEN as u64 && points_at(offset as usize, timestamp) => {
+            offset as usize
+        }
+        _ => HEADER_LEN     // Nothing indexed before 'start_time', or the index is stale
+    }
+}
+
+// Timestamp of the record in the payload of the intact frame at 'offset', None if it isn't one
+pub(crate) fn timestamp_of(header: &PartitionHeader, keys: &KeyRing, offset: u64, payload: &[u8]) -> Option<u64> {
+    with_record(header, keys, offset, payload, |entry| entry.timestamp)
+}
+
+// Hand the record in the payload of the intact frame at 'offset' to 'f', None if it isn't one
+pub(crate) fn with_record<R, F>(header: &PartitionHeader, keys: &KeyRing, offset: u64, payload: &[u8], f: F) -> Option<R>
+                                where F: FnOnce(MpdRecordRef) -> R {
+    let decrypted;
+    let payload = match header.frames_encrypted() {
+        true => {
+            decrypted = decrypt(header.cipher, keys, &header.aad(offset), payload).ok()??;
+            &decrypted[..]
+        }
+        false => payload
+    };
+    return rmps::from_read_ref::<_, MpdRecordRef>(payload).ok().map(f);
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::database::Database;
+    use crate::format::{Frame, read_frame};
+    use crate::registry::TableOptions;
+    use crate::testing::{TempDir, entry};
+    use super::*;
+
+    const T: u64 = 1_577_887_200_000;      // 2020-01-01 14:00:00 UTC
+
+    // Ten records a second apart in one partition, every other one indexed
+    fn open(dir: &TempDir) -> (Database, PathBuf) {
+        let database = Database::builder().source(dir.path()).index_interval(2).build().unwrap();
+        database.create_table("levels", TableOptions::default()).unwrap();
+        for i in 0..10u8 {
+            database.insert(entry("levels", T + u64::from(i) * 1000, &[i])).unwrap();
+        }
+        database.flush().unwrap();
+        return (database, dir.path().join("levels").join("20200101").join("14"));
+    }
+
+    // Offset seek gives, checking the entry against the partition as a query does
+    fn seek_in(database: &Database, partition: &Path, buf: &[u8], start_time: u64) -> usize {
+        let header = PartitionHeader::decode(partition, buf).unwrap();
+        return seek(partition, &header, database.keys(), start_time, |offset, timestamp| match read_frame(buf, offset) {
+            Frame::Record(payload, _) => timestamp_of(&header, database.keys(), offset as u64, payload) == Some(timestamp),
+            Frame::Damaged(_) => false
+        });
+    }
+
+    fn datalogs(database: &Database, start: u64) -> Vec<u8> {
+        database.range("levels", start, T + 60_000).unwrap().map(|record| record.unwrap().datalog[0]).collect()
+    }
+
+    #[test]
+    fn seeks_to_the_last_entry_before_the_start() {
+        let dir = TempDir::new("index_seek");
+        let (database, partition) = open(&dir);
+        assert_eq!(fs::metadata(index_path(&partition)).unwrap().len(), 5 * ENTRY_LEN as u64);
+
+        let buf = fs::read(&partition).unwrap();
+        let frame_len = (buf.len() - HEADER_LEN) / 10;
+        assert_eq!(seek_in(&database, &partition, &buf, T), HEADER_LEN);
+        assert_eq!(seek_in(&database, &partition, &buf, T + 5500), HEADER_LEN + 4 * frame_len);     // Entry of record 4
+        assert_eq!(seek_in(&database, &partition, &buf, T + 60_000), HEADER_LEN + 8 * frame_len);
+        assert_eq!(datalogs(&database, T + 5000), vec![5, 6, 7, 8, 9]);
+        assert_eq!(datalogs(&database, T + 5500), vec![6, 7, 8, 9]);
+    }
+
+    #[test]
+    fn stale_or_missing_index_reads_from_the_start() {
+        let dir = TempDir::new("index_stale");
+        let (database, partition) = open(&dir);
+        let buf = fs::read(&partition).unwrap();
+
+        // Offsets that no longer point at the indexed records, Ex: after the partition was rewritten
+        let mut index = fs::read(index_path(&partition)).unwrap();
+        for entry in index.chunks_exact_mut(ENTRY_LEN) {
+            let offset = u64::from_le_bytes(entry[8..16].try_into().unwrap()) + 1;
+            entry[8..16].copy_from_slice(&offset.to_le_bytes());
+        }
+        fs::write(index_path(&partition), &index).unwrap();
+        assert_eq!(seek_in(&database, &partition, &buf, T + 5500), HEADER_LEN);
+        assert_eq!(datalogs(&database, T + 5500), vec![6, 7, 8, 9]);
+
+        fs::remove_file(index_path(&partition)).unwrap();
+        assert_eq!(seek_in(&database, &partition, &buf, T + 5500), HEADER_LEN);
+        assert_eq!(datalogs(&database, T + 5500), vec![6, 7, 8, 9]);
+    }
+}
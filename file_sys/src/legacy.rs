@@ -0,0 +1,233 @@
+use std::path::Path;
+use chrono::prelude::*;
+use crc::crc32;
+use serde::Deserialize;
+use serde::de::IgnoredAny;
+use crate::database::{MpdRecordType, serialize_struct};
+use crate::error::Result;
+use crate::format::{Codec, PartitionHeader, MAGIC, encode_frame};
+use crate::partition::DATE_FORMAT;
+
+/***
+* Legacy formats:
+*
+* Purpose:
+* Partition files written before the header existed are bare concatenations of
+* msgpack values, Ex: the files checked in under data/levels. The first versions
+* of the crate appended a record [id, datalog, checksum] per insert, before that
+* insert appended the caller's bytes as they were. Such files are read as if
+* they were framed, see upgrade, and are rewritten in the current format before
+* an insert appends to them, see recompress.
+***/
+
+// Format version reported for files without a header
+pub(crate) const HEADERLESS_VERSION: u16 = 0;
+
+// Record appended by the first versions of the crate
+#[derive(Deserialize)]
+struct FirstRecord {
+    _id:        u32,
+    datalog:    Vec<u8>,    // msgpack array of integers
+    checksum:   u32,
+}
+
+/***
+* Function upgrade:
+*
+* Purpose:
+* Lays out a headerless partition file as the current format: a header, with
+* HEADERLESS_VERSION, followed by the framed records. None if 'bytes' isn't a
+* headerless partition, Ex: a partition with a header or a foreign file.
+***/
+pub(crate) fn upgrade(file: &Path, bytes: &[u8]) -> Result<Option<(PartitionHeader, Vec<u8>)>> {
+    if bytes.starts_with(MAGIC) {
+        return Ok(None);
+    }
+
+    // Every whole value must be a record of either layout, Ex: a text file reads as msgpack integers
+    let start = partition_start(file);
+    let values = split_values(bytes);
+    let records: Option<Vec<MpdRecordType>> = values.iter().enumerate()
+        .map(|(seq, value)| legacy_record(value, start, seq as u32))
+        .collect();
+    let records = match records {
+        Some(records) if !records.is_empty() => records,
+        _ => return Ok(None)        // Not a partition file
+    };
+    let header = PartitionHeader {
+        version:    HEADERLESS_VERSION,
+        ..PartitionHeader::new(Codec::MsgPack, 0, start)
+    };
+    let mut buf = header.encode().to_vec();
+    for record in records {
+        buf.extend_from_slice(&encode_frame(&serialize_struct(record)?));
+    }
+    let end: usize = values.iter().map(|value| value.len()).sum();
+
+    // Bytes that aren't a whole value, Ex: a torn write, are kept so readers count them as damaged
+    buf.extend_from_slice(&bytes[end..]);
+    return Ok(Some((header, buf)));
+}
+
+// Record stored in a headerless file, None if 'value' is neither layout. Both carry no time of their own and
+// are stamped with the start of their partition and their position in it
+fn legacy_record(value: &[u8], start: u64, seq: u32) -> Option<MpdRecordType> {
+    let (datalog, checksum) = match rmps::from_read_ref::<_, FirstRecord>(value) {
+        Ok(record) => (record.datalog, record.checksum),
+        Err(_) if is_array(value) => (value.to_vec(), crc32::checksum_ieee(value)),
+        Err(_) => return None
+    };
+    return Some(MpdRecordType {
+        timestamp:  start,
+        seq:        seq,
+        datalog:    datalog,
+        checksum:   checksum,
+        source:     None,
+    });
+}
+
+// Whether 'value' is a msgpack array, the caller's bytes were always one, Ex: a level reading
+fn is_array(value: &[u8]) -> bool {
+    return match value.first() {
+        Some(0x90..=0x9f) | Some(0xdc) | Some(0xdd) => true,    // fixarray, array 16 and array 32
+        _ => false
+    };
+}
+
+// Consecutive msgpack values from the start of 'bytes', up to the first one that can't be read
+fn split_values(bytes: &[u8]) -> Vec<&[u8]> {
+    let mut values = Vec::new();
+    let mut rest = bytes;
+    while !rest.is_empty() {
+        let mut reader = rest;
+        if IgnoredAny::deserialize(&mut rmps::Deserializer::new(&mut reader)).is_err() {
+            break;
+        }
+        let len = rest.len() - reader.len();
+        values.push(&rest[..len]);
+        rest = &rest[len..];
+    }
+    return values;
+}
+
+// Start of a partition from its path, Ex: data/levels/20200101/14, hours and minutes in UTC
+fn partition_start(file: &Path) -> u64 {
+    let day = file.parent()
+        .and_then(|day| day.file_name())
+        .and_then(|day| NaiveDate::parse_from_str(&day.to_string_lossy(), DATE_FORMAT).ok());
+    let day = match day {
+        Some(day) => day,
+        None => return 0
+    };
+
+    // Hourly and minute partitions, a name that is neither starts at midnight
+    let name = file.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+    let time = match name.len() {
+        2 => NaiveTime::parse_from_str(&format!("{}00", name), "%H%M").ok(),
+        4 => NaiveTime::parse_from_str(&name, "%H%M").ok(),
+        _ => None
+    };
+    let start = day.and_time(time.unwrap_or_else(|| NaiveTime::from_hms(0, 0, 0)));
+    return start.timestamp_millis().max(0) as u64;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::Path;
+    use crate::format::FORMAT_VERSION;
+    use crate::database::{Database, Entry, serialize_struct};
+    use crate::error::DbError;
+    use crate::registry::TableOptions;
+    use crate::seal::read_header;
+    use crate::testing::TempDir;
+    use super::*;
+
+    // Copy of the data tree checked in with the crate
+    fn checked_in(dir: &TempDir) -> Database {
+        let data = Path::new(env!("CARGO_MANIFEST_DIR")).join("data").join("levels");
+        for day in fs::read_dir(&data).unwrap() {
+            let day = day.unwrap().path();
+            let copy = dir.path().join("levels").join(day.file_name().unwrap());
+            fs::create_dir_all(&copy).unwrap();
+            for file in fs::read_dir(&day).unwrap() {
+                let file = file.unwrap().path();
+                fs::copy(&file, copy.join(file.file_name().unwrap())).unwrap();
+            }
+        }
+        return Database::new(dir.path()).unwrap();
+    }
+
+    #[test]
+    fn reads_checked_in_files() {
+        let dir = TempDir::new("legacy_checked_in");
+        let database = checked_in(&dir);
+        let records: Vec<_> = database.find_data("levels", "20200101").unwrap().map(Result::unwrap).collect();
+        assert!(!records.is_empty());
+        assert!(records.iter().all(|record| crc32::checksum_ieee(&record.datalog) == record.checksum));
+        assert_eq!(records[0].timestamp, 1_577_840_400_000);     // 2020-01-01 01:00 UTC
+    }
+
+    #[test]
+    fn repair_migrates_checked_in_files() {
+        let dir = TempDir::new("legacy_repair");
+        let database = checked_in(&dir);
+        let before: Vec<_> = database.find_data("levels", "20200526").unwrap().map(Result::unwrap).collect();
+
+        let report = database.verify().unwrap();
+        assert!(report.is_clean());
+        assert_eq!(report.files_migrated, 48);
+        let report = database.repair().unwrap();
+        assert_eq!((report.files_migrated, report.files_quarantined), (48, 0));
+        assert_eq!(database.verify().unwrap().files_migrated, 0);
+
+        let after: Vec<_> = database.find_data("levels", "20200526").unwrap().map(Result::unwrap).collect();
+        assert_eq!(before, after);
+    }
+
+    #[test]
+    fn insert_migrates_before_appending() {
+        let dir = TempDir::new("legacy_append");
+        let database = checked_in(&dir);
+        database.create_table("levels", TableOptions::default()).unwrap();
+        let count = database.find_data("levels", "20200101").unwrap().count();
+
+        let entry = Entry { table: "levels", data: b"new".to_vec(), timestamp: Some(1_577_840_400_001), source: None };
+        database.insert(entry).unwrap();
+        let partition = dir.path().join("levels").join("20200101").join("01");
+        assert!(fs::read(&partition).unwrap().starts_with(MAGIC));
+        assert_eq!(read_header(&partition).unwrap().version, FORMAT_VERSION);
+        let records: Vec<_> = database.find_data("levels", "20200101").unwrap().map(Result::unwrap).collect();
+        assert_eq!(records.len(), count + 1);
+        assert_eq!(records.iter().filter(|record| record.datalog == b"new").count(), 1);
+    }
+
+    #[test]
+    fn torn_tail_and_foreign_files() {
+        let dir = TempDir::new("legacy_torn");
+        let file = dir.path().join("20200101").join("14");
+        let record = serialize_struct((1u32, vec![1u8, 2, 3], crc32::checksum_ieee(&[1, 2, 3]))).unwrap();
+        let mut bytes = record.clone();
+        bytes.extend_from_slice(&[0x93, 0x01]);
+        let (header, buf) = upgrade(&file, &bytes).unwrap().unwrap();
+        assert_eq!((header.version, header.start), (HEADERLESS_VERSION, 1_577_887_200_000));
+        assert!(buf.ends_with(&[0x93, 0x01]));
+
+        let mut foreign = b"not msgpack".to_vec();
+        foreign.insert(0, 0xc1);    // Never used
+        assert!(upgrade(&file, &foreign).unwrap().is_none());
+    }
+
+    #[test]
+    fn text_files_are_not_partitions() {
+        let dir = TempDir::new("legacy_text");
+        let file = dir.path().join("20200101").join("14");
+        fs::create_dir_all(file.parent().unwrap()).unwrap();
+        fs::write(&file, b"notes about the levels\n").unwrap();
+        assert!(upgrade(&file, &fs::read(&file).unwrap()).unwrap().is_none());
+        match read_header(&file) {
+            Err(DbError::InvalidHeader { .. }) => (),
+            other => panic!("expected InvalidHeader, got {:?}", other.map(|header| header.version))
+        }
+    }
+}
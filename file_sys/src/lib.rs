@@ -0,0 +1,41 @@
+extern crate rmp_serde as rmps;
+extern crate chrono;
+extern crate toml;
+
+pub mod columnar;
+pub mod config;
+pub mod crypto;
+pub mod database;
+pub mod dedup;
+pub mod error;
+pub mod format;
+pub mod fsck;
+pub mod index;
+pub mod legacy;
+pub mod order;
+pub mod partition;
+pub mod query;
+pub mod raw_data;
+pub mod registry;
+pub mod seal;
+pub mod stream;
+pub mod summary;
+pub mod table;
+pub mod wal;
+pub mod writer;
+
+#[cfg(test)]
+mod testing;
+
+pub use config::{ChecksumPolicy, Config, DatabaseBuilder, FsyncPolicy, LogLevel};
+pub use crypto::{Key, KeyRing};
+pub use database::{Database, Entry, MpdRecordRef, MpdRecordType, DB};
+pub use error::{DbError, Result};
+pub use format::{Cipher, Codec, Compression, PartitionHeader};
+pub use fsck::FsckReport;
+pub use partition::{Granularity, Zone};
+pub use query::{Nearest, Range, ReadStats};
+pub use raw_data::RawData;
+pub use registry::{Duplicates, Manifest, TableOptions};
+pub use summary::{FieldSummary, PartitionSummary, Summarize};
+pub use table::{Schema, Table, TableRange};
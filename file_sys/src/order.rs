@@ -0,0 +1,240 @@
+use std::collections::hash_map::DefaultHasher;
+use std::fs;
+use std::fs::File;
+use std::hash::{Hash, Hasher};
+use std::io::prelude::*;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use crate::crypto::KeyRing;
+use crate::database::MpdRecordRef;
+use crate::error::Result;
+use crate::format::{Frame, PartitionHeader, HEADER_LEN, SYNC_MARKER, read_frame};
+use crate::index::with_record;
+
+pub(crate) static UNSORTED_EXTENSION: &str = "unsorted";
+const TAIL_WINDOW: u64 = 64 * 1024;
+
+/***
+* Record order:
+*
+* Purpose:
+* Records are appended in arrival order, so a partition receiving a late
+* record, Ex: from a device uploading readings it buffered offline, holds
+* records out of time order. Such a partition gets an empty sidecar, Ex:
+* data/levels/20200101/14.unsorted, created before the late record is written.
+* Queries read the records of a marked partition sorted by timestamp, sealing
+* sorts the file itself and removes the marker. A partition holding a record
+* replaced under Duplicates::Replace is marked as well: the replacement is
+* appended after it and queries and sealing keep the latest, see
+* drop_superseded.
+***/
+
+// Marker of a partition holding records out of time order
+pub(crate) fn unsorted_path(partition: &Path) -> PathBuf {
+    partition.with_extension(UNSORTED_EXTENSION)
+}
+
+// True unless a late record was appended to the partition since it was last sorted
+pub(crate) fn is_sorted(partition: &Path) -> bool {
+    !unsorted_path(partition).exists()
+}
+
+pub(crate) fn mark_unsorted(partition: &Path) -> Result<()> {
+    File::create(unsorted_path(partition))?;
+    return Ok(());
+}
+
+pub(crate) fn mark_sorted(partition: &Path) -> Result<()> {
+    let marker = unsorted_path(partition);
+    if marker.exists() {
+        fs::remove_file(marker)?;
+    }
+    return Ok(());
+}
+
+/***
+* Function last_record:
+*
+* Purpose:
+* Timestamp and sequence number of the last record of a partition that isn't
+* sealed, found from the end of the file, read back TAIL_WINDOW bytes at a
+* time. None if it is empty or its last frame is damaged.
+***/
+pub(crate) fn last_record(partition: &Path, header: &PartitionHeader, keys: &KeyRing) -> Result<Option<(u64, u32)>> {
+    let mut file = File::open(partition)?;
+    let mut start = file.metadata()?.len();     // Offset of the first byte of 'tail'
+    let mut tail = Vec::new();                  // Bytes from 'start' to the end of the file
+    let mut end = 0;                            // Sync markers are looked for in tail[..end]
+    loop {
+        // Latest sync marker before 'end', the last frame starts at one that reaches the end of the file
+        match tail[..end].windows(SYNC_MARKER.len()).rposition(|window| window == SYNC_MARKER) {
+            Some(pos) => {
+                if let Frame::Record(payload, next) = read_frame(&tail, pos) {
+                    if next == tail.len() {
+                        let offset = start + pos as u64;
+                        return Ok(with_record(header, keys, offset, payload, |entry| (entry.timestamp, entry.seq)));
+                    }
+                }
+                end = pos + SYNC_MARKER.len() - 1;
+            }
+            None if start > HEADER_LEN as u64 => {
+                // Read the window before the tail, a marker may span both
+                let from = start.saturating_sub(TAIL_WINDOW).max(HEADER_LEN as u64);
+                let mut window = vec![0u8; (start - from) as usize];
+                file.seek(SeekFrom::Start(from))?;
+                file.read_exact(&mut window)?;
+                end += window.len();
+                window.extend_from_slice(&tail);
+                tail = window;
+                start = from;
+            }
+            None => return Ok(None)
+        }
+    }
+}
+
+/***
+* Function sort_frames:
+*
+* Purpose:
+* Plaintext frames reordered by the timestamp of their records, records
+* sharing one keep their order. Damaged frames and frames that aren't records
+* go first, unchanged, so readers skip and count them as before. With
+* 'supersede', records superseded by a later one are dropped.
+***/
+pub(crate) fn sort_frames(frames: &[u8], supersede: bool) -> Vec<u8> {
+    let mut spans = Vec::new();
+    let mut pos = 0;
+    while pos < frames.len() {
+        let (key, next) = match read_frame(frames, pos) {
+            Frame::Record(payload, next) => {
+                let entry = rmps::from_read_ref::<_, MpdRecordRef>(payload).ok();
+                (entry.map(|entry| (entry.timestamp, source_hash(entry.source.as_deref()))), next)
+            }
+            Frame::Damaged(next) => (None, next),
+        };
+        spans.push((key.map(|(timestamp, _)| timestamp), key.map_or(0, |(_, source)| source), (pos, next)));
+        pos = next;
+    }
+    spans.sort_by_key(|&(timestamp, _, _)| timestamp);
+    if supersede {
+        spans = drop_superseded(spans);
+    }
+
+    let mut sorted = Vec::with_capacity(frames.len());
+    for (_, _, (start, end)) in spans {
+        sorted.extend_from_slice(&frames[start..end]);
+    }
+    return sorted;
+}
+
+// Hash of the source of a record, to tell records of different sources at the same timestamp apart
+pub(crate) fn source_hash(source: Option<&str>) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    source.hash(&mut hasher);
+    return hasher.finish();
+}
+
+/***
+* Function drop_superseded:
+*
+* Purpose:
+* Keeps the last of the records sharing a timestamp and source, Ex: the
+* replacement of a record under Duplicates::Replace, appended after it with a
+* higher seq. Records are given as their timestamp, the source_hash of their
+* source and a value, sorted by timestamp with records sharing one in write
+* order. Those without a timestamp, Ex: damaged frames, are kept.
+***/
+pub(crate) fn drop_superseded<T>(records: Vec<(Option<u64>, u64, T)>) -> Vec<(Option<u64>, u64, T)> {
+    let mut kept = Vec::with_capacity(records.len());
+    let mut sources = Vec::new();       // Of the records kept at the timestamp being visited
+    let mut curr = None;
+    for record in records.into_iter().rev() {
+        if record.0 != curr {
+            sources.clear();
+            curr = record.0;
+        }
+        if record.0.is_some() {
+            if sources.contains(&record.1) {
+                continue;
+            }
+            sources.push(record.1);
+        }
+        kept.push(record);
+    }
+    kept.reverse();
+    return kept;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::OpenOptions;
+    use std::io::Write;
+    use crate::database::Database;
+    use crate::format::Compression;
+    use crate::registry::TableOptions;
+    use crate::seal::read_header;
+    use crate::testing::{TempDir, entry};
+    use super::*;
+
+    const T: u64 = 1_577_887_200_000;      // 2020-01-01 14:00:00 UTC
+    const HOUR: u64 = 3_600_000;
+
+    fn partition(dir: &TempDir) -> PathBuf {
+        dir.path().join("levels").join("20200101").join("14")
+    }
+
+    fn timestamps(database: &Database) -> Vec<u64> {
+        database.range("levels", T, T + HOUR).unwrap().map(|record| record.unwrap().timestamp).collect()
+    }
+
+    #[test]
+    fn late_record_is_read_in_time_order() {
+        let dir = TempDir::new("order_late");
+        let database = Database::new(dir.path()).unwrap();
+        database.create_table("levels", TableOptions::default()).unwrap();
+        database.insert(entry("levels", T + 10, b"a")).unwrap();
+        database.insert(entry("levels", T + 20, b"b")).unwrap();
+        assert!(is_sorted(&partition(&dir)));
+
+        database.insert(entry("levels", T + 5, b"late")).unwrap();
+        assert!(!is_sorted(&partition(&dir)));
+        assert_eq!(timestamps(&database), vec![T + 5, T + 10, T + 20]);
+    }
+
+    #[test]
+    fn seal_sorts_the_partition() {
+        let dir = TempDir::new("order_seal");
+        let database = Database::new(dir.path()).unwrap();
+        let options = TableOptions { compression: Compression::Zstd, ..TableOptions::default() };
+        database.create_table("levels", options).unwrap();
+        database.insert(entry("levels", T + 10, b"a")).unwrap();
+        database.insert(entry("levels", T + 5, b"late")).unwrap();
+
+        // Moving on to the next hour seals the partition, then a record late by an hour reopens it
+        database.insert(entry("levels", T + HOUR, b"next hour")).unwrap();
+        database.insert(entry("levels", T + 1, b"later")).unwrap();
+        database.flush().unwrap();
+        assert!(!is_sorted(&partition(&dir)));
+        assert_eq!(database.seal("levels").unwrap(), 2);       // And the 15:00 one, which is in the past
+        assert!(is_sorted(&partition(&dir)));
+        assert_eq!(timestamps(&database), vec![T + 1, T + 5, T + 10, T + HOUR]);
+    }
+
+    #[test]
+    fn last_record_reads_back_past_a_window() {
+        let dir = TempDir::new("order_last");
+        let database = Database::new(dir.path()).unwrap();
+        database.create_table("levels", TableOptions::default()).unwrap();
+        database.insert(entry("levels", T, b"small")).unwrap();
+        database.insert(entry("levels", T + 5, &vec![7; 3 * TAIL_WINDOW as usize])).unwrap();
+        database.flush().unwrap();
+
+        let header = read_header(&partition(&dir)).unwrap();
+        assert_eq!(last_record(&partition(&dir), &header, database.keys()).unwrap(), Some((T + 5, 0)));
+
+        // A torn last frame leaves the last record unknown
+        OpenOptions::new().append(true).open(partition(&dir)).unwrap().write_all(&SYNC_MARKER[..]).unwrap();
+        assert_eq!(last_record(&partition(&dir), &header, database.keys()).unwrap(), None);
+    }
+}
@@ -0,0 +1,317 @@
+use std::convert::TryFrom;
+use std::path::{Path, PathBuf};
+use chrono::prelude::*;
+use chrono::Duration;
+use serde::{Serialize, Deserialize};
+
+pub(crate) static DATE_FORMAT: &str = "%Y%m%d";
+
+// Span of time stored in one partition file
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Granularity {
+    Day,        // data/<table>/<YYYYMMDD>/00
+    Hour,       // data/<table>/<YYYYMMDD>/<HH>
+    Minute,     // data/<table>/<YYYYMMDD>/<HHMM>
+}
+
+// Time zone partitions are named in, written as "utc", "local" or an offset such as "-06:00"
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum Zone {
+    Utc,
+    Local,
+    Offset(i32),    // Minutes east of UTC
+}
+
+impl TryFrom<String> for Zone {
+    type Error = String;
+
+    fn try_from(text: String) -> Result<Zone, String> {
+        match text.as_str() {
+            "utc" => return Ok(Zone::Utc),
+            "local" => return Ok(Zone::Local),
+            _ => {}
+        }
+
+        // Offset, Ex: +05:30
+        let invalid = || format!("invalid time zone {:?}, expected \"utc\", \"local\" or \"+HH:MM\"", text);
+        let sign = match text.get(..1) {
+            Some("+") => 1,
+            Some("-") => -1,
+            _ => return Err(invalid())
+        };
+        let mut parts = text[1..].splitn(2, ':');
+        let hours: i32 = parts.next().and_then(|hours| hours.parse().ok()).ok_or_else(invalid)?;
+        let minutes: i32 = parts.next().and_then(|minutes| minutes.parse().ok()).ok_or_else(invalid)?;
+        if hours > 23 || minutes > 59 {
+            return Err(invalid());
+        }
+        return Ok(Zone::Offset(sign * (hours * 60 + minutes)));
+    }
+}
+
+impl From<Zone> for String {
+    fn from(zone: Zone) -> String {
+        match zone {
+            Zone::Utc => "utc".to_string(),
+            Zone::Local => "local".to_string(),
+            Zone::Offset(minutes) => {
+                let sign = if minutes < 0 { '-' } else { '+' };
+                format!("{}{:02}:{:02}", sign, minutes.abs() / 60, minutes.abs() % 60)
+            }
+        }
+    }
+}
+
+/***
+* Struct Partitioning:
+*
+* Purpose:
+* Maps record timestamps to partition files. A partition starts at a UTC
+* millisecond and is named after that time in the configured zone, Ex: 14:37
+* with hourly partitions -> 14. Partitions follow each other in UTC, so the
+* hour a DST change repeats is a partition of its own, named with its UTC
+* offset, Ex: 20201101/01-0500 after 20201101/01 in America/Toronto.
+***/
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct Partitioning {
+    pub granularity:    Granularity,
+    pub zone:           Zone,
+}
+
+impl Partitioning {
+    // First millisecond of the partition holding a timestamp, None past the dates chrono represents
+    pub fn start_of(self, timestamp: u64) -> Option<u64> {
+        let datetime = self.to_naive(timestamp)?;
+        let date = datetime.date();
+        let start = match self.granularity {
+            Granularity::Day => date.and_hms(0, 0, 0),
+            Granularity::Hour => date.and_hms(datetime.hour(), 0, 0),
+            Granularity::Minute => date.and_hms(datetime.hour(), datetime.minute(), 0),
+        };
+
+        // At the offset of the timestamp, unless it changed since the start, Ex: a day partition after a DST change
+        let offset = datetime.timestamp_millis() - timestamp as i64;
+        let millis = start.timestamp_millis() - offset;
+        if millis < 0 {
+            return Some(0);     // Starts before 1970
+        }
+        if self.to_naive(millis as u64) == Some(start) {
+            return Some(millis as u64);
+        }
+        return self.to_timestamp(&start).or(Some(millis as u64));
+    }
+
+    // Start of the partition following 'start', None if it is the last one chrono represents
+    pub fn next(self, start: u64) -> Option<u64> {
+        let span = match self.granularity {
+            Granularity::Day => 25 * 3_600_000,     // Longest day, across a DST change
+            Granularity::Hour => 3_600_000,
+            Granularity::Minute => 60_000,
+        };
+        return self.start_of(start.checked_add(span)?);
+    }
+
+    // Start of the first partition of the day after the one 'start' is in, None past the dates chrono represents
+    pub fn next_day(self, start: u64) -> Option<u64> {
+        let midnight = self.to_naive(start)?.date().succ_opt()?.and_hms(0, 0, 0);
+        return self.start_of(self.to_timestamp(&midnight)?);
+    }
+
+    // First millisecond of the partition following 'start', u64::MAX if none follows
+    pub fn end_of(self, start: u64) -> u64 {
+        self.next(start).unwrap_or(u64::MAX)
+    }
+
+    // Day directory of a partition, Ex: data/levels/20200101
+    pub fn directory(self, table: &Path, start: u64) -> PathBuf {
+        return table.join(self.wall_clock(start).format(DATE_FORMAT).to_string());
+    }
+
+    // Partition file, Ex: data/levels/20200101/14
+    pub fn file(self, table: &Path, start: u64) -> PathBuf {
+        let datetime = self.wall_clock(start);
+        let format = match self.granularity {
+            Granularity::Day | Granularity::Hour => "%H",
+            Granularity::Minute => "%H%M",
+        };
+        let mut name = datetime.format(format).to_string();
+
+        // The second of two partitions with the same wall clock time, Ex: the hour repeated when DST ends -> 01-0500
+        if self.zone == Zone::Local && self.repeats(start) {
+            name.push_str(&Local.timestamp_millis(start as i64).format("%z").to_string());
+        }
+        return self.directory(table, start).join(name);
+    }
+
+    // True if the wall clock already showed the time 'start' is at, under the offset before a change that set
+    // it back by up to 3 hours
+    fn repeats(self, start: u64) -> bool {
+        let offset = |timestamp: u64| {
+            self.to_naive(timestamp).map(|datetime| datetime.timestamp_millis() - timestamp as i64)
+        };
+        let (before, after) = match (start.checked_sub(3 * 3_600_000).and_then(offset), offset(start)) {
+            (Some(before), Some(after)) if before > after => (before, after),
+            _ => return false
+        };
+        let earlier = start - (before - after) as u64;
+        return offset(earlier) == Some(before) && self.to_naive(earlier) == self.to_naive(start);
+    }
+
+    // Wall clock time a partition starts at, 'start' is always one chrono represents, see start_of
+    fn wall_clock(self, start: u64) -> NaiveDateTime {
+        self.to_naive(start).unwrap_or_else(|| NaiveDate::from_ymd(1970, 1, 1).and_hms(0, 0, 0))
+    }
+
+    // First and last millisecond of a day
+    pub fn day_bounds(self, day: NaiveDate) -> Option<(u64, u64)> {
+        let start = self.to_timestamp(&day.and_hms(0, 0, 0))?;
+        let end = self.to_timestamp(&day.succ_opt()?.and_hms(0, 0, 0))?;
+        return Some((start, end - 1));
+    }
+
+    // Wall clock time of a timestamp in the partition zone, None past the dates chrono represents
+    pub fn to_naive(self, timestamp: u64) -> Option<NaiveDateTime> {
+        let utc = get_datetime(timestamp)?;
+        match self.zone {
+            Zone::Utc => Some(utc.naive_utc()),
+            Zone::Local => Some(utc.with_timezone(&Local).naive_local()),
+            Zone::Offset(minutes) => utc.naive_utc().checked_add_signed(Duration::minutes(i64::from(minutes))),
+        }
+    }
+
+    // Timestamp of a wall clock time in the partition zone, the earliest if it is ambiguous, None before 1970
+    pub fn to_timestamp(self, datetime: &NaiveDateTime) -> Option<u64> {
+        let millis = match self.zone {
+            Zone::Utc => datetime.timestamp_millis(),
+            Zone::Local => match Local.from_local_datetime(datetime).earliest() {
+                Some(local) => local.timestamp_millis(),
+                None => Local.from_utc_datetime(datetime).timestamp_millis()   // Inside a DST gap
+            },
+            Zone::Offset(minutes) => (*datetime - Duration::minutes(i64::from(minutes))).timestamp_millis(),
+        };
+        return u64::try_from(millis).ok();
+    }
+}
+
+// Convert millisecond timestamp to datetime, None past the dates chrono represents
+pub(crate) fn get_datetime(timestamp: u64) -> Option<DateTime<Utc>> {
+    let seconds = i64::try_from(timestamp / 1000).ok()?;
+    let nanoseconds = (timestamp % 1000) as u32 * 1_000_000;
+    let naive_datetime = NaiveDateTime::from_timestamp_opt(seconds, nanoseconds)?;
+    let utc_datetime = DateTime::<Utc>::from_utc(naive_datetime, Utc);
+    return Some(utc_datetime);
+}
+
+// Current time in milliseconds since epoch
+pub(crate) fn get_timestamp() -> u64 {
+    let local: DateTime<Utc> = Utc::now();
+    return u64::try_from(local.timestamp_millis()).unwrap_or(0);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const T: u64 = 1_577_890_020_000;      // 2020-01-01 14:47:00 UTC
+
+    #[test]
+    fn files_follow_the_granularity() {
+        let table = Path::new("levels");
+        let file = |granularity: Granularity| -> PathBuf {
+            let partitioning = Partitioning { granularity: granularity, zone: Zone::Utc };
+            partitioning.file(table, partitioning.start_of(T).unwrap())
+        };
+        assert_eq!(file(Granularity::Day), table.join("20200101").join("00"));
+        assert_eq!(file(Granularity::Hour), table.join("20200101").join("14"));
+        assert_eq!(file(Granularity::Minute), table.join("20200101").join("1447"));
+    }
+
+    #[test]
+    fn times_past_chrono_have_no_partition() {
+        let partitioning = Partitioning { granularity: Granularity::Hour, zone: Zone::Utc };
+        assert_eq!(get_datetime(u64::MAX), None);
+        assert_eq!(partitioning.start_of(u64::MAX), None);
+
+        // The last partition chrono represents has none after it
+        let last = NaiveDate::from_ymd(262_143, 12, 31).and_hms(23, 0, 0).timestamp_millis() as u64;
+        let start = partitioning.start_of(last + 1).unwrap();
+        assert_eq!(start, last);
+        assert_eq!(partitioning.next(start), None);
+        assert_eq!(partitioning.end_of(start), u64::MAX);
+
+        // Nor does a zone east of UTC past it
+        let east = Partitioning { zone: Zone::Offset(60), ..partitioning };
+        assert_eq!(east.start_of(last), None);
+    }
+
+    #[test]
+    fn offset_zone_shifts_partitions_and_days() {
+        // 10 hours east of UTC, 14:47 UTC is 00:47 on the next day
+        let partitioning = Partitioning { granularity: Granularity::Hour, zone: Zone::Offset(600) };
+        let start = partitioning.start_of(T).unwrap();
+        assert_eq!(partitioning.file(Path::new("levels"), start), Path::new("levels").join("20200102").join("00"));
+        assert_eq!(start, T - 47 * 60_000);
+
+        let (start_time, end_time) = partitioning.day_bounds(NaiveDate::from_ymd(2020, 1, 2)).unwrap();
+        assert_eq!(start_time, T - 47 * 60_000);
+        assert_eq!(end_time, start_time + 86_400_000 - 1);
+        assert_eq!(partitioning.day_bounds(NaiveDate::from_ymd(1970, 1, 1)), None);
+    }
+
+    #[test]
+    fn hour_repeated_by_dst_is_a_partition_of_its_own() {
+        // Eastern time without tzdata, clocks go back from 02:00 EDT to 01:00 EST on 2020-11-01. No other test
+        // depends on the local zone.
+        std::env::set_var("TZ", "EST5EDT,M3.2.0,M11.1.0");
+        let table = Path::new("levels");
+        let day = table.join("20201101");
+        let first = 1_604_206_800_000;      // 2020-11-01 05:00 UTC, 01:00 EDT
+        let hour = 3_600_000;
+        for granularity in [Granularity::Hour, Granularity::Minute] {
+            let partitioning = Partitioning { granularity: granularity, zone: Zone::Local };
+            let repeated = partitioning.start_of(first + hour + 30 * 60_000).unwrap();
+            assert_eq!(partitioning.start_of(first + 30 * 60_000).unwrap(), match granularity {
+                Granularity::Minute => first + 30 * 60_000,
+                _ => first
+            });
+            assert_eq!(repeated, match granularity {
+                Granularity::Minute => first + hour + 30 * 60_000,
+                _ => first + hour
+            });
+        }
+
+        // Each hour is visited once, in order, the second 01 under its own name
+        let partitioning = Partitioning { granularity: Granularity::Hour, zone: Zone::Local };
+        let mut start = partitioning.start_of(first - hour).unwrap();
+        let mut files = Vec::new();
+        for _ in 0..4 {
+            files.push(partitioning.file(table, start));
+            assert_eq!(partitioning.end_of(start), start + hour);
+            start = partitioning.next(start).unwrap();
+        }
+        assert_eq!(files, vec![day.join("00"), day.join("01"), day.join("01-0500"), day.join("02")]);
+
+        // The day is 25 hours long
+        let midnight = partitioning.start_of(first - hour).unwrap();
+        let (start_time, end_time) = partitioning.day_bounds(NaiveDate::from_ymd(2020, 11, 1)).unwrap();
+        assert_eq!((start_time, end_time), (midnight, midnight + 25 * hour - 1));
+        let days = Partitioning { granularity: Granularity::Day, ..partitioning };
+        assert_eq!(days.start_of(first + 2 * hour), Some(midnight));
+        assert_eq!(days.next(midnight), Some(midnight + 25 * hour));
+        assert_eq!(partitioning.next_day(first + hour), Some(midnight + 25 * hour));
+    }
+
+    #[test]
+    fn zones_parse_and_print() {
+        for (text, zone) in [("utc", Zone::Utc), ("local", Zone::Local), ("+05:30", Zone::Offset(330)),
+                             ("-06:00", Zone::Offset(-360))] {
+            assert_eq!(Zone::try_from(text.to_string()), Ok(zone));
+            assert_eq!(String::from(zone), text);
+        }
+        for text in ["UTC", "05:30", "+5", "+24:00", "-01:60", "+aa:00"] {
+            assert!(Zone::try_from(text.to_string()).is_err(), "{}", text);
+        }
+    }
+}
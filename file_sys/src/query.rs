@@ -0,0 +1,611 @@
+use std::borrow::Cow;
+use std::fs;
+use std::fs::OpenOptions;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use chrono::prelude::*;
+use crc::crc32;
+use crate::config::{ChecksumPolicy, Config};
+use crate::crypto::{KeyRing, decrypt};
+use crate::database::{MpdRecordRef, MpdRecordType};
+use crate::error::{DbError, Result};
+use crate::format::{Cipher, Codec, Frame, PartitionHeader, HEADER_LEN};
+use crate::index::{seek, timestamp_of, with_record};
+use crate::order::{drop_superseded, is_sorted, source_hash};
+use crate::partition::{Partitioning, DATE_FORMAT};
+use crate::seal::PartitionData;
+use crate::stream::open_partition;
+
+pub(crate) static QUARANTINE_DIR: &str = "quarantine";
+
+// Direction of a nearest record lookup
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Nearest {
+    AtOrBefore,     // Latest record at or before the timestamp
+    AtOrAfter,      // Earliest record at or after the timestamp
+}
+
+// Records read and lost to corruption by a query
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ReadStats {
+    pub records:                u64,    // Records returned
+    pub records_lost:           u64,    // Damaged frames skipped
+    pub bytes_lost:             u64,    // Bytes skipped while resynchronizing
+    pub checksum_mismatches:    u64,    // Records whose 'datalog' failed its CRC-32
+    pub records_quarantined:    u64,    // Mismatched records copied to quarantine
+    pub auth_failures:          u64,    // Encrypted records failing authentication, handled as mismatches
+    pub values_undecodable:     u64,    // Records whose value couldn't be decoded, Ex: by a Table of another type
+    pub partitions_skipped:     u64,    // Partitions left out for a foreign or unreadable header
+}
+
+/***
+* Struct Range:
+*
+* Purpose:
+* Iterator over the records of a table between two timestamps. Partitions are
+* visited in time order and only one partition file is mapped or held in
+* memory at a time. The records of a partition holding late records are
+* visited sorted by timestamp, without those a later record supersedes when
+* the table replaces duplicates. A record or partition that can't be read is
+* reported as an error and counted in ReadStats, the next call moves on past
+* it as fsck does. Only I/O errors and ChecksumPolicy::Fail end the range.
+***/
+pub struct Range {
+    table:          PathBuf,            // Table directory, Ex: data/levels
+    partitioning:   Partitioning,
+    table_id:       u32,                // Expected in partition headers, 0 skips the check
+    checksums:      ChecksumPolicy,     // Applied to records failing their checksum or authentication
+    cipher:         Cipher,             // Configured, plaintext partitions are refused unless it is Cipher::None
+    keys:           KeyRing,
+    read_buffer:    usize,              // Bytes of a streamed partition held at once
+    curr_partition: Option<u64>,        // Start of the next partition to read, None past the last one
+    end_partition:  u64,                // Start of the last partition to read
+    start_time:     u64,                // Milliseconds since epoch
+    end_time:       u64,
+    curr_file:      PathBuf,            // Partition file in 'data'
+    curr_header:    PartitionHeader,
+    data:           PartitionData,      // Partition file 'curr_file', mapped, unsealed or streamed
+    pos:            usize,              // Offset of the next frame in 'data'
+    order:          Option<Vec<usize>>, // Offsets of the frames left in an unsorted partition, last first
+    supersede:      bool,               // Leave out records superseded by a later one, see Duplicates::Replace
+    stats:          ReadStats,
+    done:           bool,               // True once exhausted or stopped by an error
+}
+
+impl Range {
+    pub(crate) fn new(table: PathBuf, partitioning: Partitioning, table_id: u32, config: &Config, keys: KeyRing,
+                      start_time: u64, end_time: u64) -> Result<Range> {
+        let bounds = (partitioning.start_of(start_time), partitioning.start_of(end_time));
+        let (curr_partition, end_partition) = match bounds {
+            (Some(curr_partition), Some(end_partition)) => (curr_partition, end_partition),
+            _ => return Err(DbError::InvalidRange { start: start_time, end: end_time })
+        };
+        return Ok(Range {
+            table:          table,
+            partitioning:   partitioning,
+            table_id:       table_id,
+            checksums:      config.checksum_policy,
+            cipher:         config.cipher,
+            keys:           keys,
+            read_buffer:    config.read_buffer,
+            curr_partition: Some(curr_partition),
+            end_partition:  end_partition,
+            start_time:     start_time,
+            end_time:       end_time,
+            curr_file:      PathBuf::new(),
+            curr_header:    PartitionHeader::new(Codec::MsgPack, table_id, 0),
+            data:           PartitionData::Owned(Vec::new()),
+            pos:            0,
+            order:          None,
+            supersede:      false,
+            stats:          ReadStats::default(),
+            done:           false,
+        });
+    }
+
+    // Leave out the records a later one of the same source and timestamp supersedes, for Duplicates::Replace
+    pub(crate) fn superseding(mut self, supersede: bool) -> Range {
+        self.supersede = supersede;
+        self
+    }
+
+    // Records read and lost so far
+    pub fn stats(&self) -> ReadStats {
+        self.stats
+    }
+
+    // Load the next existing partition into 'data', false once the range is exhausted
+    fn next_partition(&mut self) -> Result<bool> {
+        while let Some(curr_partition) = self.curr_partition.filter(|curr| *curr <= self.end_partition) {
+            let curr_directory = self.partitioning.directory(&self.table, curr_partition);
+            let curr_file = self.partitioning.file(&self.table, curr_partition);
+
+            /*** Check if Directory doesn't exist ***/
+            if !curr_directory.exists() {
+                // Skip to midnight of the next day
+                self.curr_partition = self.partitioning.next_day(curr_partition);
+                continue;
+            }
+
+            // Move to the next partition for the next call
+            self.curr_partition = self.partitioning.next(curr_partition);
+
+            /*** Check if File doesn't exist ***/
+            // Or is empty, Ex: created before its header by an older version, see fsck
+            if fs::metadata(&curr_file).map_or(true, |metadata| metadata.len() == 0) {
+                continue;
+            }
+
+            // A partition that isn't readable is reported and skipped, the next call carries on after it
+            return match self.open(curr_file) {
+                Ok(()) => Ok(true),
+                Err(DbError::Io(err)) => Err(DbError::Io(err)),
+                Err(err) => {
+                    self.stats.partitions_skipped += 1;
+                    Err(err)
+                }
+            };
+        }
+
+        return Ok(false);
+    }
+
+    // Make 'curr_file' the partition records are read from
+    fn open(&mut self, curr_file: PathBuf) -> Result<()> {
+        /*** Open Unsorted File ***/
+        if !is_sorted(&curr_file) {
+            // Streamed like a sorted one when large, only the offsets of its records are held to sort them
+            let (header, mut data) = open_partition(&curr_file, &self.keys, self.read_buffer)?;
+            header.check_table(&curr_file, self.table_id)?;
+            header.check_cipher(&curr_file, self.cipher)?;
+            self.order = Some(self.sort(&header, &mut data)?);
+            self.data = data;
+            self.curr_file = curr_file;
+            self.curr_header = header;
+            return Ok(());
+        }
+
+        /*** Open File ***/
+        let (header, mut data) = open_partition(&curr_file, &self.keys, self.read_buffer)?;
+
+        /*** Check Header ***/
+        header.check_table(&curr_file, self.table_id)?;
+        header.check_cipher(&curr_file, self.cipher)?;
+        self.pos = HEADER_LEN;
+        self.order = None;
+        if header.start < self.start_time {
+            // Range starts inside this partition, skip ahead using its index
+            let keys = &self.keys;
+            self.pos = seek(&curr_file, &header, keys, self.start_time, |offset, timestamp| match data.frame(offset) {
+                Ok(Frame::Record(payload, _)) => timestamp_of(&header, keys, offset as u64, payload) == Some(timestamp),
+                _ => false
+            });
+            if data.passed(self.pos) {
+                // The index was stale and the stream read past the start, open it again
+                data = open_partition(&curr_file, &self.keys, self.read_buffer)?.1;
+            }
+        }
+        self.data = data;
+        self.curr_file = curr_file;
+        self.curr_header = header;
+        return Ok(());
+    }
+
+    // Offsets of the frames of a partition to visit, sorted by the timestamp of their records and last first.
+    // Frames that aren't readable records are kept, first, so they are counted as in a sorted partition.
+    fn sort(&self, header: &PartitionHeader, data: &mut PartitionData) -> Result<Vec<usize>> {
+        let mut frames = Vec::new();
+        let mut pos = HEADER_LEN;
+        while !data.at_end(pos)? {
+            let (key, next) = match data.frame(pos)? {
+                Frame::Record(payload, next) => {
+                    let key = with_record(header, &self.keys, pos as u64, payload, |entry| match self.supersede {
+                        true => (entry.timestamp, source_hash(entry.source.as_deref())),
+                        false => (entry.timestamp, 0)
+                    });
+                    (key, next)
+                }
+                Frame::Damaged(next) => (None, next),
+            };
+            match key {
+                Some((timestamp, _)) if timestamp < self.start_time || timestamp > self.end_time => {}
+                _ => frames.push((key.map(|(timestamp, _)| timestamp), key.map_or(0, |(_, source)| source), pos))
+            }
+            pos = next;
+        }
+        frames.sort_by_key(|&(timestamp, _, _)| timestamp);
+        if self.supersede {
+            frames = drop_superseded(frames);
+        }
+        return Ok(frames.into_iter().rev().map(|(_, _, offset)| offset).collect());
+    }
+
+    // Offset of the next frame to read from the partition, None once it is exhausted
+    fn next_offset(&mut self) -> Result<Option<usize>> {
+        if let Some(order) = &mut self.order {
+            return Ok(order.pop());
+        }
+        match self.data.at_end(self.pos)? {
+            true => Ok(None),
+            false => Ok(Some(self.pos))
+        }
+    }
+
+    /***
+    * Function next_with:
+    *
+    * Purpose:
+    * Hands the next record of the range to 'decode' in place, its 'datalog'
+    * borrowed from the partition instead of copied, Ex: to deserialize a value
+    * straight out of a mapped file. None once the range is exhausted.
+    ***/
+    pub fn next_with<R, F>(&mut self, decode: F) -> Result<Option<R>> where F: FnOnce(MpdRecordRef) -> Result<R> {
+        if self.done {
+            return Ok(None);
+        }
+
+        let record = self.next_record(decode);
+        match record {
+            Ok(Some(_)) => {}
+            Ok(None) | Err(DbError::Io(_)) => self.done = true,     // Stop after reporting the error
+            Err(_) => {}        // Past the record or partition, unless rejected under ChecksumPolicy::Fail
+        }
+        return record;
+    }
+
+    // Decode the next record of the range, see next_with
+    fn next_record<R, F>(&mut self, decode: F) -> Result<Option<R>> where F: FnOnce(MpdRecordRef) -> Result<R> {
+        loop {
+            // Skip to the next partition with records left
+            let offset = match self.next_offset()? {
+                Some(offset) => offset,
+                None if self.next_partition()? => continue,
+                None => return Ok(None)
+            };
+            let (payload, next) = match self.data.frame(offset)? {
+                Frame::Record(payload, next) => (payload, next),
+                Frame::Damaged(next) => {
+                    // Resynchronize on the next frame
+                    self.pos = next;
+                    self.stats.records_lost += 1;
+                    self.stats.bytes_lost += (next - offset) as u64;
+                    continue;
+                }
+            };
+            self.pos = next;
+
+            // Check if payload is authentic
+            let payload = match self.curr_header.frames_encrypted() {
+                false => Cow::Borrowed(payload),
+                true => match decrypt(self.curr_header.cipher, &self.keys, &self.curr_header.aad(offset as u64), payload)? {
+                    Some(payload) => Cow::Owned(payload),
+                    None => {
+                        self.stats.auth_failures += 1;
+                        self.reject(offset, DbError::Decryption { file: self.curr_file.clone(), offset: offset as u64 })?;
+                        continue;
+                    }
+                }
+            };
+            let entry: MpdRecordRef = match rmps::from_read_ref(&payload[..]) {
+                Ok(entry) => entry,
+                Err(_) => {
+                    // Frame is intact but its payload isn't a record
+                    self.stats.records_lost += 1;
+                    self.stats.bytes_lost += (next - offset) as u64;
+                    continue;
+                }
+            };
+
+            // Check if entry is older than start_timestamp
+            if entry.timestamp < self.start_time {
+                continue;
+            }
+
+            // Check if entry is newer than end_timestamp
+            if entry.timestamp > self.end_time {
+                return Ok(None);
+            }
+
+            // Check if datalog is intact
+            if crc32::checksum_ieee(&entry.datalog) != entry.checksum {
+                self.stats.checksum_mismatches += 1;
+                self.reject(offset, DbError::ChecksumMismatch { file: self.curr_file.clone(), offset: offset as u64 })?;
+                continue;
+            }
+
+            return match decode(entry) {
+                Ok(value) => {
+                    self.stats.records += 1;
+                    Ok(Some(value))
+                }
+                Err(err) => {
+                    self.stats.values_undecodable += 1;
+                    Err(err)
+                }
+            };
+        }
+    }
+
+    // Apply the checksum policy to the record at 'offset', 'err' ends the query under ChecksumPolicy::Fail
+    fn reject(&mut self, offset: usize, err: DbError) -> Result<()> {
+        match self.checksums {
+            ChecksumPolicy::Fail => {
+                self.done = true;
+                Err(err)
+            }
+            ChecksumPolicy::Skip => Ok(()),
+            ChecksumPolicy::Quarantine => self.quarantine(offset)
+        }
+    }
+
+    // Copy the frame at 'offset' to data/<table>/quarantine/<YYYYMMDD>-<file>
+    fn quarantine(&mut self, offset: usize) -> Result<()> {
+        let path = quarantine_path(&self.table, &self.curr_file);
+        fs::create_dir_all(self.table.join(QUARANTINE_DIR))?;
+
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        file.write_all(self.data.slice(offset, self.pos))?;
+        self.stats.records_quarantined += 1;
+        return Ok(());
+    }
+}
+
+impl Iterator for Range {
+    type Item = Result<MpdRecordType>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_with(|entry| Ok(entry.into_record())).transpose()
+    }
+}
+
+/***
+* Function partition_days:
+*
+* Purpose:
+* Lists the days with a partition directory in a table, oldest first
+***/
+pub(crate) fn partition_days(table: &Path) -> Result<Vec<NaiveDate>> {
+    let mut days = Vec::new();
+    if !table.exists() {
+        return Ok(days);
+    }
+
+    for entry in fs::read_dir(table)? {
+        let entry = entry?;
+        if !entry.path().is_dir() {
+            continue;
+        }
+        if let Ok(day) = NaiveDate::parse_from_str(&entry.file_name().to_string_lossy(), DATE_FORMAT) {
+            days.push(day);
+        }
+    }
+
+    days.sort();
+    return Ok(days);
+}
+
+// Quarantine copy of a partition file, Ex: data/levels/quarantine/20200101-14
+pub(crate) fn quarantine_path(table: &Path, partition: &Path) -> PathBuf {
+    let mut name = String::new();
+    if let Some(day) = partition.parent().and_then(|day| day.file_name()) {
+        name.push_str(&day.to_string_lossy());
+        name.push('-');
+    }
+    if let Some(file) = partition.file_name() {
+        name.push_str(&file.to_string_lossy());
+    }
+    return table.join(QUARANTINE_DIR).join(name);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use crate::database::{Database, Entry};
+    use crate::error::DbError;
+    use crate::format::encode_frame;
+    use crate::registry::TableOptions;
+    use crate::testing::TempDir;
+    use super::*;
+
+    const T: u64 = 1_577_917_800_000;      // 2020-01-01 22:30:00 UTC
+    const HOUR: u64 = 3_600_000;
+
+    // Insert records stamped with their ids, each holding its id
+    fn write(dir: &TempDir, ids: &[u64]) {
+        let database = Database::new(dir.path()).unwrap();
+        database.create_table("levels", TableOptions::default()).unwrap();
+        for &id in ids {
+            let entry = Entry { table: "levels", data: id.to_le_bytes().to_vec(), timestamp: Some(id), source: None };
+            database.insert(entry).unwrap();
+        }
+    }
+
+    fn ids(database: &Database, start_time: u64, end_time: u64) -> Vec<u64> {
+        database.range("levels", start_time, end_time).unwrap().map(|record| record.unwrap().timestamp).collect()
+    }
+
+    #[test]
+    fn crosses_days_and_skips_missing_ones() {
+        let dir = TempDir::new("range_days");
+        // 22:30 and 23:30 on the 1st, 00:30 on the 2nd, nothing on the 3rd, 00:30 on the 4th
+        write(&dir, &[T, T + HOUR, T + 2 * HOUR, T + 50 * HOUR]);
+        let database = Database::new(dir.path()).unwrap();
+        assert_eq!(ids(&database, T, T + 50 * HOUR), vec![T, T + HOUR, T + 2 * HOUR, T + 50 * HOUR]);
+        assert_eq!(ids(&database, T + 1, T + 2 * HOUR), vec![T + HOUR, T + 2 * HOUR]);
+        assert_eq!(ids(&database, T + 3 * HOUR, T + 49 * HOUR), Vec::<u64>::new());
+    }
+
+    #[test]
+    fn starts_and_ends_inside_a_partition() {
+        let dir = TempDir::new("range_inside");
+        write(&dir, &[T, T + 10, T + 20, T + 30]);
+        let database = Database::new(dir.path()).unwrap();
+        assert_eq!(ids(&database, T + 10, T + 20), vec![T + 10, T + 20]);
+        assert_eq!(ids(&database, T + 20, T + 20), vec![T + 20]);
+        assert_eq!(ids(&database, T + 31, T + HOUR), Vec::<u64>::new());
+    }
+
+    #[test]
+    fn reads_partitions_lazily() {
+        let dir = TempDir::new("range_lazy");
+        write(&dir, &[T, T + HOUR, T + 2 * HOUR]);
+        let database = Database::new(dir.path()).unwrap();
+        let mut records = database.range("levels", T, T + 2 * HOUR).unwrap();
+        assert_eq!(records.next().unwrap().unwrap().timestamp, T);
+
+        // The next partition is only opened once the first is exhausted, and skipped once reported
+        let next = dir.path().join("levels").join("20200101").join("23");
+        fs::write(&next, [0xc1]).unwrap();
+        assert!(matches!(records.next(), Some(Err(DbError::InvalidHeader { .. }))));
+        assert_eq!(records.next().unwrap().unwrap().timestamp, T + 2 * HOUR);
+        assert!(records.next().is_none());
+        assert_eq!(records.stats(), ReadStats { records: 2, partitions_skipped: 1, ..ReadStats::default() });
+    }
+
+    #[test]
+    fn find_data_reads_one_day() {
+        let dir = TempDir::new("find_data");
+        write(&dir, &[T, T + HOUR, T + 2 * HOUR]);
+        let database = Database::new(dir.path()).unwrap();
+        let day = |date: &str| -> Vec<u64> {
+            database.find_data("levels", date).unwrap().map(|record| record.unwrap().timestamp).collect()
+        };
+        assert_eq!(day("20200101"), vec![T, T + HOUR]);
+        assert_eq!(day("20200102"), vec![T + 2 * HOUR]);
+        assert_eq!(day("20200103"), Vec::<u64>::new());
+        assert!(matches!(database.find_data("levels", "2020-01-01"), Err(DbError::InvalidDate(_))));
+    }
+
+    #[test]
+    fn find_nearest_in_both_directions() {
+        let dir = TempDir::new("find_nearest");
+        // The 2nd has a directory without records, the 3rd has none
+        write(&dir, &[T, T + 10, T + 50 * HOUR]);
+        fs::create_dir_all(dir.path().join("levels").join("20200102")).unwrap();
+        let database = Database::new(dir.path()).unwrap();
+        let nearest = |timestamp: u64, nearest: Nearest| -> Option<u64> {
+            database.find_nearest("levels", timestamp, nearest).unwrap().map(|record| record.timestamp)
+        };
+        assert_eq!(nearest(T + 11, Nearest::AtOrAfter), Some(T + 50 * HOUR));
+        assert_eq!(nearest(T + 49 * HOUR, Nearest::AtOrBefore), Some(T + 10));
+        assert_eq!(nearest(T + 5, Nearest::AtOrBefore), Some(T));
+        assert_eq!(nearest(T + 5, Nearest::AtOrAfter), Some(T + 10));
+        assert_eq!(nearest(T + 10, Nearest::AtOrBefore), Some(T + 10));
+        assert_eq!(nearest(T + 10, Nearest::AtOrAfter), Some(T + 10));
+        assert_eq!(nearest(T - 1, Nearest::AtOrBefore), None);
+        assert_eq!(nearest(T + 50 * HOUR + 1, Nearest::AtOrAfter), None);
+        assert_eq!(database.find_nearest("missing", T, Nearest::AtOrBefore).unwrap(), None);
+    }
+
+    #[test]
+    fn resyncs_past_damaged_frames() {
+        let dir = TempDir::new("range_resync");
+        write(&dir, &[T, T + 10, T + 20]);
+        let partition = dir.path().join("levels").join("20200101").join("22");
+        let mut bytes = fs::read(&partition).unwrap();
+        let frame_len = (bytes.len() - HEADER_LEN) / 3;
+
+        // Flip a payload byte of the second frame and tear the tail of a fourth
+        bytes[HEADER_LEN + frame_len + frame_len - 1] ^= 0xff;
+        bytes.extend_from_slice(&encode_frame(b"torn")[..6]);
+        fs::write(&partition, &bytes).unwrap();
+
+        // Opened without recovery, which would truncate the torn tail
+        let database = Database::from_config(Config { source: dir.path().to_path_buf(), ..Config::default() });
+        let mut records = database.range("levels", T, T + HOUR).unwrap();
+        assert_eq!(records.by_ref().map(|record| record.unwrap().timestamp).collect::<Vec<_>>(), vec![T, T + 20]);
+        assert_eq!(records.stats(), ReadStats {
+            records:        2,
+            records_lost:   2,
+            bytes_lost:     frame_len as u64 + 6,
+            ..ReadStats::default()
+        });
+    }
+
+    // Database with the records of 'write' at T and T + 20 around one at T + 10 whose datalog fails its checksum
+    fn mismatched(dir: &TempDir, policy: ChecksumPolicy) -> Database {
+        write(dir, &[T, T + 20]);
+        let partition = dir.path().join("levels").join("20200101").join("22");
+        let mut bytes = fs::read(&partition).unwrap();
+        let frame_len = (bytes.len() - HEADER_LEN) / 2;
+        let record = MpdRecordType { timestamp: T + 10, seq: 0, datalog: vec![1, 2], checksum: 0, source: None };
+        let frame = encode_frame(&rmps::to_vec(&record).unwrap());
+        bytes.splice(HEADER_LEN + frame_len..HEADER_LEN + frame_len, frame);
+        fs::write(&partition, &bytes).unwrap();
+        return Database::builder().source(dir.path()).checksum_policy(policy).build().unwrap();
+    }
+
+    #[test]
+    fn checksum_fail_ends_the_query() {
+        let dir = TempDir::new("checksum_fail");
+        let database = mismatched(&dir, ChecksumPolicy::Fail);
+        let mut records = database.range("levels", T, T + HOUR).unwrap();
+        assert_eq!(records.next().unwrap().unwrap().timestamp, T);
+        match records.next() {
+            Some(Err(DbError::ChecksumMismatch { file, .. })) => assert!(file.ends_with("20200101/22")),
+            _ => panic!("checksum mismatch not reported")
+        }
+        assert!(records.next().is_none());
+        assert_eq!(records.stats().checksum_mismatches, 1);
+    }
+
+    #[test]
+    fn checksum_skip_leaves_the_record_out() {
+        let dir = TempDir::new("checksum_skip");
+        let database = mismatched(&dir, ChecksumPolicy::Skip);
+        let mut records = database.range("levels", T, T + HOUR).unwrap();
+        assert_eq!(records.by_ref().map(|record| record.unwrap().timestamp).collect::<Vec<_>>(), vec![T, T + 20]);
+        assert_eq!(records.stats(), ReadStats { records: 2, checksum_mismatches: 1, ..ReadStats::default() });
+        assert!(!dir.path().join("levels").join(QUARANTINE_DIR).exists());
+    }
+
+    #[test]
+    fn checksum_quarantine_copies_the_frame() {
+        let dir = TempDir::new("checksum_quarantine");
+        let database = mismatched(&dir, ChecksumPolicy::Quarantine);
+        let mut records = database.range("levels", T, T + HOUR).unwrap();
+        assert_eq!(records.by_ref().map(|record| record.unwrap().timestamp).collect::<Vec<_>>(), vec![T, T + 20]);
+        assert_eq!(records.stats(), ReadStats {
+            records:                2,
+            checksum_mismatches:    1,
+            records_quarantined:    1,
+            ..ReadStats::default()
+        });
+
+        let quarantined = fs::read(dir.path().join("levels").join(QUARANTINE_DIR).join("20200101-22")).unwrap();
+        let record = MpdRecordType { timestamp: T + 10, seq: 0, datalog: vec![1, 2], checksum: 0, source: None };
+        assert_eq!(quarantined, encode_frame(&rmps::to_vec(&record).unwrap()));
+    }
+
+    #[test]
+    fn next_with_borrows_datalog_from_the_mapping() {
+        let dir = TempDir::new("next_with");
+        write(&dir, &[T, T + 10]);
+        let database = Database::new(dir.path()).unwrap();
+        let mut records = database.range("levels", T, T + HOUR).unwrap();
+        let borrowed = |record: MpdRecordRef| Ok((record.timestamp, matches!(record.datalog, Cow::Borrowed(_))));
+        assert_eq!(records.next_with(borrowed).unwrap(), Some((T, true)));
+        assert_eq!(records.next_with(borrowed).unwrap(), Some((T + 10, true)));
+        assert_eq!(records.next_with(borrowed).unwrap(), None);
+    }
+
+    #[test]
+    fn datalog_stored_as_an_array_is_still_read() {
+        let dir = TempDir::new("next_with_array");
+        write(&dir, &[]);
+        let partition = dir.path().join("levels").join("20200101").join("22");
+        fs::create_dir_all(partition.parent().unwrap()).unwrap();
+
+        // Records written before 'datalog' was msgpack bin hold it as an array of integers
+        let record = (T, 0u32, vec![1u8, 2], crc32::checksum_ieee(&[1, 2]));
+        let mut bytes = PartitionHeader::new(Codec::MsgPack, 0, T - 30 * 60_000).encode().to_vec();
+        bytes.extend_from_slice(&encode_frame(&rmps::to_vec(&record).unwrap()));
+        fs::write(&partition, bytes).unwrap();
+
+        let database = Database::new(dir.path()).unwrap();
+        let mut records = database.range("levels", T, T + HOUR).unwrap();
+        let copied = records.next_with(|record| Ok(matches!(record.datalog, Cow::Owned(_)))).unwrap();
+        assert_eq!(copied, Some(true));
+        assert_eq!(database.range("levels", T, T + HOUR).unwrap().next().unwrap().unwrap().datalog, vec![1, 2]);
+    }
+}
@@ -0,0 +1,49 @@
+use serde::{Serialize, Deserialize};
+use crate::summary::Summarize;
+use crate::table::Schema;
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[allow(non_snake_case)]
+pub struct RawData { // change all names
+    pub AQHI:		Option<i32>,
+	pub AQI:		Option<i32>,
+	pub CO:			Option<f32>,
+	pub CO2:		Option<f32>,
+	pub NO:			Option<f32>,
+	pub NO2:		Option<f32>,
+	pub O3:			Option<f32>,
+	pub PM1:		Option<f32>,
+	pub PM2_5:		Option<f32>,
+	pub PM10:		Option<f32>,
+	pub SO2:		Option<f32>,
+	pub T:			Option<f32>,
+	pub RH:			Option<f32>,
+	pub NOISE:		Option<f32>,
+	pub TimeStamp:	Option<String> // change ~ ticks
+}
+
+impl Schema for RawData {
+	const NAME: &'static str = "RawData";
+	const VERSION: u32 = 1;
+}
+
+impl Summarize for RawData {
+	fn fields(&self) -> Vec<(&'static str, Option<f64>)> {
+		vec![
+			("AQHI",	self.AQHI.map(f64::from)),
+			("AQI",		self.AQI.map(f64::from)),
+			("CO",		self.CO.map(f64::from)),
+			("CO2",		self.CO2.map(f64::from)),
+			("NO",		self.NO.map(f64::from)),
+			("NO2",		self.NO2.map(f64::from)),
+			("O3",		self.O3.map(f64::from)),
+			("PM1",		self.PM1.map(f64::from)),
+			("PM2_5",	self.PM2_5.map(f64::from)),
+			("PM10",	self.PM10.map(f64::from)),
+			("SO2",		self.SO2.map(f64::from)),
+			("T",		self.T.map(f64::from)),
+			("RH",		self.RH.map(f64::from)),
+			("NOISE",	self.NOISE.map(f64::from)),
+		]
+	}
+}
@@ -0,0 +1,224 @@
+use std::fs;
+use std::path::{Component, Path};
+use serde::{Serialize, Deserialize};
+use crate::error::{DbError, Result};
+use crate::format::{Codec, Compression};
+use crate::partition::{Granularity, Partitioning, Zone};
+use crate::table::Schema;
+
+pub(crate) static MANIFEST_FILE: &str = "manifest.toml";
+
+// Handling of a record with the same source and timestamp as one already stored, Ex: a retried upload.
+// Stored keys are looked up in a bloom filter of the partition, confirmed through its index, see dedup.rs
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Duplicates {
+    #[default]
+    Allow,      // Stored again, records are never looked up on insert
+    Ignore,     // Dropped, the stored record is kept
+    Replace,    // Appended, reads keep the latest record and sealing drops the others
+}
+
+/***
+* Struct Manifest:
+*
+* Purpose:
+* Describes a table, stored as data/<table>/manifest.toml
+***/
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Manifest {
+    pub name:               String,
+    #[serde(default)]
+    pub id:                 u32,            // Written in partition headers, 0 for tables created before ids
+    pub schema:             String,         // Type stored in 'datalog', Ex: RawData
+    pub schema_version:     u32,
+    pub partition:          Granularity,
+    pub created:            u64,            // Milliseconds since epoch
+    pub time_zone:          Zone,
+    #[serde(default)]
+    pub codec:              Codec,          // Encoding of sealed partitions, columnar for RawData
+    #[serde(default)]
+    pub compression:        Compression,    // Applied to partitions once they are sealed
+    pub retention_days:     Option<u32>,    // Days of data Database::expire keeps, None keeps everything
+    #[serde(default)]
+    pub duplicates:         Duplicates,     // Records are unique by source and timestamp unless allowed
+}
+
+impl Manifest {
+    // Partition layout of the table
+    pub(crate) fn partitioning(&self) -> Partitioning {
+        Partitioning {
+            granularity:    self.partition,
+            zone:           self.time_zone,
+        }
+    }
+
+    // True if a record supersedes the one stored with the same source and timestamp, see Duplicates::Replace
+    pub(crate) fn supersedes(&self) -> bool {
+        self.duplicates == Duplicates::Replace
+    }
+
+    // True if partitions are rewritten once they are closed
+    pub(crate) fn seals(&self) -> bool {
+        self.codec != Codec::MsgPack || self.compression != Compression::None
+    }
+}
+
+// Options of a new table, partitioning left as None follows the database configuration
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TableOptions {
+    pub schema:             String,         // Empty for a table of bytes only read as records, see TableOptions::of
+    pub schema_version:     u32,
+    pub partition:          Option<Granularity>,
+    pub time_zone:          Option<Zone>,
+    pub codec:              Codec,
+    pub compression:        Compression,
+    pub retention_days:     Option<u32>,
+    pub duplicates:         Duplicates,
+}
+
+impl TableOptions {
+    // Options of a table storing values of T, which Database::table opens it with
+    pub fn of<T: Schema>() -> TableOptions {
+        TableOptions {
+            schema:         T::NAME.to_string(),
+            schema_version: T::VERSION,
+            ..TableOptions::default()
+        }
+    }
+}
+
+/***
+* Function read_manifest:
+*
+* Purpose:
+* Loads the manifest of a table directory, None if the table was never created
+***/
+pub(crate) fn read_manifest(table: &Path) -> Result<Option<Manifest>> {
+    let path = table.join(MANIFEST_FILE);
+    if !path.exists() {
+        return Ok(None);
+    }
+
+    let text = fs::read_to_string(&path)?;
+    match toml::from_str(&text) {
+        Ok(manifest) => Ok(Some(manifest)),
+        Err(err) => Err(DbError::InvalidManifest { file: path, message: err.to_string() })
+    }
+}
+
+/***
+* Function write_manifest:
+*
+* Purpose:
+* Stores the manifest of a table directory
+***/
+pub(crate) fn write_manifest(table: &Path, manifest: &Manifest) -> Result<()> {
+    let path = table.join(MANIFEST_FILE);
+    let text = match toml::to_string(manifest) {
+        Ok(text) => text,
+        Err(err) => return Err(DbError::InvalidManifest { file: path, message: err.to_string() })
+    };
+    fs::write(&path, text)?;
+    return Ok(());
+}
+
+// Table names are a single directory under the source
+pub(crate) fn check_table_name(name: &str) -> Result<()> {
+    let mut components = Path::new(name).components();
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(_)), None) => Ok(()),
+        _ => Err(DbError::InvalidTableName(name.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::database::{Database, Entry};
+    use crate::partition::get_timestamp;
+    use crate::testing::{TempDir, entry};
+
+    fn options(schema: &str) -> TableOptions {
+        TableOptions { schema: schema.to_string(), schema_version: 1, ..TableOptions::default() }
+    }
+
+    #[test]
+    fn create_table_writes_its_manifest() {
+        let dir = TempDir::new("create_table");
+        let database = Database::builder().source(dir.path()).partition(Granularity::Day).build().unwrap();
+        let zoned = TableOptions { time_zone: Some(Zone::Offset(60)), retention_days: Some(30), ..options("RawData") };
+        let manifest = database.create_table("levels", zoned).unwrap();
+        assert_eq!((manifest.partition, manifest.time_zone), (Granularity::Day, Zone::Offset(60)));
+        assert_eq!(read_manifest(&dir.path().join("levels")).unwrap(), Some(manifest.clone()));
+
+        // A new handle reads the same manifest back
+        assert_eq!(Database::new(dir.path()).unwrap().manifest("levels").unwrap(), Some(manifest));
+        assert!(matches!(database.create_table("levels", options("RawData")), Err(DbError::TableExists(_))));
+        for name in ["", "a/b", "..", "/levels"] {
+            assert!(matches!(database.create_table(name, options("RawData")), Err(DbError::InvalidTableName(_))));
+        }
+    }
+
+    #[test]
+    fn expire_deletes_partitions_past_retention() {
+        let dir = TempDir::new("expire");
+        let database = Database::new(dir.path()).unwrap();
+        database.create_table("levels", TableOptions { retention_days: Some(30), ..options("RawData") }).unwrap();
+        database.create_table("kept", options("RawData")).unwrap();
+        let now = get_timestamp();
+        for &(table, timestamp) in &[("levels", 1_577_887_200_000), ("levels", now), ("kept", 1_577_887_200_000)] {
+            database.insert(entry(table, timestamp, &[1])).unwrap();
+        }
+        database.flush().unwrap();
+
+        assert_eq!(database.expire("levels").unwrap(), 1);
+        assert!(!dir.path().join("levels").join("20200101").exists());
+        assert_eq!(database.range("levels", 0, now).unwrap().count(), 1);
+        assert_eq!(database.expire("levels").unwrap(), 0);
+
+        // Tables without a retention keep everything
+        assert_eq!(database.expire("kept").unwrap(), 0);
+        assert_eq!(database.range("kept", 0, now).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn drop_table_removes_its_partitions() {
+        let dir = TempDir::new("drop_table");
+        let database = Database::new(dir.path()).unwrap();
+        database.create_table("levels", options("RawData")).unwrap();
+        let entry = Entry { table: "levels", data: vec![1], timestamp: Some(1_577_887_200_000), source: None };
+        database.insert(entry).unwrap();
+        database.drop_table("levels").unwrap();
+        assert!(!dir.path().join("levels").exists());
+        assert_eq!(database.manifest("levels").unwrap(), None);
+        assert!(matches!(database.drop_table("levels"), Err(DbError::UnknownTable(_))));
+    }
+
+    #[test]
+    fn list_tables_skips_directories_without_a_manifest() {
+        let dir = TempDir::new("list_tables");
+        let database = Database::new(dir.path()).unwrap();
+        database.create_table("raw", options("Vec<u8>")).unwrap();
+        database.create_table("levels", options("RawData")).unwrap();
+        fs::create_dir(dir.path().join("scratch")).unwrap();
+        let names: Vec<String> = database.list_tables().unwrap().into_iter().map(|manifest| manifest.name).collect();
+        assert_eq!(names, vec!["levels", "raw"]);
+        let missing = Database::from_config(Config { source: dir.path().join("missing"), ..Config::default() });
+        assert_eq!(missing.list_tables().unwrap(), Vec::new());
+    }
+
+    #[test]
+    fn insert_needs_a_table() {
+        let dir = TempDir::new("unknown_table");
+        let database = Database::new(dir.path()).unwrap();
+        let entry = Entry { table: "levels", data: vec![1], timestamp: Some(1_577_887_200_000), source: None };
+        assert!(matches!(database.insert(entry), Err(DbError::UnknownTable(_))));
+        assert!(!dir.path().join("levels").exists());
+
+        fs::create_dir(dir.path().join("levels")).unwrap();
+        fs::write(dir.path().join("levels").join(MANIFEST_FILE), "name = 1").unwrap();
+        assert!(matches!(database.manifest("levels"), Err(DbError::InvalidManifest { .. })));
+    }
+}
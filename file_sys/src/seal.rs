@@ -0,0 +1,442 @@
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::path::Path;
+use lz4_flex::frame::{FrameDecoder, FrameEncoder};
+use memmap2::Mmap;
+use crate::error::{DbError, Result};
+use crate::columnar;
+use crate::columnar::BlockReader;
+use crate::crypto::{ChunkReader, KeyRing, decrypt_frames, encrypt_chunks, encrypt_frames};
+use crate::dedup::filter_path;
+use crate::format::{Cipher, Codec, Compression, PartitionHeader, FORMAT_VERSION, HEADER_LEN, Frame, read_frame};
+use crate::index::index_path;
+use crate::legacy::upgrade;
+use crate::order::{is_sorted, mark_sorted, sort_frames};
+use crate::stream::FrameStream;
+use crate::summary::{read_summary, write_summary};
+
+/***
+* Function read_partition:
+*
+* Purpose:
+* Reads a partition file into 'buf' as it was before sealing: the header
+* followed by the uncompressed frames, so offsets are the same either way.
+* Sealed partitions are decrypted whole, the frames of open ones are left
+* encrypted for the reader, see PartitionHeader::frames_encrypted. Files of an
+* older format are laid out as the current one, see upgrade.
+***/
+pub(crate) fn read_partition(file: &Path, keys: &KeyRing, buf: &mut Vec<u8>) -> Result<PartitionHeader> {
+    buf.clear();
+    File::open(file)?.read_to_end(buf)?;
+    let header = match PartitionHeader::decode(file, buf) {
+        Ok(header) => header,
+        Err(err) => match upgrade(file, buf)? {
+            Some((header, upgraded)) => {
+                *buf = upgraded;
+                return Ok(header);
+            }
+            None => return Err(err)
+        }
+    };
+    if header.is_sealed() {
+        let frames = unseal(file, &header, keys, &buf[HEADER_LEN..])?;
+        buf.truncate(HEADER_LEN);
+        buf.extend_from_slice(&frames);
+    }
+    return Ok(header);
+}
+
+// Contents of a partition file laid out as read_partition describes
+pub(crate) enum PartitionData {
+    Owned(Vec<u8>),             // Unsealed from a sealed partition
+    Mapped(Mmap),               // File read in place, pages are loaded and evicted by the OS
+    Streamed(FrameStream),      // Read front to back through a window, see open_partition
+}
+
+impl PartitionData {
+    // True if there is nothing left from 'pos' on
+    pub(crate) fn at_end(&mut self, pos: usize) -> Result<bool> {
+        match self {
+            PartitionData::Owned(buf) => Ok(pos >= buf.len()),
+            PartitionData::Mapped(map) => Ok(pos >= map.len()),
+            PartitionData::Streamed(stream) => stream.at_end(pos)
+        }
+    }
+
+    // Frame at offset 'pos', offsets past the end read as damaged
+    pub(crate) fn frame(&mut self, pos: usize) -> Result<Frame<'_>> {
+        let buf = match self {
+            PartitionData::Owned(buf) => &buf[..],
+            PartitionData::Mapped(map) => &map[..],
+            PartitionData::Streamed(stream) => return stream.frame(pos)
+        };
+        if pos >= buf.len() {
+            return Ok(Frame::Damaged(buf.len()));
+        }
+        return Ok(read_frame(buf, pos));
+    }
+
+    // True if 'pos' can't be read anymore, streams only move forward
+    pub(crate) fn passed(&self, pos: usize) -> bool {
+        match self {
+            PartitionData::Streamed(stream) => stream.passed(pos),
+            _ => false
+        }
+    }
+
+    // Bytes between two offsets, of the frame last read when streamed
+    pub(crate) fn slice(&self, start: usize, end: usize) -> &[u8] {
+        match self {
+            PartitionData::Owned(buf) => &buf[start..end],
+            PartitionData::Mapped(map) => &map[start..end],
+            PartitionData::Streamed(stream) => stream.slice(start, end)
+        }
+    }
+}
+
+/***
+* Function map_partition:
+*
+* Purpose:
+* Same as read_partition without copying the file: partitions that aren't
+* sealed are mapped as they are and sealed ones are decompressed straight
+* from the mapping, so long scans leave caching to the page cache
+***/
+pub(crate) fn map_partition(file: &Path, keys: &KeyRing) -> Result<(PartitionHeader, PartitionData)> {
+    // Partition files are only appended to or replaced by a rename, see replace_partition, never shrunk in
+    // place, so the mapped pages stay backed by the file while the mapping is held
+    let map = unsafe { Mmap::map(&File::open(file)?)? };
+    let header = match PartitionHeader::decode(file, &map) {
+        Ok(header) => header,
+        Err(err) => match upgrade(file, &map)? {
+            Some((header, buf)) => return Ok((header, PartitionData::Owned(buf))),
+            None => return Err(err)
+        }
+    };
+    if !header.is_sealed() {
+        return Ok((header, PartitionData::Mapped(map)));
+    }
+
+    let mut buf = map[..HEADER_LEN].to_vec();
+    buf.extend_from_slice(&unseal(file, &header, keys, &map[HEADER_LEN..])?);
+    return Ok((header, PartitionData::Owned(buf)));
+}
+
+// Header of a partition file without reading the rest of it, files of an older format are read whole
+pub(crate) fn read_header(file: &Path) -> Result<PartitionHeader> {
+    let mut bytes = [0u8; HEADER_LEN];
+    let len = File::open(file)?.read(&mut bytes)?;
+    return match PartitionHeader::decode(file, &bytes[..len]) {
+        Ok(header) => Ok(header),
+        Err(err) => match upgrade(file, &fs::read(file)?)? {
+            Some((header, _)) => Ok(header),
+            None => Err(err)
+        }
+    };
+}
+
+/***
+* Function write_partition:
+*
+* Purpose:
+* Replaces a partition file with 'header' and 'body', the records already in
+* the header's codec, compressed and, when sealed, encrypted in chunks as the
+* header says, so the file can be read back as a stream, see sealed_reader.
+* The new file is written beside the old one and renamed over it, so a crash
+* leaves one or the other.
+***/
+pub(crate) fn write_partition(file: &Path, header: &PartitionHeader, body: &[u8], keys: &KeyRing) -> Result<()> {
+    let mut body = match header.compression {
+        Compression::None => body.to_vec(),
+        Compression::Zstd => zstd::encode_all(body, 0)?,
+        Compression::Lz4 => {
+            let mut encoder = FrameEncoder::new(Vec::new());
+            encoder.write_all(body)?;
+            encoder.finish().map_err(io::Error::from)?
+        }
+    };
+    if header.is_sealed() && header.cipher != Cipher::None {
+        body = encrypt_chunks(header, keys, &body, HEADER_LEN as u64)?;
+    }
+
+    let temp = file.with_extension("tmp");
+    let mut partition = File::create(&temp)?;
+    partition.write_all(&header.encode())?;
+    partition.write_all(&body)?;
+    partition.sync_all()?;
+    fs::rename(&temp, file)?;
+    return Ok(());
+}
+
+/***
+* Function replace_partition:
+*
+* Purpose:
+* Replaces a partition file with 'bytes', Ex: its intact frames when cutting a
+* torn tail. Readers may hold the file mapped, shrinking it in place would fault
+* their reads of the pages cut, so the bytes are written beside it and renamed
+* over it as write_partition does. Readers keep the file they opened.
+***/
+pub(crate) fn replace_partition(file: &Path, bytes: &[u8]) -> Result<()> {
+    let temp = file.with_extension("tmp");
+    let mut partition = File::create(&temp)?;
+    partition.write_all(bytes)?;
+    partition.sync_all()?;
+    fs::rename(&temp, file)?;
+    return Ok(());
+}
+
+/***
+* Function recompress:
+*
+* Purpose:
+* Rewrites a partition with another encoding, Ex: Codec::Columnar and
+* Compression::Zstd to seal it or Codec::MsgPack and Compression::None to append
+* to it again. False if it already was, unless 'rekey' asks for everything to be
+* encrypted again with the current key. Files of an older format are upgraded
+* and records appended out of time order are sorted while the partition is
+* rewritten, dropping those a later record supersedes with 'supersede', see
+* drop_superseded.
+***/
+pub(crate) fn recompress(file: &Path, codec: Codec, compression: Compression, cipher: Cipher, keys: &KeyRing,
+                         rekey: bool, supersede: bool) -> Result<bool> {
+    let mut buf = Vec::new();
+    let header = read_partition(file, keys, &mut buf)?;
+    let mut frames = match header.frames_encrypted() {
+        true => decrypt_frames(file, &header, keys, &buf[HEADER_LEN..], HEADER_LEN as u64)?.0,
+        false => buf.split_off(HEADER_LEN)
+    };
+    let sort = !is_sorted(file);
+    if sort {
+        frames = sort_frames(&frames, supersede);
+    }
+
+    // Partitions that can't be stored as columns stay msgpack
+    let columns = match codec {
+        Codec::Columnar => columnar::encode(&frames),
+        Codec::MsgPack => None
+    };
+    let codec = if columns.is_some() { Codec::Columnar } else { Codec::MsgPack };
+    let target = PartitionHeader {
+        version:        FORMAT_VERSION,
+        codec:          codec,
+        compression:    compression,
+        cipher:         cipher,
+        ..header
+    };
+    if target == header && !(rekey && cipher != Cipher::None) {
+        return Ok(false);
+    }
+
+    let body = match (columns, target.frames_encrypted()) {
+        (Some(columns), _) => columns,
+        (None, true) => encrypt_frames(&target, keys, &frames, HEADER_LEN as u64)?,
+        (None, false) => frames
+    };
+    let summary = read_summary(file, keys);
+    write_partition(file, &target, &body, keys)?;
+    if sort {
+        mark_sorted(file)?;
+    }
+
+    // Frames moved if they were sorted, upgraded or their payloads were or now are encrypted one by one. An
+    // encrypted index is bound to the header it was written under, see encrypt_sidecar.
+    if sort || header.version != target.version || header.cipher != Cipher::None || target.cipher != Cipher::None {
+        let index = index_path(file);
+        if index.exists() {
+            fs::remove_file(index)?;
+        }
+    }
+    let stored = filter_path(file);
+    if stored.exists() {
+        fs::remove_file(stored)?;       // Stamped with the file replaced, see read_filter
+    }
+
+    // Same records, keep the summary current
+    if let Some(mut summary) = summary {
+        summary.stamp(file)?;
+        write_summary(file, keys, &summary)?;
+    }
+    return Ok(true);
+}
+
+/***
+* Function is_stale:
+*
+* Purpose:
+* True if any part of an encrypted partition was encrypted with a key other
+* than the current one, Ex: after a rotation
+***/
+pub(crate) fn is_stale(file: &Path, keys: &KeyRing) -> Result<bool> {
+    if read_header(file)?.cipher == Cipher::None {
+        return Ok(false);       // Files of an older format included
+    }
+    let buf = fs::read(file)?;
+    let header = PartitionHeader::decode(file, &buf)?;
+    if header.is_sealed() {
+        return Ok(keys.is_stale(&buf[HEADER_LEN..]));
+    }
+
+    let mut pos = HEADER_LEN;
+    while pos < buf.len() {
+        match read_frame(&buf, pos) {
+            Frame::Record(payload, next) => {
+                if keys.is_stale(payload) {
+                    return Ok(true);
+                }
+                pos = next;
+            }
+            Frame::Damaged(next) => pos = next,
+        }
+    }
+    return Ok(false);
+}
+
+// Frames of a sealed partition from the body following its header
+fn unseal(file: &Path, header: &PartitionHeader, keys: &KeyRing, body: &[u8]) -> Result<Vec<u8>> {
+    let mut frames = Vec::new();
+    sealed_reader(file, header, keys, body)?.read_to_end(&mut frames)
+        .map_err(|err| decode_error(file, HEADER_LEN as u64, err))?;
+    return Ok(frames);
+}
+
+/***
+* Function sealed_reader:
+*
+* Purpose:
+* Reads the frames of a sealed partition from its body, the bytes following
+* its header, as they are decrypted a chunk, decompressed and decoded a block
+* at a time, so a partition of any size is read in bounded memory. Errors read
+* from it are turned back into a DbError by decode_error.
+***/
+pub(crate) fn sealed_reader<'a, R>(file: &Path, header: &PartitionHeader, keys: &KeyRing, body: R)
+                                   -> Result<Box<dyn Read + 'a>> where R: BufRead + 'a {
+    let decrypted: Box<dyn BufRead + 'a> = match header.cipher {
+        Cipher::None => Box::new(body),
+        _ => Box::new(BufReader::new(ChunkReader::new(file, header, keys, body, HEADER_LEN as u64)))
+    };
+    let decompressed: Box<dyn Read + 'a> = match header.compression {
+        Compression::None => Box::new(decrypted),
+        Compression::Zstd => Box::new(zstd::Decoder::with_buffer(decrypted)?),
+        Compression::Lz4 => Box::new(FrameDecoder::new(decrypted)),
+    };
+    return Ok(match header.codec {
+        Codec::MsgPack => decompressed,
+        Codec::Columnar => Box::new(BlockReader::new(file, decompressed))
+    });
+}
+
+// Error reading from a sealed_reader: the DbError of a step, Ex: a chunk failing authentication, or corrupt data
+pub(crate) fn decode_error(file: &Path, offset: u64, err: io::Error) -> DbError {
+    let wrapped = err.get_ref().is_some_and(|inner| inner.is::<DbError>());
+    let inner = match wrapped {
+        true => err.into_inner().and_then(|inner| inner.downcast::<DbError>().ok()),
+        false => None
+    };
+    match inner.map(|inner| *inner) {
+        Some(DbError::Io(err)) => decode_error(file, offset, err),
+        Some(err) => err,
+        None => DbError::CorruptRecord { file: file.to_path_buf(), offset: offset }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use crate::database::Database;
+    use crate::raw_data::RawData;
+    use crate::registry::TableOptions;
+    use crate::testing::{TempDir, entry};
+    use super::*;
+
+    const T: u64 = 1_577_887_200_000;      // 2020-01-01 14:00:00 UTC
+    const HOUR: u64 = 3_600_000;
+
+    fn open(dir: &TempDir, compression: Compression) -> Database {
+        let database = Database::new(dir.path()).unwrap();
+        database.create_table("levels", TableOptions { compression: compression, ..TableOptions::default() }).unwrap();
+        return database;
+    }
+
+    fn partition(dir: &TempDir, hour: &str) -> PathBuf {
+        dir.path().join("levels").join("20200101").join(hour)
+    }
+
+    fn datalogs(database: &Database) -> Vec<Vec<u8>> {
+        database.range("levels", T, T + 2 * HOUR).unwrap().map(|record| record.unwrap().datalog).collect()
+    }
+
+    #[test]
+    fn moving_on_seals_the_previous_partition() {
+        let dir = TempDir::new("seal_zstd");
+        let database = open(&dir, Compression::Zstd);
+        database.insert(entry("levels", T, &[1; 64])).unwrap();
+        database.insert(entry("levels", T + 1, &[2; 64])).unwrap();
+        database.flush().unwrap();
+        let open_len = fs::metadata(partition(&dir, "14")).unwrap().len();
+        assert_eq!(read_header(&partition(&dir, "14")).unwrap().compression, Compression::None);
+
+        database.insert(entry("levels", T + HOUR, &[3; 64])).unwrap();
+        assert_eq!(read_header(&partition(&dir, "14")).unwrap().compression, Compression::Zstd);
+        assert!(fs::metadata(partition(&dir, "14")).unwrap().len() < open_len);
+        assert_eq!(read_header(&partition(&dir, "15")).unwrap().compression, Compression::None);
+        assert_eq!(datalogs(&database), vec![vec![1; 64], vec![2; 64], vec![3; 64]]);
+    }
+
+    #[test]
+    fn late_record_reopens_a_sealed_partition() {
+        let dir = TempDir::new("seal_reopen");
+        let database = open(&dir, Compression::Lz4);
+        database.insert(entry("levels", T, &[1; 64])).unwrap();
+        database.insert(entry("levels", T + HOUR, &[3; 64])).unwrap();
+        assert_eq!(read_header(&partition(&dir, "14")).unwrap().compression, Compression::Lz4);
+
+        database.insert(entry("levels", T + 1, &[2; 64])).unwrap();
+        assert_eq!(read_header(&partition(&dir, "14")).unwrap().compression, Compression::None);
+        assert_eq!(datalogs(&database), vec![vec![1; 64], vec![2; 64], vec![3; 64]]);
+    }
+
+    #[test]
+    fn seal_compresses_closed_partitions_once() {
+        let dir = TempDir::new("seal_table");
+        let database = open(&dir, Compression::Zstd);
+        database.insert(entry("levels", T + HOUR, &[3; 64])).unwrap();
+        database.insert(entry("levels", T, &[1; 64])).unwrap();
+
+        // Neither was closed by moving on to a later partition
+        assert_eq!(read_header(&partition(&dir, "15")).unwrap().compression, Compression::None);
+        assert_eq!(database.seal("levels").unwrap(), 2);
+        assert_eq!(database.seal("levels").unwrap(), 0);
+        assert_eq!(read_header(&partition(&dir, "14")).unwrap().compression, Compression::Zstd);
+        assert_eq!(datalogs(&database), vec![vec![1; 64], vec![3; 64]]);
+
+        let plain = TempDir::new("seal_none");
+        assert_eq!(open(&plain, Compression::None).seal("levels").unwrap(), 0);
+    }
+
+    #[test]
+    fn raw_data_is_sealed_as_columns() {
+        let dir = TempDir::new("seal_columnar");
+        let database = Database::new(dir.path()).unwrap();
+        let options = TableOptions { codec: Codec::Columnar, compression: Compression::Zstd, ..TableOptions::of::<RawData>() };
+        database.create_table("levels", options).unwrap();
+        let levels = database.table::<RawData>("levels").unwrap();
+        let reading = |o3: f32| RawData {
+            AQHI: None, AQI: Some(3), CO: None, CO2: None, NO: None, NO2: None, O3: Some(o3), PM1: None,
+            PM2_5: None, PM10: None, SO2: None, T: None, RH: None, NOISE: None, TimeStamp: None,
+        };
+        levels.insert_at(T, &reading(1.0)).unwrap();
+        levels.insert_at(T + 1000, &reading(1.5)).unwrap();
+        database.insert(entry("levels", T + HOUR, b"not RawData")).unwrap();
+        database.insert(entry("levels", T + 2 * HOUR, b"next")).unwrap();
+
+        // Partitions that aren't all RawData are sealed as msgpack
+        assert_eq!(read_header(&partition(&dir, "14")).unwrap().codec, Codec::Columnar);
+        assert_eq!(read_header(&partition(&dir, "15")).unwrap().codec, Codec::MsgPack);
+        let values: Vec<_> = levels.range(T, T + HOUR - 1).unwrap().map(Result::unwrap).collect();
+        assert_eq!(values, vec![reading(1.0), reading(1.5)]);
+    }
+}
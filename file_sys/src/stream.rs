@@ -0,0 +1,493 @@
+use std::cmp::max;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::io::{BufReader, SeekFrom};
+use std::mem;
+use std::path::{Path, PathBuf};
+use crate::crypto::KeyRing;
+use crate::database::MpdRecordRef;
+use crate::error::{DbError, Result};
+use crate::format::{Frame, PartitionHeader, FRAME_HEADER_LEN, HEADER_LEN, MAX_FRAME_LEN, SYNC_MARKER, read_frame,
+                    read_u32};
+use crate::index::with_record;
+use crate::seal::{PartitionData, decode_error, map_partition, sealed_reader};
+
+const SCAN_BUFFER: usize = 64 * 1024;
+
+// Where a stream reads the frames of a partition from
+enum Source {
+    File(File),                 // Partition that isn't sealed, offsets are those of the file
+    Decoded(Box<dyn Read>),     // Sealed partition, decrypted, decompressed and decoded as it is read
+}
+
+// Bytes of a partition read at once, from partition offset 'start' on
+struct Window {
+    bytes:  Vec<u8>,
+    start:  usize,
+    eof:    bool,       // True once 'bytes' reaches the end of the partition
+}
+
+impl Window {
+    fn at(start: usize) -> Window {
+        Window { bytes: Vec::new(), start: start, eof: false }
+    }
+
+    fn end(&self) -> usize {
+        self.start + self.bytes.len()
+    }
+
+    // True if reading from 'pos' on starts in or right after the window
+    fn holds(&self, pos: usize) -> bool {
+        pos >= self.start && pos <= self.end()
+    }
+}
+
+/***
+* Struct FrameStream:
+*
+* Purpose:
+* Reads the frames of a partition through a window of Config::read_buffer
+* bytes, grown only to fit a frame larger than it, so a day of records is
+* decoded without holding the day in memory. Offsets are those of the
+* partition laid out as read_partition describes. Sealed partitions are read
+* front to back, files can also be read at any offset and hold up to two
+* windows, see jump.
+***/
+pub(crate) struct FrameStream {
+    file:       PathBuf,
+    source:     Source,
+    window:     Window,
+    spare:      Window,         // Window set aside by the last jump, files only
+    head:       usize,          // Index in 'window' of the offset last asked for
+    capacity:   usize,          // Bytes read ahead at most
+    cursor:     usize,          // Partition offset the source reads from next
+    jumped:     Option<usize>,  // Offset the window was last moved to by a jump, its frame is read alone
+    bytes_read: u64,            // Bytes read from the source
+}
+
+impl FrameStream {
+    fn new(file: &Path, source: Source, capacity: usize) -> FrameStream {
+        FrameStream {
+            file:       file.to_path_buf(),
+            source:     source,
+            window:     Window::at(HEADER_LEN),
+            spare:      Window::at(HEADER_LEN),
+            head:       0,
+            capacity:   capacity,
+            cursor:     HEADER_LEN,
+            jumped:     None,
+            bytes_read: 0,
+        }
+    }
+
+    // True if 'pos' was already read past and can't be read again
+    pub(crate) fn passed(&self, pos: usize) -> bool {
+        match self.source {
+            Source::File(_) => false,
+            Source::Decoded(_) => pos < self.window.start + self.head
+        }
+    }
+
+    // Bytes from 'pos' on, at least 'want' of them unless the partition ends first
+    fn fill(&mut self, pos: usize, want: usize) -> Result<&[u8]> {
+        if !self.window.holds(pos) {
+            self.jump(pos)?;
+        }
+        self.head = pos - self.window.start;
+
+        if self.window.bytes.len() - self.head < want && !self.window.eof {
+            // Drop what was read before 'pos' and read ahead, only as far as the frame jumped to
+            self.window.bytes.drain(..self.head);
+            self.window.start = pos;
+            self.head = 0;
+            let target = match self.jumped == Some(pos) {
+                true => want,
+                false => max(self.capacity, want)
+            };
+            while self.window.bytes.len() < want && !self.window.eof {
+                let len = self.window.bytes.len();
+                self.window.bytes.resize(target, 0);
+                match self.read(len) {
+                    Ok(read) => {
+                        self.window.bytes.truncate(len + read);
+                        self.window.eof = read == 0;
+                    }
+                    Err(err) => {
+                        self.window.bytes.truncate(len);
+                        return Err(err);
+                    }
+                }
+            }
+        }
+        return Ok(&self.window.bytes[self.head..]);
+    }
+
+    // Read into the window from 'len' on
+    fn read(&mut self, len: usize) -> Result<usize> {
+        let end = self.window.start + len;
+        if let Source::File(file) = &mut self.source {
+            if self.cursor != end {
+                file.seek(SeekFrom::Start(end as u64))?;
+                self.cursor = end;
+            }
+        }
+        let buf = &mut self.window.bytes[len..];
+        loop {
+            let read = match &mut self.source {
+                Source::File(file) => file.read(buf),
+                Source::Decoded(reader) => reader.read(buf),
+            };
+            match read {
+                Ok(read) => {
+                    self.cursor += read;
+                    self.bytes_read += read as u64;
+                    return Ok(read);
+                }
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(self.error(err))
+            }
+        }
+    }
+
+    /***
+    * Function jump:
+    *
+    * Purpose:
+    * Moves the window to 'pos', outside of it. A file keeps the window left as
+    * the spare and reads the frame at 'pos' alone, so visiting an unsorted
+    * partition in time order, back and forth between late records and the run
+    * they belong in, reads about each frame once instead of a window per late
+    * record. A sealed partition only skips forward.
+    ***/
+    fn jump(&mut self, pos: usize) -> Result<()> {
+        match self.source {
+            Source::File(_) if self.spare.holds(pos) => mem::swap(&mut self.window, &mut self.spare),
+            Source::File(_) => {
+                self.spare = mem::replace(&mut self.window, Window::at(pos));
+                self.jumped = Some(pos);
+            }
+            Source::Decoded(_) if pos < self.window.start => {
+                return Err(DbError::Io(io::Error::new(io::ErrorKind::Unsupported,
+                                                      "a sealed partition is only read front to back")));
+            }
+            Source::Decoded(ref mut reader) => {
+                // Skip what was never read, Ex: up to an indexed record
+                let skip = (pos - self.cursor) as u64;
+                if let Err(err) = io::copy(&mut reader.take(skip), &mut io::sink()) {
+                    return Err(self.error(err));
+                }
+                self.cursor = pos;
+                self.window.bytes.clear();
+                self.window.start = pos;
+            }
+        }
+        return Ok(());
+    }
+
+    // A sealed partition that fails to decode is corrupt, like in read_partition, unless a step says otherwise
+    fn error(&self, err: io::Error) -> DbError {
+        match self.source {
+            Source::File(_) => DbError::Io(err),
+            Source::Decoded(_) => decode_error(&self.file, self.window.end() as u64, err)
+        }
+    }
+
+    // Bytes read from the partition file or its decoded stream so far
+    #[cfg(test)]
+    pub(crate) fn bytes_read(&self) -> u64 {
+        self.bytes_read
+    }
+
+    // True once every frame was read
+    pub(crate) fn at_end(&mut self, pos: usize) -> Result<bool> {
+        Ok(self.fill(pos, 1)?.is_empty())
+    }
+
+    /***
+    * Function frame:
+    *
+    * Purpose:
+    * Same as read_frame at partition offset 'pos', reading as much as the
+    * frame needs. A damaged frame is skipped up to the next sync marker even
+    * if it lies beyond the window.
+    ***/
+    pub(crate) fn frame(&mut self, pos: usize) -> Result<Frame<'_>> {
+        let bytes = self.fill(pos, FRAME_HEADER_LEN)?;
+        let mut len = FRAME_HEADER_LEN;
+        if bytes.len() >= FRAME_HEADER_LEN && bytes[0..4] == SYNC_MARKER {
+            len += (read_u32(&bytes[4..8]) as usize).min(MAX_FRAME_LEN);
+        }
+        let next = match read_frame(self.fill(pos, len)?, 0) {
+            Frame::Record(_, next) => Some(next),
+            Frame::Damaged(_) => None
+        };
+        if let Some(next) = next {
+            return Ok(Frame::Record(&self.window.bytes[self.head + FRAME_HEADER_LEN..self.head + next], pos + next));
+        }
+
+        // Look for the next sync marker a window at a time
+        let mut scan = pos + 1;
+        loop {
+            let bytes = self.fill(scan, SYNC_MARKER.len())?;
+            let found = bytes.windows(SYNC_MARKER.len()).position(|window| window == SYNC_MARKER);
+            let len = bytes.len();
+            match found {
+                Some(offset) => return Ok(Frame::Damaged(scan + offset)),
+                None if len < SYNC_MARKER.len() => return Ok(Frame::Damaged(scan + len)),
+                None => scan += len + 1 - SYNC_MARKER.len()     // A marker may straddle the window
+            }
+        }
+    }
+
+    // Bytes between two partition offsets of the frame last read, Ex: to quarantine it
+    pub(crate) fn slice(&self, start: usize, end: usize) -> &[u8] {
+        &self.window.bytes[start - self.window.start..end - self.window.start]
+    }
+}
+
+/***
+* Function open_partition:
+*
+* Purpose:
+* Opens a partition for a query. Sealed partitions and files larger than
+* 'read_buffer' that aren't sealed are streamed through a window of that
+* size, other partitions are mapped by map_partition. A 'read_buffer' of 0
+* maps every partition that isn't sealed and streams sealed ones through a
+* window of SCAN_BUFFER bytes, so none is ever unsealed whole.
+***/
+pub(crate) fn open_partition(file: &Path, keys: &KeyRing, read_buffer: usize) -> Result<(PartitionHeader, PartitionData)> {
+    let mut partition = File::open(file)?;
+    let mut bytes = Vec::with_capacity(HEADER_LEN);
+    (&mut partition).take(HEADER_LEN as u64).read_to_end(&mut bytes)?;
+    let header = match PartitionHeader::decode(file, &bytes) {
+        Ok(header) => header,
+        Err(_) => return map_partition(file, keys)      // Upgraded in memory if of an older format
+    };
+
+    let large = read_buffer > 0 && partition.metadata()?.len() > read_buffer as u64;
+    let source = match header.is_sealed() {
+        true => Source::Decoded(sealed_reader(file, &header, keys, BufReader::new(partition))?),
+        false if large => Source::File(partition),
+        false => return map_partition(file, keys)
+    };
+    let capacity = if read_buffer == 0 { SCAN_BUFFER } else { read_buffer };
+    return Ok((header, PartitionData::Streamed(FrameStream::new(file, source, capacity))));
+}
+
+/***
+* Function scan_records:
+*
+* Purpose:
+* Hands every readable record of a partition that isn't sealed, from the frame
+* at 'from' on, to 'f' with the offset of its frame. The partition is read
+* through a window of SCAN_BUFFER bytes, damaged frames and payloads that
+* aren't records are skipped.
+***/
+pub(crate) fn scan_records<F>(file: &Path, header: &PartitionHeader, keys: &KeyRing, from: u64, mut f: F) -> Result<()>
+                              where F: FnMut(u64, MpdRecordRef) {
+    let mut partition = File::open(file)?;
+    partition.seek(SeekFrom::Start(HEADER_LEN as u64))?;
+    let mut stream = FrameStream::new(file, Source::File(partition), SCAN_BUFFER);
+    let mut pos = max(from as usize, HEADER_LEN);
+    while !stream.at_end(pos)? {
+        pos = match stream.frame(pos)? {
+            Frame::Record(payload, next) => {
+                with_record(header, keys, pos as u64, payload, |entry| f(pos as u64, entry));
+                next
+            }
+            Frame::Damaged(next) => next
+        };
+    }
+    return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::PathBuf;
+    use crate::crypto::Key;
+    use crate::database::Database;
+    use crate::format::{Cipher, Codec, Compression};
+    use crate::index::timestamp_of;
+    use crate::order::is_sorted;
+    use crate::raw_data::RawData;
+    use crate::registry::TableOptions;
+    use crate::seal::read_header;
+    use crate::testing::{TempDir, entry};
+    use super::*;
+
+    const T: u64 = 1_577_887_200_000;      // 2020-01-01 14:00:00 UTC
+    const RECORDS: u64 = 5000;             // More than a columnar block
+
+    fn reading(i: u64) -> RawData {
+        RawData {
+            AQHI: None, AQI: Some((i % 7) as i32), CO: None, CO2: Some(400.0 + (i * 7919 % 1000) as f32 / 3.0), NO: None,
+            NO2: None, O3: Some(i as f32 / 10.0), PM1: None, PM2_5: None, PM10: None, SO2: None, T: None, RH: None,
+            NOISE: None, TimeStamp: None,
+        }
+    }
+
+    fn open(dir: &TempDir, cipher: Cipher, read_buffer: usize) -> Database {
+        return Database::builder().source(dir.path()).cipher(cipher).key(Key::new([1; 32])).read_buffer(read_buffer)
+            .build().unwrap();
+    }
+
+    fn partition(dir: &TempDir) -> PathBuf {
+        dir.path().join("levels").join("20200101").join("14")
+    }
+
+    fn streamed(database: &Database, file: &Path) -> bool {
+        let (_, data) = open_partition(file, database.keys(), 4096).unwrap();
+        return matches!(data, PartitionData::Streamed(_));
+    }
+
+    #[test]
+    fn sealed_partitions_stream() {
+        for &codec in &[Codec::MsgPack, Codec::Columnar] {
+            for &compression in &[Compression::None, Compression::Zstd, Compression::Lz4] {
+                for &cipher in &[Cipher::None, Cipher::Aes256Gcm] {
+                    if codec == Codec::MsgPack && compression == Compression::None {
+                        continue;       // Not sealed
+                    }
+                    let dir = TempDir::new("stream_sealed");
+                    let database = open(&dir, cipher, 4096);
+                    let options = TableOptions { codec: codec, compression: compression,
+                                                 ..TableOptions::of::<RawData>() };
+                    database.create_table("levels", options).unwrap();
+                    let levels = database.table::<RawData>("levels").unwrap();
+                    for i in 0..RECORDS {
+                        levels.insert_at(T + i, &reading(i)).unwrap();
+                    }
+                    levels.insert_at(T + 3_600_000, &reading(0)).unwrap();
+
+                    let header = read_header(&partition(&dir)).unwrap();
+                    assert_eq!((header.codec, header.compression, header.cipher), (codec, compression, cipher));
+                    assert!(streamed(&database, &partition(&dir)));
+                    let values: Vec<RawData> = levels.range(T, T + RECORDS).unwrap().map(Result::unwrap).collect();
+                    assert_eq!(values.len() as u64, RECORDS);
+                    assert!(values.iter().enumerate().all(|(i, value)| *value == reading(i as u64)));
+                    assert_eq!(levels.range(T + RECORDS - 2, T + RECORDS).unwrap().count(), 2);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn truncated_sealed_partition_fails() {
+        let dir = TempDir::new("stream_truncated");
+        let database = open(&dir, Cipher::ChaCha20Poly1305, 4096);
+        let options = TableOptions { compression: Compression::Lz4, ..TableOptions::of::<RawData>() };
+        database.create_table("levels", options).unwrap();
+        let levels = database.table::<RawData>("levels").unwrap();
+        for i in 0..RECORDS {
+            levels.insert_at(T + i, &reading(i)).unwrap();
+        }
+        levels.insert_at(T + 3_600_000, &reading(0)).unwrap();
+
+        // Cut at a chunk boundary, the chunk before it wasn't the last one written
+        let bytes = fs::read(partition(&dir)).unwrap();
+        let chunk = 4 + 12 + 64 * 1024 + 16;
+        assert!(bytes.len() > HEADER_LEN + chunk);
+        fs::write(partition(&dir), &bytes[..HEADER_LEN + chunk]).unwrap();
+        let result: Result<Vec<_>> = levels.range(T, T + RECORDS).unwrap().collect();
+        assert!(matches!(result, Err(DbError::Decryption { .. })));
+    }
+
+    #[test]
+    fn large_open_partition_streams() {
+        let dir = TempDir::new("stream_open");
+        let database = open(&dir, Cipher::None, 4096);
+        database.create_table("levels", TableOptions::of::<RawData>()).unwrap();
+        let levels = database.table::<RawData>("levels").unwrap();
+        for i in 0..RECORDS {
+            levels.insert_at(T + i, &reading(i)).unwrap();
+        }
+
+        // One record larger than the window grows it for that record alone
+        let large = RawData { TimeStamp: Some("x".repeat(10_000)), ..reading(RECORDS) };
+        levels.insert_at(T + RECORDS, &large).unwrap();
+        assert!(streamed(&database, &partition(&dir)));
+        let values: Vec<RawData> = levels.range(T + 100, T + RECORDS).unwrap().map(Result::unwrap).collect();
+        assert_eq!(values.len() as u64, RECORDS - 99);
+        assert!(values[0] == reading(100));
+        assert!(values.last() == Some(&large));
+    }
+
+    #[test]
+    fn unsorted_partition_streams_in_time_order() {
+        let dir = TempDir::new("stream_unsorted");
+        let database = open(&dir, Cipher::None, 4096);
+        database.create_table("levels", TableOptions::default()).unwrap();
+        for i in 0..RECORDS {
+            // Every tenth record arrives late, after the one following it
+            let i = match i % 10 { 8 => i + 1, 9 => i - 1, _ => i };
+            database.insert(entry("levels", T + i, &i.to_le_bytes())).unwrap();
+        }
+        database.flush().unwrap();
+
+        assert!(!is_sorted(&partition(&dir)));
+        assert!(streamed(&database, &partition(&dir)));
+        let timestamps: Vec<u64> = database.range("levels", T, T + RECORDS).unwrap()
+            .map(|record| record.unwrap().timestamp).collect();
+        assert_eq!(timestamps, (T..T + RECORDS).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn interleaved_partition_is_read_about_once() {
+        let dir = TempDir::new("stream_interleaved");
+        let database = open(&dir, Cipher::None, 4096);
+        database.create_table("levels", TableOptions::default()).unwrap();
+        for i in 0..RECORDS {
+            // Even timestamps, then odd ones, so visiting in time order goes back and forth between two runs
+            let timestamp = if i < RECORDS / 2 { T + 2 * i } else { T + 2 * (i - RECORDS / 2) + 1 };
+            database.insert(entry("levels", timestamp, &i.to_le_bytes())).unwrap();
+        }
+        database.flush().unwrap();
+        assert!(!is_sorted(&partition(&dir)));
+
+        let file = partition(&dir);
+        let keys = database.keys();
+        let (header, mut data) = open_partition(&file, keys, 4096).unwrap();
+        let mut frames = Vec::new();
+        let mut pos = HEADER_LEN;
+        while !data.at_end(pos).unwrap() {
+            let next = match data.frame(pos).unwrap() {
+                Frame::Record(payload, next) => {
+                    frames.push((timestamp_of(&header, keys, pos as u64, payload).unwrap(), pos));
+                    next
+                }
+                Frame::Damaged(_) => panic!("damaged frame at {}", pos)
+            };
+            pos = next;
+        }
+        frames.sort();
+
+        let (_, mut data) = open_partition(&file, keys, 4096).unwrap();
+        for &(_, offset) in &frames {
+            assert!(matches!(data.frame(offset).unwrap(), Frame::Record(..)));
+        }
+        let read = match data {
+            PartitionData::Streamed(stream) => stream.bytes_read(),
+            _ => panic!("expected a streamed partition")
+        };
+        assert!(read < 2 * fs::metadata(&file).unwrap().len(), "{} bytes read", read);
+    }
+
+    #[test]
+    fn sealed_partitions_stream_without_read_buffer() {
+        let dir = TempDir::new("stream_unbuffered");
+        let database = open(&dir, Cipher::None, 0);
+        let options = TableOptions { compression: Compression::Zstd, ..TableOptions::of::<RawData>() };
+        database.create_table("levels", options).unwrap();
+        let levels = database.table::<RawData>("levels").unwrap();
+        for i in 0..RECORDS {
+            levels.insert_at(T + i, &reading(i)).unwrap();
+        }
+        levels.insert_at(T + 3_600_000, &reading(0)).unwrap();
+
+        let (_, data) = open_partition(&partition(&dir), database.keys(), 0).unwrap();
+        assert!(matches!(data, PartitionData::Streamed(_)));
+        assert_eq!(levels.range(T, T + RECORDS).unwrap().count() as u64, RECORDS);
+    }
+}
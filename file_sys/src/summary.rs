@@ -0,0 +1,353 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+use serde::{Serialize, Deserialize};
+use serde::de::DeserializeOwned;
+use crate::crypto::{KeyRing, decrypt_sidecar, encrypt_sidecar};
+use crate::database::Database;
+use crate::error::{DbError, Result};
+use crate::partition::get_timestamp;
+use crate::query::Range;
+use crate::seal::read_header;
+
+pub(crate) static SUMMARY_EXTENSION: &str = "sum";
+const SUMMARY_TAG: &[u8] = b"sum";
+
+// Types whose numeric fields can be summarized per partition
+pub trait Summarize {
+    // Name and value of every numeric field, None for nulls
+    fn fields(&self) -> Vec<(&'static str, Option<f64>)>;
+}
+
+// Fields of a 'datalog' decoded as the schema of a table, see Database::register_summary
+pub(crate) type Summarizer = fn(&[u8]) -> Result<Vec<(&'static str, Option<f64>)>>;
+
+// Summarizer of tables storing T
+pub(crate) fn summarizer_of<T>(datalog: &[u8]) -> Result<Vec<(&'static str, Option<f64>)>>
+                               where T: DeserializeOwned + Summarize {
+    let value: T = rmps::from_read_ref(datalog)?;
+    return Ok(value.fields());
+}
+
+// Statistics of one field over a partition
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct FieldSummary {
+    pub min:            Option<f64>,    // None when every value was null
+    pub max:            Option<f64>,
+    pub sum:            f64,
+    pub count:          u64,            // Non null values
+    pub null_count:     u64,
+}
+
+impl FieldSummary {
+    // Average of the non null values
+    pub fn mean(&self) -> Option<f64> {
+        if self.count == 0 {
+            return None;
+        }
+        return Some(self.sum / self.count as f64);
+    }
+
+    // Fold another partition's statistics in, Ex: hourly summaries into a day
+    pub fn merge(&mut self, other: &FieldSummary) {
+        self.min = min_of(self.min, other.min);
+        self.max = max_of(self.max, other.max);
+        self.sum += other.sum;
+        self.count += other.count;
+        self.null_count += other.null_count;
+    }
+
+    fn add(&mut self, value: Option<f64>) {
+        match value {
+            Some(value) => {
+                self.min = min_of(self.min, Some(value));
+                self.max = max_of(self.max, Some(value));
+                self.sum += value;
+                self.count += 1;
+            }
+            None => self.null_count += 1,
+        }
+    }
+}
+
+/***
+* Struct PartitionSummary:
+*
+* Purpose:
+* Statistics of every field of a partition, stored beside closed partitions as
+* a sidecar, Ex: data/levels/20200101/14.sum, so aggregates over long ranges
+* don't decode the records. Summaries are stored as partitions are closed, and
+* recomputed once their partition changes. The summary of an encrypted
+* partition is encrypted with it, see encrypt_sidecar.
+***/
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct PartitionSummary {
+    pub start:          u64,            // First millisecond of the partition
+    pub end:            u64,            // Last millisecond of the partition
+    pub records:        u64,
+    pub file_len:       u64,            // Size of the partition file summarized
+    #[serde(default)]
+    pub modified:       u64,            // Modification time of the partition file in nanoseconds since epoch
+    pub fields:         BTreeMap<String, FieldSummary>,
+}
+
+impl PartitionSummary {
+    fn add(&mut self, fields: Vec<(&'static str, Option<f64>)>) {
+        for (name, value) in fields {
+            self.fields.entry(name.to_string()).or_default().add(value);
+        }
+        self.records += 1;
+    }
+
+    // Describe the partition file as it is now, so changes to it can be told
+    pub(crate) fn stamp(&mut self, partition: &Path) -> Result<()> {
+        let (file_len, modified) = file_stamp(partition)?;
+        self.file_len = file_len;
+        self.modified = modified;
+        return Ok(());
+    }
+}
+
+/***
+* Function summarize:
+*
+* Purpose:
+* Summaries of the partitions of a table overlapping a time range. Stored
+* summaries are used when their partition hasn't changed, otherwise the
+* partition is read and, if it's closed, its summary is stored.
+***/
+pub(crate) fn summarize<T>(database: &Database, table: &str, start_time: u64, end_time: u64)
+                           -> Result<Vec<PartitionSummary>> where T: DeserializeOwned + Summarize {
+    let partitioning = database.partitioning(table)?;
+    let directory = database.source().join(table);
+    let mut summaries = Vec::new();
+    let bounds = (partitioning.start_of(start_time), partitioning.start_of(end_time));
+    let (mut curr_partition, end_partition) = match bounds {
+        (Some(curr_partition), Some(end_partition)) => (Some(curr_partition), end_partition),
+        _ => return Err(DbError::InvalidRange { start: start_time, end: end_time })
+    };
+
+    while let Some(curr) = curr_partition.filter(|curr| *curr <= end_partition) {
+        /*** Check if Directory doesn't exist ***/
+        if !partitioning.directory(&directory, curr).exists() {
+            // Skip to midnight of the next day
+            curr_partition = partitioning.next_day(curr);
+            continue;
+        }
+
+        let file = partitioning.file(&directory, curr);
+        if fs::metadata(&file).is_ok_and(|metadata| metadata.len() != 0) {
+            let end = partitioning.end_of(curr).saturating_sub(1);
+            summaries.push(summarize_partition::<T>(database, table, &file, curr, end)?);
+        }
+        curr_partition = partitioning.next(curr);
+    }
+
+    return Ok(summaries);
+}
+
+// Stored summary of a partition if it is current, otherwise one read from its records
+fn summarize_partition<T>(database: &Database, table: &str, file: &Path, start: u64, end: u64)
+                          -> Result<PartitionSummary> where T: DeserializeOwned + Summarize {
+    if let Some(summary) = read_summary(file, database.keys()) {
+        return Ok(summary);
+    }
+
+    let summary = read_records(database, table, file, start, end, summarizer_of::<T>)?;
+
+    // Open partitions are still being written, summarize them again next time
+    if end < get_timestamp() {
+        write_summary(file, database.keys(), &summary)?;
+    }
+    return Ok(summary);
+}
+
+/***
+* Function store_summary:
+*
+* Purpose:
+* Summarizes a partition that was closed and stores the summary beside it,
+* unless the stored one is current. Tables whose schema has no summarizer are
+* left to summarize on their first query, see Database::register_summary.
+***/
+pub(crate) fn store_summary(database: &Database, table: &str, schema: &str, file: &Path, start: u64, end: u64)
+                            -> Result<()> {
+    let summarizer = match database.summarizer(schema) {
+        Some(summarizer) => summarizer,
+        None => return Ok(())
+    };
+    if read_summary(file, database.keys()).is_some() {
+        return Ok(());
+    }
+    let summary = read_records(database, table, file, start, end, summarizer)?;
+    return write_summary(file, database.keys(), &summary);
+}
+
+// Summary of the records of a partition
+fn read_records(database: &Database, table: &str, file: &Path, start: u64, end: u64, summarizer: Summarizer)
+                -> Result<PartitionSummary> {
+    let mut summary = PartitionSummary {
+        start:      start,
+        end:        end,
+        ..PartitionSummary::default()
+    };
+    summary.stamp(file)?;
+
+    // Read without Database::range, the table's writer is locked while a partition closes, after closing the
+    // partition's writer. Table::summaries flushes the table before, so buffered records are counted either way.
+    let partitioning = database.partitioning(table)?;
+    let manifest = database.manifest(table)?;
+    let table_id = manifest.as_ref().map_or(0, |manifest| manifest.id);
+    let mut records = Range::new(database.source().join(table), partitioning, table_id, database.config(),
+                                 database.keys().clone(), start, end)?
+        .superseding(manifest.is_some_and(|manifest| manifest.supersedes()));
+    while let Some(fields) = records.next_with(|entry| summarizer(&entry.datalog))? {
+        summary.add(fields);
+    }
+    return Ok(summary);
+}
+
+// Sidecar summary of a partition file
+pub(crate) fn summary_path(partition: &Path) -> PathBuf {
+    partition.with_extension(SUMMARY_EXTENSION)
+}
+
+// Stored summary of a partition, None if missing, unreadable or the partition changed since
+pub(crate) fn read_summary(partition: &Path, keys: &KeyRing) -> Option<PartitionSummary> {
+    let bytes = fs::read(summary_path(partition)).ok()?;
+    let bytes = decrypt_sidecar(&read_header(partition).ok()?, keys, SUMMARY_TAG, &bytes)?;
+    let summary: PartitionSummary = rmps::from_read(&bytes[..]).ok()?;
+    if file_stamp(partition).ok()? != (summary.file_len, summary.modified) {
+        return None;
+    }
+    return Some(summary);
+}
+
+// Length and modification time of a file, see PartitionSummary::stamp
+pub(crate) fn file_stamp(file: &Path) -> Result<(u64, u64)> {
+    let metadata = fs::metadata(file)?;
+    let modified = metadata.modified()?.duration_since(UNIX_EPOCH).map_or(0, |since| since.as_nanos() as u64);
+    return Ok((metadata.len(), modified));
+}
+
+// Store the summary of a partition, encrypted if the partition is
+pub(crate) fn write_summary(partition: &Path, keys: &KeyRing, summary: &PartitionSummary) -> Result<()> {
+    let bytes = encrypt_sidecar(&read_header(partition)?, keys, SUMMARY_TAG, &rmps::to_vec(summary)?)?;
+    fs::write(summary_path(partition), bytes)?;
+    return Ok(());
+}
+
+fn min_of(a: Option<f64>, b: Option<f64>) -> Option<f64> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b)
+    }
+}
+
+fn max_of(a: Option<f64>, b: Option<f64>) -> Option<f64> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.max(b)),
+        (a, b) => a.or(b)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::raw_data::RawData;
+    use crate::registry::TableOptions;
+    use crate::table::Schema;
+    use crate::testing::TempDir;
+    use super::*;
+
+    const T: u64 = 1_577_887_200_000;      // 2020-01-01 14:00:00 UTC
+
+    fn reading(o3: f32) -> RawData {
+        RawData {
+            AQHI: None, AQI: Some(3), CO: None, CO2: None, NO: None, NO2: None, O3: Some(o3), PM1: None,
+            PM2_5: None, PM10: None, SO2: None, T: None, RH: None, NOISE: None, TimeStamp: None,
+        }
+    }
+
+    fn levels(dir: &TempDir) -> Database {
+        let database = Database::new(dir.path()).unwrap();
+        database.create_table("levels", TableOptions::of::<RawData>()).unwrap();
+        return database;
+    }
+
+    #[test]
+    fn stored_when_partition_closes() {
+        let dir = TempDir::new("summary_rotate");
+        let database = levels(&dir);
+        let levels = database.table::<RawData>("levels").unwrap();
+        levels.insert_at(T, &reading(1.0)).unwrap();
+        levels.insert_at(T + 1, &reading(3.0)).unwrap();
+        let partition = dir.path().join("levels").join("20200101").join("14");
+        assert!(read_summary(&partition, database.keys()).is_none());
+
+        // The next hour closes 14 without it being queried
+        levels.insert_at(T + 3_600_000, &reading(2.0)).unwrap();
+        let summary = read_summary(&partition, database.keys()).unwrap();
+        assert_eq!((summary.start, summary.end, summary.records), (T, T + 3_599_999, 2));
+        assert_eq!((summary.fields["O3"].min, summary.fields["O3"].max), (Some(1.0), Some(3.0)));
+        assert_eq!(summary.fields["AQHI"].null_count, 2);
+    }
+
+    #[test]
+    fn stale_after_same_length_rewrite() {
+        let dir = TempDir::new("summary_stale");
+        let database = levels(&dir);
+        let levels = database.table::<RawData>("levels").unwrap();
+        levels.insert_at(T, &reading(1.0)).unwrap();
+        levels.insert_at(T + 3_600_000, &reading(2.0)).unwrap();
+        let partition = dir.path().join("levels").join("20200101").join("14");
+        assert!(read_summary(&partition, database.keys()).is_some());
+
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        let bytes = fs::read(&partition).unwrap();
+        fs::write(&partition, &bytes).unwrap();
+        assert!(read_summary(&partition, database.keys()).is_none());
+        let summaries = levels.summaries(T, T + 1).unwrap();
+        assert_eq!(summaries[0].records, 1);
+        assert!(read_summary(&partition, database.keys()).is_some());
+    }
+
+    #[test]
+    fn open_partition_includes_buffered_records() {
+        let dir = TempDir::new("summary_buffered");
+        let database = levels(&dir);
+        let levels = database.table::<RawData>("levels").unwrap();
+        levels.insert_at(T, &reading(1.0)).unwrap();
+        levels.insert_at(T + 1, &reading(3.0)).unwrap();
+
+        let summaries = levels.summaries(T, T + 1).unwrap();
+        assert_eq!(summaries.len(), 1);
+        assert_eq!((summaries[0].records, summaries[0].fields["O3"].max), (2, Some(3.0)));
+    }
+
+    #[test]
+    fn registered_schema() {
+        #[derive(Serialize, Deserialize)]
+        struct Level(f64);
+        impl Schema for Level {
+            const NAME: &'static str = "Level";
+            const VERSION: u32 = 1;
+        }
+        impl Summarize for Level {
+            fn fields(&self) -> Vec<(&'static str, Option<f64>)> {
+                vec![("level", Some(self.0))]
+            }
+        }
+
+        let dir = TempDir::new("summary_registered");
+        let database = Database::new(dir.path()).unwrap();
+        database.register_summary::<Level>("Level");
+        let options = TableOptions::of::<Level>();
+        database.create_table("tank", options).unwrap();
+        let tank = database.table::<Level>("tank").unwrap();
+        tank.insert_at(T, &Level(4.5)).unwrap();
+        tank.insert_at(T + 3_600_000, &Level(1.0)).unwrap();
+        let summary = read_summary(&dir.path().join("tank").join("20200101").join("14"), database.keys()).unwrap();
+        assert_eq!(summary.fields["level"].sum, 4.5);
+    }
+}
@@ -0,0 +1,198 @@
+use std::marker::PhantomData;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use crate::database::{Database, Entry};
+use crate::error::{DbError, Result};
+use crate::query::{Nearest, Range, ReadStats};
+use crate::summary::{PartitionSummary, Summarize, summarize};
+
+// Type a typed table stores, named with its version in the table manifest, see Database::table
+pub trait Schema {
+    const NAME: &'static str;       // Ex: RawData
+    const VERSION: u32;             // Raised when the encoding of the type changes
+}
+
+/***
+* Struct Table:
+*
+* Purpose:
+* Typed handle to a table, values are encoded into 'datalog' on insert and
+* decoded back on read so callers never touch the msgpack bytes. Only opened
+* over a table created for the schema of T, see TableOptions::of.
+***/
+pub struct Table<'a, T> {
+    database:   &'a Database,
+    name:       &'a str,
+    marker:     PhantomData<T>,
+}
+
+impl<'a, T> Table<'a, T> where T: Serialize + DeserializeOwned + Schema {
+    pub(crate) fn new(database: &'a Database, name: &'a str) -> Table<'a, T> {
+        Table {
+            database:   database,
+            name:       name,
+            marker:     PhantomData,
+        }
+    }
+
+    // Name of the table directory
+    pub fn name(&self) -> &str {
+        self.name
+    }
+
+    // Insert a value captured now
+    pub fn insert(&self, value: &T) -> Result<()> {
+        self.insert_entry(None, None, value)
+    }
+
+    // Insert a value captured at a timestamp in milliseconds since epoch
+    pub fn insert_at(&self, timestamp: u64, value: &T) -> Result<()> {
+        self.insert_entry(None, Some(timestamp), value)
+    }
+
+    // Insert a value a device captured at a timestamp, see Duplicates for repeated ones
+    pub fn insert_from(&self, source: &str, timestamp: u64, value: &T) -> Result<()> {
+        self.insert_entry(Some(source.to_string()), Some(timestamp), value)
+    }
+
+    fn insert_entry(&self, source: Option<String>, timestamp: Option<u64>, value: &T) -> Result<()> {
+        let entry = Entry {
+            table:      self.name,
+            data:       rmps::to_vec(value)?,
+            timestamp:  timestamp,
+            source:     source,
+        };
+        self.database.insert(entry)
+    }
+
+    // Iterate over the values of the table between two timestamps, in time order
+    pub fn range(&self, start_time: u64, end_time: u64) -> Result<TableRange<T>> {
+        let records = self.database.range(self.name, start_time, end_time)?;
+        return Ok(TableRange { records: records, marker: PhantomData });
+    }
+
+    // Iterate over the values of the table for a day, Ex: 20200101
+    pub fn find_data(&self, date: &str) -> Result<TableRange<T>> {
+        let records = self.database.find_data(self.name, date)?;
+        return Ok(TableRange { records: records, marker: PhantomData });
+    }
+
+    // Field statistics of every partition overlapping a time range, Ex: daily maximum O3
+    pub fn summaries(&self, start_time: u64, end_time: u64) -> Result<Vec<PartitionSummary>> where T: Summarize {
+        if start_time > end_time {
+            return Err(DbError::InvalidRange { start: start_time, end: end_time });
+        }
+        self.database.flush_table(self.name)?;
+        return summarize::<T>(self.database, self.name, start_time, end_time);
+    }
+
+    // Find the value closest to a timestamp
+    pub fn find_nearest(&self, timestamp: u64, nearest: Nearest) -> Result<Option<T>> {
+        match self.database.find_nearest(self.name, timestamp, nearest)? {
+            Some(entry) => Ok(Some(rmps::from_read(&entry.datalog[..])?)),
+            None => Ok(None)
+        }
+    }
+}
+
+/***
+* Struct TableRange:
+*
+* Purpose:
+* Iterator decoding the 'datalog' of each record in a Range
+***/
+pub struct TableRange<T> {
+    records:    Range,
+    marker:     PhantomData<T>,
+}
+
+impl<T> TableRange<T> {
+    // Records read and lost so far
+    pub fn stats(&self) -> ReadStats {
+        self.records.stats()
+    }
+}
+
+impl<T> Iterator for TableRange<T> where T: DeserializeOwned {
+    type Item = Result<T>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // Values are decoded from the partition without copying 'datalog'
+        self.records.next_with(|entry| Ok(rmps::from_read_ref(&entry.datalog[..])?)).transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::DbError;
+    use crate::raw_data::RawData;
+    use crate::registry::TableOptions;
+    use crate::testing::TempDir;
+    use super::*;
+
+    fn reading(o3: f32) -> RawData {
+        RawData {
+            AQHI: Some(2), AQI: None, CO: None, CO2: Some(410.5), NO: None, NO2: None, O3: Some(o3), PM1: None,
+            PM2_5: None, PM10: None, SO2: None, T: Some(-4.25), RH: None, NOISE: None,
+            TimeStamp: Some("2020-01-01T14:00:00".to_string()),
+        }
+    }
+
+    #[test]
+    fn raw_data_round_trip() {
+        let dir = TempDir::new("table_round_trip");
+        let database = Database::new(dir.path()).unwrap();
+        database.create_table("levels", TableOptions::of::<RawData>()).unwrap();
+        let levels = database.table::<RawData>("levels").unwrap();
+        levels.insert(&reading(1.5)).unwrap();
+        levels.insert(&reading(2.5)).unwrap();
+
+        let values: Vec<RawData> = levels.range(0, u64::from(u32::MAX) * 1000).unwrap().map(Result::unwrap).collect();
+        assert_eq!(values, vec![reading(1.5), reading(2.5)]);
+    }
+
+    #[test]
+    fn tables_of_another_schema_do_not_open() {
+        let dir = TempDir::new("table_other_schema");
+        let database = Database::new(dir.path()).unwrap();
+        database.create_table("bytes", TableOptions::default()).unwrap();
+        database.create_table("levels", TableOptions { schema_version: 2, ..TableOptions::of::<RawData>() }).unwrap();
+
+        assert!(matches!(database.table::<RawData>("bytes"), Err(DbError::SchemaMismatch { .. })));
+        assert!(matches!(database.table::<RawData>("levels"), Err(DbError::SchemaMismatch { version: 2, .. })));
+        assert!(matches!(database.table::<RawData>("missing"), Err(DbError::UnknownTable(_))));
+    }
+
+    #[test]
+    fn undecodable_values_are_reported_and_skipped() {
+        let dir = TempDir::new("table_undecodable");
+        let database = Database::new(dir.path()).unwrap();
+        database.create_table("levels", TableOptions::of::<RawData>()).unwrap();
+        let levels = database.table::<RawData>("levels").unwrap();
+        levels.insert_at(1_577_887_200_000, &reading(1.5)).unwrap();
+        database.insert(Entry { table: "levels", data: vec![0xc1], timestamp: Some(1_577_887_200_001), source: None })
+            .unwrap();
+        levels.insert_at(1_577_887_200_002, &reading(2.5)).unwrap();
+
+        let mut values = levels.range(1_577_887_200_000, 1_577_887_200_002).unwrap();
+        assert_eq!(values.next().unwrap().unwrap().O3, Some(1.5));
+        assert!(matches!(values.next(), Some(Err(DbError::Deserialization(_)))));
+        assert_eq!(values.next().unwrap().unwrap().O3, Some(2.5));
+        assert!(values.next().is_none());
+        assert_eq!(values.stats(), ReadStats { records: 2, values_undecodable: 1, ..ReadStats::default() });
+    }
+
+    #[test]
+    fn listed_tables_open_as_typed_tables() {
+        let dir = TempDir::new("table_listed");
+        let database = Database::new(dir.path()).unwrap();
+        database.create_table("levels", TableOptions::of::<RawData>()).unwrap();
+        database.table::<RawData>("levels").unwrap().insert_at(1_577_887_200_000, &reading(1.5)).unwrap();
+
+        for manifest in database.list_tables().unwrap() {
+            let table = database.table::<RawData>(&manifest.name).unwrap();
+            assert_eq!(table.name(), "levels");
+            assert_eq!(table.range(0, 1_577_887_200_000).unwrap().count(), 1);
+        }
+    }
+}
@@ -0,0 +1,47 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use crate::database::Entry;
+
+static NEXT_DIR: AtomicUsize = AtomicUsize::new(0);
+
+/***
+* Struct TempDir:
+*
+* Purpose:
+* Source directory of a test, removed with everything in it once dropped
+***/
+pub(crate) struct TempDir {
+    path:   PathBuf,
+}
+
+impl TempDir {
+    pub(crate) fn new(name: &str) -> TempDir {
+        let id = NEXT_DIR.fetch_add(1, Ordering::SeqCst);
+        let path = std::env::temp_dir().join(format!("file_sys-{}-{}-{}", name, process::id(), id));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        TempDir { path: path }
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+// Entry of 'data' at 'timestamp'
+pub(crate) fn entry<'a>(table: &'a str, timestamp: u64, data: &[u8]) -> Entry<'a> {
+    Entry {
+        table:      table,
+        data:       data.to_vec(),
+        timestamp:  Some(timestamp),
+        source:     None,
+    }
+}
@@ -0,0 +1,338 @@
+use std::collections::{BTreeSet, HashSet};
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use serde::{Serialize, Deserialize};
+use crate::config::LogLevel;
+use crate::database::{Database, serialize_struct};
+use crate::error::Result;
+use crate::format::{Frame, PartitionHeader, HEADER_LEN, encode_frame, read_frame};
+use crate::partition::DATE_FORMAT;
+use crate::query::partition_days;
+use crate::seal::replace_partition;
+
+pub(crate) static WAL_FILE: &str = "wal.log";
+const CHECKPOINT_LEN: u64 = 4 * 1024 * 1024;
+
+/***
+* Write-ahead log:
+*
+* Purpose:
+* data/wal.log, holding every append to a partition before it is made, as
+* frames of msgpack WalEntry. Only the log is synced by the fsync policy, the
+* partitions written since the last checkpoint are synced before it is
+* emptied. After a crash the log is replayed so partitions hold whole records.
+***/
+#[derive(Serialize, Deserialize)]
+struct WalEntry {
+    partition:  PathBuf,        // Relative to the source, Ex: levels/20200101/14
+    offset:     u64,            // Length of the partition before the append
+    #[serde(with = "serde_bytes")]
+    bytes:      Vec<u8>,        // Appended as they are: frames, after the header for a new partition
+}
+
+pub(crate) struct Wal {
+    source:     PathBuf,
+    file:       File,
+    len:        u64,
+    dirty:      HashSet<PathBuf>,   // Partitions written since the last checkpoint
+    synced:     bool,               // False while appends since the last fsync are, for FsyncPolicy::Interval
+}
+
+impl Wal {
+    pub(crate) fn open(source: &Path) -> Result<Wal> {
+        let file = OpenOptions::new().create(true).append(true).open(source.join(WAL_FILE))?;
+        Ok(Wal {
+            source:     source.to_path_buf(),
+            len:        file.metadata()?.len(),
+            file:       file,
+            dirty:      HashSet::new(),
+            synced:     true,
+        })
+    }
+
+    // Log 'bytes' about to be appended to 'partition' at 'offset'
+    pub(crate) fn append(&mut self, partition: &Path, offset: u64, bytes: &[u8]) -> Result<()> {
+        let entry = WalEntry {
+            partition:  partition.strip_prefix(&self.source).unwrap_or(partition).to_path_buf(),
+            offset:     offset,
+            bytes:      bytes.to_vec(),
+        };
+        let frame = encode_frame(&serialize_struct(entry)?);
+        self.file.write_all(&frame)?;
+        self.len += frame.len() as u64;
+        self.dirty.insert(partition.to_path_buf());
+        self.synced = false;
+        return Ok(());
+    }
+
+    // Fsync the log, for the fsync policy
+    pub(crate) fn sync(&mut self) -> Result<()> {
+        self.file.sync_data()?;
+        self.synced = true;
+        return Ok(());
+    }
+
+    // True unless appends were logged since the last fsync
+    pub(crate) fn is_synced(&self) -> bool {
+        self.synced
+    }
+
+    // True once the log should be emptied
+    pub(crate) fn is_full(&self) -> bool {
+        self.len >= CHECKPOINT_LEN
+    }
+
+    // Sync the partitions written since the last checkpoint and empty the log
+    pub(crate) fn checkpoint(&mut self) -> Result<()> {
+        for partition in self.dirty.drain() {
+            // Dropped tables take their partitions with them
+            if let Ok(file) = OpenOptions::new().append(true).open(&partition) {
+                file.sync_all()?;
+            }
+        }
+        self.file.set_len(0)?;
+        self.file.sync_all()?;
+        self.len = 0;
+        self.synced = true;
+        return Ok(());
+    }
+}
+
+/***
+* Function recover_source:
+*
+* Purpose:
+* Replays the write-ahead log left by a crash: appends that didn't reach their
+* partition, or only partly, are written again. Torn tails of every partition
+* the log names, Ex: late partitions written beside the current one, and of the
+* latest partition of every table, left by appends the log lost too, are
+* truncated. Returns how many appends were replayed.
+***/
+pub(crate) fn recover_source(database: &Database) -> Result<u64> {
+    let source = database.source();
+    let path = source.join(WAL_FILE);
+    let log = fs::read(&path).unwrap_or_default();
+
+    let mut replayed = 0;
+    let mut touched = HashSet::new();
+    let mut logged = BTreeSet::new();
+    let mut pos = 0;
+    while pos < log.len() {
+        let payload = match read_frame(&log, pos) {
+            Frame::Record(payload, next) => {
+                pos = next;
+                payload
+            }
+            Frame::Damaged(next) => {
+                // Torn while logging, its append never started
+                pos = next;
+                continue;
+            }
+        };
+        let entry: WalEntry = match rmps::from_read_ref(payload) {
+            Ok(entry) => entry,
+            Err(_) => continue
+        };
+        let partition = source.join(&entry.partition);
+        if replay(&partition, &entry)? {
+            database.log(LogLevel::Info, || format!("Replayed {} bytes to {:?}", entry.bytes.len(), partition));
+            touched.insert(partition.clone());
+            replayed += 1;
+        }
+        logged.insert(partition);
+    }
+
+    for partition in &touched {
+        File::open(partition)?.sync_all()?;
+    }
+    for manifest in database.list_tables()? {
+        if let Some(partition) = latest_partition(&source.join(&manifest.name))? {
+            logged.insert(partition);
+        }
+    }
+    // Partitions of tables dropped since the crash are gone
+    for partition in logged.iter().filter(|partition| partition.is_file()) {
+        if let Some(len) = truncate_torn_tail(partition)? {
+            database.log(LogLevel::Info, || format!("Truncated {:?} to {} bytes", partition, len));
+        }
+    }
+
+    if path.exists() {
+        let file = OpenOptions::new().write(true).open(&path)?;
+        file.set_len(0)?;
+        file.sync_all()?;
+    }
+    return Ok(replayed);
+}
+
+// Write a logged append again unless the partition already holds it, true if it was written
+fn replay(partition: &Path, entry: &WalEntry) -> Result<bool> {
+    let len = fs::metadata(partition).map(|metadata| metadata.len()).unwrap_or(0);
+    let end = entry.offset + entry.bytes.len() as u64;
+    if len < entry.offset {
+        return Ok(false);       // Appends before it were lost with the partition, nothing to append to
+    }
+
+    if len >= end {
+        // Already there, or the partition was rewritten since
+        return Ok(false);
+    }
+
+    // Missing, appended again
+    if let Some(directory) = partition.parent() {
+        fs::create_dir_all(directory)?;
+    }
+    if len == entry.offset {
+        OpenOptions::new().create(true).append(true).open(partition)?.write_all(&entry.bytes)?;
+        return Ok(true);
+    }
+
+    // Torn, written again over the part that made it without shrinking the file in place
+    let mut bytes = fs::read(partition)?;
+    bytes.truncate(entry.offset as usize);
+    bytes.extend_from_slice(&entry.bytes);
+    replace_partition(partition, &bytes)?;
+    return Ok(true);
+}
+
+// Last partition file of a table, the one a crash may have torn
+fn latest_partition(table: &Path) -> Result<Option<PathBuf>> {
+    let day = match partition_days(table)?.pop() {
+        Some(day) => day,
+        None => return Ok(None)
+    };
+
+    let mut latest = None;
+    for entry in fs::read_dir(table.join(day.format(DATE_FORMAT).to_string()))? {
+        let path = entry?.path();
+        // Sidecars have an extension, partitions don't
+        if path.is_file() && path.extension().is_none() && latest.as_ref() < Some(&path) {
+            latest = Some(path);
+        }
+    }
+    return Ok(latest);
+}
+
+// Cut bytes after the last intact frame of a partition being appended to, returns the new length if it was
+fn truncate_torn_tail(partition: &Path) -> Result<Option<u64>> {
+    let buf = fs::read(partition)?;
+    let header = match PartitionHeader::decode(partition, &buf) {
+        Ok(header) => header,
+        Err(_) => return Ok(None)       // Left for fsck
+    };
+    if header.is_sealed() {
+        return Ok(None);                // Written whole by a rename
+    }
+
+    let mut pos = HEADER_LEN;
+    let mut intact_end = HEADER_LEN;
+    while pos < buf.len() {
+        match read_frame(&buf, pos) {
+            Frame::Record(_, next) => {
+                pos = next;
+                intact_end = next;
+            }
+            Frame::Damaged(next) => pos = next,
+        }
+    }
+    if intact_end == buf.len() {
+        return Ok(None);
+    }
+
+    replace_partition(partition, &buf[..intact_end])?;
+    return Ok(Some(intact_end as u64));
+}
+
+#[cfg(test)]
+mod tests {
+    use std::mem;
+    use crate::config::Config;
+    use crate::registry::TableOptions;
+    use crate::testing::{TempDir, entry};
+    use super::*;
+
+    const T: u64 = 1_577_887_200_000;      // 2020-01-01 14:00:00 UTC
+    const HOUR: u64 = 3_600_000;
+
+    // Records stay buffered until flushed, the timer is left out of the crashes below
+    fn open(dir: &TempDir) -> Database {
+        Database::builder().source(dir.path()).wal(true).flush_interval(60_000).build().unwrap()
+    }
+
+    fn datalogs(database: &Database) -> Vec<Vec<u8>> {
+        database.range("levels", T, T + 2 * HOUR).unwrap().map(|record| record.unwrap().datalog).collect()
+    }
+
+    // Frame header promising more bytes than follow, as a write cut short leaves it
+    fn tear(partition: &Path) -> u64 {
+        let len = fs::metadata(partition).unwrap().len();
+        let mut file = OpenOptions::new().append(true).open(partition).unwrap();
+        file.write_all(&encode_frame(b"cut short")[..14]).unwrap();
+        return len;
+    }
+
+    #[test]
+    fn replays_appends_lost_in_a_crash() {
+        let dir = TempDir::new("wal_replay");
+        let database = open(&dir);
+        database.create_table("levels", TableOptions::default()).unwrap();
+        for i in 0..3u8 {
+            database.insert(entry("levels", T + u64::from(i), &[i])).unwrap();
+        }
+        mem::forget(database);      // Crash, the records are still in the writer's buffer
+
+        let partition = dir.path().join("levels").join("20200101").join("14");
+        assert_eq!(fs::metadata(&partition).unwrap().len(), HEADER_LEN as u64);
+        let database = open(&dir);
+        assert_eq!(datalogs(&database), vec![vec![0], vec![1], vec![2]]);
+        assert_eq!(fs::metadata(dir.path().join(WAL_FILE)).unwrap().len(), 0);
+    }
+
+    #[test]
+    fn truncates_every_logged_partition() {
+        let dir = TempDir::new("wal_truncate");
+        let database = open(&dir);
+        database.create_table("levels", TableOptions::default()).unwrap();
+        database.insert(entry("levels", T + HOUR, b"current")).unwrap();
+        database.insert(entry("levels", T, b"late")).unwrap();
+        database.flush().unwrap();
+        mem::forget(database);
+
+        // The late partition isn't the latest of its table, only the log names it
+        let late = dir.path().join("levels").join("20200101").join("14");
+        let current = dir.path().join("levels").join("20200101").join("15");
+        let (late_len, current_len) = (tear(&late), tear(&current));
+        let database = open(&dir);
+        assert_eq!(fs::metadata(&late).unwrap().len(), late_len);
+        assert_eq!(fs::metadata(&current).unwrap().len(), current_len);
+        assert_eq!(datalogs(&database), vec![b"late".to_vec(), b"current".to_vec()]);
+    }
+
+    #[test]
+    fn queries_leave_the_source_alone() {
+        let dir = TempDir::new("wal_read_only");
+        let database = open(&dir);
+        database.create_table("levels", TableOptions::default()).unwrap();
+        database.insert(entry("levels", T, b"a")).unwrap();
+        database.flush().unwrap();
+        mem::forget(database);
+        let partition = dir.path().join("levels").join("20200101").join("14");
+        let len = tear(&partition);
+        let log = fs::read(dir.path().join(WAL_FILE)).unwrap();
+
+        // Not recovered by from_config, the torn tail is skipped by the reader instead
+        let database = Database::from_config(Config { source: dir.path().to_path_buf(), wal: true, ..Config::default() });
+        assert_eq!(datalogs(&database), vec![b"a".to_vec()]);
+        assert_eq!(fs::metadata(&partition).unwrap().len(), len + 14);
+        assert_eq!(fs::read(dir.path().join(WAL_FILE)).unwrap(), log);
+
+        // Until the first write
+        database.insert(entry("levels", T + 1, b"b")).unwrap();
+        let mut records = database.range("levels", T, T + HOUR).unwrap();
+        let datalogs: Vec<_> = records.by_ref().map(|record| record.unwrap().datalog).collect();
+        assert_eq!(datalogs, vec![b"a".to_vec(), b"b".to_vec()]);
+        assert_eq!(records.stats().records_lost, 0);
+    }
+}
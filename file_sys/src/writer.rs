@@ -0,0 +1,484 @@
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::prelude::*;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{RecvTimeoutError, Sender, channel};
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use crate::config::{FsyncPolicy, LogLevel};
+use crate::crypto::KeyRing;
+use crate::dedup::{KeyFilter, find_record, read_filter, write_filter};
+use crate::error::Result;
+use crate::format::{PartitionHeader, HEADER_LEN};
+use crate::order::{is_sorted, last_record, mark_unsorted};
+use crate::registry::Duplicates;
+use crate::seal::read_header;
+use crate::stream::scan_records;
+use crate::wal::Wal;
+
+/***
+* Struct PartitionWriter:
+*
+* Purpose:
+* Keeps the partition a table is writing to open between inserts, with
+* records buffered up to Config::write_buffer bytes. Records of the same
+* partition are appended without looking up or reopening the file, a record
+* outside [start, end) rotates the table to another writer. The partition file
+* exists with its header before a writer opens it, see Database::rotate.
+***/
+pub(crate) struct PartitionWriter {
+    path:       PathBuf,
+    header:     PartitionHeader,    // Of the partition, encrypted payloads are bound to it
+    start:      u64,                // First millisecond of the partition
+    end:        u64,                // First millisecond of the next partition
+    file:       BufWriter<File>,
+    len:        u64,                // Partition length, buffered bytes included
+    count:      u64,                // Records written since it was opened
+    last:       Option<(u64, u32)>, // Latest timestamp in the partition and the seq of its last record, None if unknown
+    seqs:       Option<HashMap<u64, u32>>,  // Last seq of every timestamp, read once a record isn't the latest
+    sorted:     bool,               // False once records are out of time order, the partition is then marked unsorted
+    duplicates: Duplicates,         // Of the table
+    filter:     Option<KeyFilter>,  // Keys of the records, unless duplicates are allowed
+    stored:     bool,               // True while the stored filter of the partition matches 'filter', see write_filter
+    flushed:    Instant,            // Last flush, for Config::flush_interval
+    synced:     bool,               // False while records written since the last fsync are, for FsyncPolicy::Interval
+}
+
+impl PartitionWriter {
+    pub(crate) fn open(path: &Path, keys: &KeyRing, start: u64, end: u64, capacity: usize, duplicates: Duplicates)
+                       -> Result<PartitionWriter> {
+        let file = OpenOptions::new().append(true).open(path)?;   // Write at end of file
+        let len = file.metadata()?.len();
+        let header = read_header(path)?;
+        let sorted = is_sorted(path);
+        let last = match sorted && len > HEADER_LEN as u64 {
+            true => last_record(path, &header, keys)?,
+            false => None
+        };
+        let (filter, stored) = match duplicates {
+            Duplicates::Allow => (None, true),
+            _ if len > HEADER_LEN as u64 => read_filter(path, &header, keys).map(|(filter, stored)| (Some(filter), stored))?,
+            _ => (Some(KeyFilter::new()), true)
+        };
+        Ok(PartitionWriter {
+            path:       path.to_path_buf(),
+            header:     header,
+            start:      start,
+            end:        end,
+            len:        len,
+            file:       BufWriter::with_capacity(capacity, file),
+            count:      0,
+            last:       last,
+            seqs:       None,
+            sorted:     sorted,
+            duplicates: duplicates,
+            filter:     filter,
+            stored:     stored,
+            flushed:    Instant::now(),
+            synced:     true,
+        })
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub(crate) fn header(&self) -> &PartitionHeader {
+        &self.header
+    }
+
+    // True if a record at 'timestamp' belongs to this partition
+    pub(crate) fn holds(&self, timestamp: u64) -> bool {
+        timestamp >= self.start && timestamp < self.end
+    }
+
+    // Bytes in the partition so far, header and buffered bytes included
+    pub(crate) fn len(&self) -> u64 {
+        self.len
+    }
+
+    // Records written since the writer was opened
+    pub(crate) fn count(&self) -> u64 {
+        self.count
+    }
+
+    // True if a record of 'source' at 'timestamp' is stored, looked up unless duplicates are allowed
+    pub(crate) fn contains(&mut self, keys: &KeyRing, source: Option<&str>, timestamp: u64) -> Result<bool> {
+        if !self.filter.as_ref().is_some_and(|filter| filter.may_contain(source, timestamp)) {
+            return Ok(false);
+        }
+
+        // Confirmed against the partition, Ex: a false hit of a filter holding a day of records
+        self.flush()?;
+        return find_record(&self.path, &self.header, keys, self.sorted, source, timestamp);
+    }
+
+    // Mark the partition so queries leave out the record a replacement appended to it supersedes, see Range::sort
+    pub(crate) fn supersede(&mut self) -> Result<()> {
+        if is_sorted(&self.path) {
+            mark_unsorted(&self.path)?;
+        }
+        return Ok(());
+    }
+
+    pub(crate) fn duplicates(&self) -> Duplicates {
+        self.duplicates
+    }
+
+    // Sequence number of a record at 'timestamp', one more than the last record of the partition sharing it
+    pub(crate) fn next_seq(&mut self, keys: &KeyRing, timestamp: u64) -> Result<u32> {
+        if self.seqs.is_none() {
+            match self.last {
+                _ if self.len <= HEADER_LEN as u64 => return Ok(0),
+                Some((last, _)) if self.sorted && timestamp > last => return Ok(0),
+                Some((last, seq)) if self.sorted && timestamp == last => return Ok(seq + 1),
+                _ => {}
+            }
+
+            // Late record, records sharing its timestamp may be anywhere in the partition
+            self.flush()?;
+            let mut seqs = HashMap::new();
+            scan_records(&self.path, &self.header, keys, HEADER_LEN as u64, |_, entry| {
+                let seq = seqs.entry(entry.timestamp).or_insert(entry.seq);
+                *seq = (*seq).max(entry.seq);
+            })?;
+            self.seqs = Some(seqs);
+        }
+        return Ok(self.seqs.as_ref().and_then(|seqs| seqs.get(&timestamp)).map_or(0, |seq| seq + 1));
+    }
+
+    // Buffer one record's bytes, see Database::insert for their layout
+    pub(crate) fn write(&mut self, source: Option<&str>, timestamp: u64, seq: u32, bytes: &[u8]) -> Result<()> {
+        if self.sorted && self.len > HEADER_LEN as u64 && self.last.is_none_or(|(last, _)| timestamp < last) {
+            // Late record, or one after a last record that couldn't be read
+            mark_unsorted(&self.path)?;
+            self.sorted = false;
+        }
+        if self.last.is_none_or(|(last, _)| timestamp >= last) {
+            self.last = Some((timestamp, seq));
+        }
+        if let Some(seqs) = &mut self.seqs {
+            seqs.insert(timestamp, seq);
+        }
+        if let Some(filter) = &mut self.filter {
+            filter.insert(source, timestamp);
+            self.stored = false;
+        }
+        self.file.write_all(bytes)?;
+        self.len += bytes.len() as u64;
+        self.count += 1;
+        self.synced = false;
+        return Ok(());
+    }
+
+    // First millisecond of the partition
+    pub(crate) fn start(&self) -> u64 {
+        self.start
+    }
+
+    // First millisecond of the next partition
+    pub(crate) fn end(&self) -> u64 {
+        self.end
+    }
+
+    // True once the buffer has been held for 'interval' milliseconds
+    pub(crate) fn flush_due(&self, interval: u64) -> bool {
+        self.flushed.elapsed() >= Duration::from_millis(interval)
+    }
+
+    // Write the buffer out to the partition
+    pub(crate) fn flush(&mut self) -> Result<()> {
+        self.file.flush()?;
+        self.flushed = Instant::now();
+        return Ok(());
+    }
+
+    // Flush and fsync the partition
+    pub(crate) fn sync(&mut self) -> Result<()> {
+        self.flush()?;
+        self.file.get_ref().sync_data()?;
+        self.synced = true;
+        return Ok(());
+    }
+
+    // True unless records were written since the last fsync
+    pub(crate) fn is_synced(&self) -> bool {
+        self.synced
+    }
+
+    // Flush the partition and store its key filter for the next writer, unless it is stored already
+    pub(crate) fn close(mut self, keys: &KeyRing) -> Result<()> {
+        self.flush()?;
+        if let Some(filter) = self.filter.as_ref().filter(|_| !self.stored) {
+            write_filter(&self.path, &self.header, keys, filter)?;
+        }
+        return Ok(());
+    }
+}
+
+// Partitions a table is writing to
+#[derive(Default)]
+pub(crate) struct TableWriters {
+    pub current:    Option<PartitionWriter>,    // Latest partition written to
+    pub late:       Option<PartitionWriter>,    // Earlier partition taking late records, Ex: an uploaded backlog
+}
+
+impl TableWriters {
+    // True if a partition holding 'timestamp' is open
+    pub(crate) fn holds(&self, timestamp: u64) -> bool {
+        self.current.iter().chain(self.late.iter()).any(|writer| writer.holds(timestamp))
+    }
+
+    // Writer of the partition holding 'timestamp', if open
+    pub(crate) fn get_mut(&mut self, timestamp: u64) -> Option<&mut PartitionWriter> {
+        self.current.iter_mut().chain(self.late.iter_mut()).find(|writer| writer.holds(timestamp))
+    }
+
+    pub(crate) fn flush(&mut self) -> Result<()> {
+        for writer in self.current.iter_mut().chain(self.late.iter_mut()) {
+            writer.flush()?;
+        }
+        return Ok(());
+    }
+
+    // Flush the buffers held for 'interval' milliseconds
+    pub(crate) fn flush_due(&mut self, interval: u64) -> Result<()> {
+        for writer in self.current.iter_mut().chain(self.late.iter_mut()).filter(|writer| writer.flush_due(interval)) {
+            writer.flush()?;
+        }
+        return Ok(());
+    }
+
+    // Fsync the partitions written to since their last fsync
+    pub(crate) fn sync(&mut self) -> Result<()> {
+        for writer in self.current.iter_mut().chain(self.late.iter_mut()).filter(|writer| !writer.is_synced()) {
+            writer.sync()?;
+        }
+        return Ok(());
+    }
+
+    // Close both writers, see PartitionWriter::close
+    pub(crate) fn close(self, keys: &KeyRing) -> Result<()> {
+        for writer in self.current.into_iter().chain(self.late) {
+            writer.close(keys)?;
+        }
+        return Ok(());
+    }
+}
+
+/***
+* Function sync_due:
+*
+* Purpose:
+* True if the fsync policy calls for a sync now, Ex: after an insert or on a
+* tick of the Flusher. Under FsyncPolicy::Interval a sync is due once every
+* interval, the first caller after it elapsed syncs.
+***/
+pub(crate) fn sync_due(fsync: FsyncPolicy, last_sync: &Mutex<Instant>) -> bool {
+    match fsync {
+        FsyncPolicy::Never => false,
+        FsyncPolicy::EveryRecord => true,
+        FsyncPolicy::Interval(millis) => {
+            let mut last_sync = last_sync.lock().unwrap_or_else(|err| err.into_inner());
+            if last_sync.elapsed() < Duration::from_millis(millis) {
+                return false;
+            }
+            *last_sync = Instant::now();
+            true
+        }
+    }
+}
+
+// State of a database the Flusher works on, shared with its inserts
+pub(crate) struct Shared {
+    pub writers:    Arc<Mutex<HashMap<String, TableWriters>>>,
+    pub wal:        Arc<Mutex<Option<Wal>>>,
+    pub last_sync:  Arc<Mutex<Instant>>,    // Last fsync for FsyncPolicy::Interval
+}
+
+/***
+* Struct Flusher:
+*
+* Purpose:
+* Thread writing the buffers of the partition writers out every
+* Config::flush_interval milliseconds, so the records of a device that went
+* quiet reach their partition without waiting for its next insert. Under
+* FsyncPolicy::Interval it also syncs what was written since the last sync,
+* the log if there is one and the partitions otherwise, so the last records
+* before a pause are on disk once the interval elapsed. Ticks at the shorter
+* of both intervals. Stopped and joined when dropped.
+***/
+pub(crate) struct Flusher {
+    stop:       Option<Sender<()>>,     // Dropped to stop the thread
+    thread:     Option<JoinHandle<()>>,
+}
+
+impl Flusher {
+    pub(crate) fn start(shared: Shared, flush_interval: u64, fsync: FsyncPolicy, log_level: LogLevel)
+                        -> Result<Flusher> {
+        let tick = match fsync {
+            FsyncPolicy::Interval(millis) if millis != 0 && (flush_interval == 0 || millis < flush_interval) => millis,
+            _ => flush_interval
+        };
+        let (stop, stopped) = channel::<()>();
+        let thread = thread::Builder::new().name("file_sys-flush".to_string()).spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(Duration::from_millis(tick)) {
+                // Left buffered or unsynced, the next insert or flush reports it
+                if let Err(err) = Flusher::tick(&shared, flush_interval.saturating_sub(tick), fsync) {
+                    if log_level >= LogLevel::Info {
+                        eprintln!("[file_sys] Buffered records not flushed: {}", err);
+                    }
+                }
+            }
+        })?;
+        Ok(Flusher {
+            stop:       Some(stop),
+            thread:     Some(thread),
+        })
+    }
+
+    // Flush the buffers held for 'held' milliseconds, so none is held past the next tick, and sync if the
+    // policy calls for it. Locks as Database::insert does.
+    fn tick(shared: &Shared, held: u64, fsync: FsyncPolicy) -> Result<()> {
+        let mut writers = shared.writers.lock().unwrap_or_else(|err| err.into_inner());
+        for table in writers.values_mut() {
+            table.flush_due(held)?;
+        }
+        if !matches!(fsync, FsyncPolicy::Interval(_)) {
+            return Ok(());
+        }
+
+        let mut wal = shared.wal.lock().unwrap_or_else(|err| err.into_inner());
+        if !sync_due(fsync, &shared.last_sync) {
+            return Ok(());
+        }
+        match wal.as_mut() {
+            Some(wal) if !wal.is_synced() => wal.sync()?,
+            Some(_) => {}
+            None => {
+                for table in writers.values_mut() {
+                    table.sync()?;
+                }
+            }
+        }
+        return Ok(());
+    }
+}
+
+impl Drop for Flusher {
+    fn drop(&mut self) {
+        self.stop.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::PathBuf;
+    use crate::database::Database;
+    use crate::format::HEADER_LEN;
+    use crate::registry::TableOptions;
+    use crate::testing::{TempDir, entry};
+    use super::*;
+
+    const T: u64 = 1_577_887_200_000;      // 2020-01-01 14:00:00 UTC
+
+    fn open(dir: &TempDir, flush_interval: u64) -> Database {
+        let database = Database::builder().source(dir.path()).flush_interval(flush_interval).build().unwrap();
+        database.create_table("levels", TableOptions::default()).unwrap();
+        return database;
+    }
+
+    fn partition(dir: &TempDir, hour: &str) -> PathBuf {
+        dir.path().join("levels").join("20200101").join(hour)
+    }
+
+    #[test]
+    fn header_written_at_creation() {
+        let dir = TempDir::new("writer_header");
+        let database = open(&dir, 60_000);
+        database.insert(entry("levels", T, b"buffered")).unwrap();
+
+        // The record waits in the buffer, the file already has its header
+        assert_eq!(fs::metadata(partition(&dir, "14")).unwrap().len(), HEADER_LEN as u64);
+        assert_eq!(read_header(&partition(&dir, "14")).unwrap().start, T);
+        assert!(!partition(&dir, "14").with_extension("tmp").exists());
+    }
+
+    #[test]
+    fn buffer_is_written_out_before_reads_and_rotation() {
+        let dir = TempDir::new("writer_flush");
+        let database = open(&dir, 60_000);
+        database.insert(entry("levels", T, b"a")).unwrap();
+        let records: Vec<_> = database.range("levels", T, T + 1).unwrap().map(Result::unwrap).collect();
+        assert_eq!(records[0].datalog, b"a");
+
+        database.insert(entry("levels", T + 1, b"b")).unwrap();
+        let len = fs::metadata(partition(&dir, "14")).unwrap().len();
+        database.insert(entry("levels", T + 3_600_000, b"c")).unwrap();
+        assert!(fs::metadata(partition(&dir, "14")).unwrap().len() > len);
+    }
+
+    #[test]
+    fn timer_flushes_idle_writers() {
+        let dir = TempDir::new("writer_timer");
+        let database = open(&dir, 20);
+        database.insert(entry("levels", T, b"buffered")).unwrap();
+        let len = || fs::metadata(partition(&dir, "14")).unwrap().len();
+        for _ in 0..100 {
+            if len() > HEADER_LEN as u64 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(len() > HEADER_LEN as u64);
+        drop(database);     // Stops the flusher
+    }
+
+    #[test]
+    fn timer_syncs_under_an_fsync_interval() {
+        let dir = TempDir::new("writer_sync");
+        let database = Database::builder().source(dir.path()).flush_interval(60_000)
+            .fsync(FsyncPolicy::Interval(300)).build().unwrap();
+        database.create_table("levels", TableOptions::default()).unwrap();
+        database.insert(entry("levels", T, b"a")).unwrap();
+        let len = || fs::metadata(partition(&dir, "14")).unwrap().len();
+        assert_eq!(len(), HEADER_LEN as u64);
+
+        // Synced records are written out, long before the flush interval
+        for _ in 0..200 {
+            if len() > HEADER_LEN as u64 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(len() > HEADER_LEN as u64);
+    }
+
+    #[test]
+    fn empty_files_are_skipped_and_removed() {
+        let dir = TempDir::new("writer_empty");
+        let database = open(&dir, 60_000);
+        database.insert(entry("levels", T + 3_600_000, b"a")).unwrap();
+        fs::write(partition(&dir, "14"), b"").unwrap();
+
+        let records: Vec<_> = database.range("levels", T, T + 7_200_000).unwrap().map(Result::unwrap).collect();
+        assert_eq!(records.len(), 1);
+        let report = database.verify().unwrap();
+        assert_eq!((report.files_removed, report.is_clean()), (1, false));
+        assert_eq!(database.repair().unwrap().files_removed, 1);
+        assert!(!partition(&dir, "14").exists());
+        assert!(database.verify().unwrap().is_clean());
+
+        // A later record for the hour creates it again, header first
+        database.insert(entry("levels", T, b"b")).unwrap();
+        database.flush().unwrap();
+        assert_eq!(database.range("levels", T, T + 7_200_000).unwrap().count(), 2);
+    }
+}